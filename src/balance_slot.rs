@@ -0,0 +1,396 @@
+use alloy::network::Network;
+use alloy::primitives::{address, keccak256, Address, B256, U256};
+use alloy::providers::Provider;
+use alloy::sol_types::{SolCall, SolValue};
+use alloy::transports::Transport;
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use node_db::NodeDB;
+use revm::{
+    context_interface::{result::ExecutionResult, Database, TransactTo},
+    database::CacheDB,
+    inspector::Inspector,
+    interpreter::{opcode, Interpreter},
+    DatabaseRef, EvmContext,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File};
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use crate::block_env::{sim_evm, BlockContext};
+use crate::gen_::ERC20Token;
+use crate::state_db::{BlockStateDB, InsertionType};
+
+// Where the discovered layouts are persisted between runs
+const SLOT_CACHE: &str = "cache/balance_slots.json";
+
+// Second account the layout is verified for, so it holds for any holder
+const PROBE: Address = address!("00000000000000000000000000000000000b0b01");
+
+// Written into a candidate slot, balanceOf has to return exactly this
+const SENTINEL: u64 = 0x5e7_1e5e_7115;
+
+// Bit offsets a packed balance is tried at
+const PACKED_OFFSETS: [usize; 6] = [0, 32, 64, 96, 128, 160];
+
+lazy_static! {
+    // implementation code hash => layout
+    static ref SLOTS: RwLock<HashMap<B256, BalanceSlot>> =
+        RwLock::new(read_slots_from_file(SLOT_CACHE).unwrap_or_default());
+    // token => layout verified against the token itself during this run
+    static ref VERIFIED: RwLock<HashMap<Address, BalanceSlot>> = RwLock::new(HashMap::new());
+    // eip-1967 implementation slot
+    static ref IMPLEMENTATION_SLOT: U256 =
+        U256::from_str("0x360894a13ba1a3210667c828492db98dca3e2076cc3735a920a3ca505d382bbc").unwrap();
+}
+
+// How the balances mapping is keyed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MappingLayout {
+    // keccak256(account . index)
+    Solidity,
+    // keccak256(index . account)
+    Vyper,
+}
+
+// Where the balance of any account lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalanceSlot {
+    pub layout: MappingLayout,
+    // the mapping slot, or the base of a namespaced mapping
+    pub index: U256,
+    // bit offset of a balance that is packed with other values
+    pub offset: usize,
+}
+
+impl BalanceSlot {
+    // Storage slot of the balance of an account
+    pub fn slot(&self, account: Address) -> U256 {
+        let key = match self.layout {
+            MappingLayout::Solidity => keccak256((account, self.index).abi_encode()),
+            MappingLayout::Vyper => keccak256((self.index, account).abi_encode()),
+        };
+        key.into()
+    }
+
+    // Storage value with the balance written in, keeping the bits it is packed with
+    pub fn pack(&self, current: U256, balance: U256) -> U256 {
+        if self.offset == 0 && current == U256::ZERO {
+            return balance;
+        }
+        let mask = if self.offset == 0 {
+            U256::MAX
+        } else {
+            (U256::from(1) << (256 - self.offset)) - U256::from(1)
+        };
+        (current & !(mask << self.offset)) | ((balance & mask) << self.offset)
+    }
+}
+
+// A db that slots can be written into for verification
+pub trait SlotStore: Database {
+    fn write_slot(&mut self, account: Address, slot: U256, value: U256) -> Result<()>;
+}
+
+impl SlotStore for NodeDB {
+    fn write_slot(&mut self, account: Address, slot: U256, value: U256) -> Result<()> {
+        self.insert_account_storage(account, slot, value, node_db::InsertionType::OnChain)
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+}
+
+impl<T, N, P> SlotStore for BlockStateDB<T, N, P>
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    fn write_slot(&mut self, account: Address, slot: U256, value: U256) -> Result<()> {
+        self.insert_account_storage(account, slot, value, InsertionType::OnChain)
+    }
+}
+
+impl<ExtDB> SlotStore for CacheDB<ExtDB>
+where
+    ExtDB: DatabaseRef,
+    ExtDB::Error: std::fmt::Debug,
+{
+    fn write_slot(&mut self, account: Address, slot: U256, value: U256) -> Result<()> {
+        self.insert_account_storage(account, slot, value)
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+}
+
+// Find the balance slot of a token. The layout is cached by the code hash of the token, or of
+// its implementation if it is an eip-1967 proxy, so the search only runs once per contract.
+// Beacon and other proxies share a code hash without sharing a layout, so a cached layout is
+// still verified once per token and the token is searched if it does not hold. The balanceOf
+// calls run in the block
+pub fn balance_slot<DB: SlotStore>(db: &mut DB, block: &BlockContext, token: Address) -> Option<BalanceSlot>
+where
+    DB::Error: std::fmt::Debug,
+{
+    if let Some(slot) = VERIFIED.read().unwrap().get(&token) {
+        return Some(*slot);
+    }
+    let code_hash = layout_hash(db, token).ok()?;
+    let cached = SLOTS.read().unwrap().get(&code_hash).copied();
+    if let Some(slot) = cached {
+        if verify(db, block, token, slot, PROBE).unwrap_or(false) {
+            VERIFIED.write().unwrap().insert(token, slot);
+            return Some(slot);
+        }
+        debug!("Cached balance slot {:?} does not hold for {}", slot, token);
+    }
+
+    match discover(db, block, token) {
+        Ok(slot) => {
+            debug!("Balance slot for {}: {:?}", token, slot);
+            SLOTS.write().unwrap().entry(code_hash).or_insert(slot);
+            VERIFIED.write().unwrap().insert(token, slot);
+            Some(slot)
+        }
+        Err(e) => {
+            warn!("No balance slot for {}: {}", token, e);
+            None
+        }
+    }
+}
+
+// Write all of the discovered layouts to the cache
+pub fn save_balance_slots() {
+    create_dir_all("cache").unwrap();
+    if let Err(e) = write_slots_to_file(&SLOTS.read().unwrap(), SLOT_CACHE) {
+        warn!("Failed to write balance slots: {}", e);
+    }
+}
+
+// Code hash that determines the storage layout, following the implementation of a proxy
+fn layout_hash<DB: SlotStore>(db: &mut DB, token: Address) -> Result<B256>
+where
+    DB::Error: std::fmt::Debug,
+{
+    let implementation = db
+        .storage(token, *IMPLEMENTATION_SLOT)
+        .map_err(|e| anyhow!("{e:?}"))?;
+    let layout_address = if implementation == U256::ZERO {
+        token
+    } else {
+        Address::from_word(implementation.into())
+    };
+    let info = db
+        .basic(layout_address)
+        .map_err(|e| anyhow!("{e:?}"))?
+        .ok_or_else(|| anyhow!("No account for {layout_address}"))?;
+    Ok(info.code_hash)
+}
+
+// Trace a balanceOf call, every slot it loads whose key was hashed from the account is a
+// candidate. Shadowed mappings that are read but do not decide the balance fail verification
+// and the next candidate is tried. Each candidate is verified by writing a sentinel balance, for both the traced
+// account and a probe account, and reading it back through balanceOf
+fn discover<DB: SlotStore>(db: &mut DB, block: &BlockContext, token: Address) -> Result<BalanceSlot>
+where
+    DB::Error: std::fmt::Debug,
+{
+    let account = address!("0000000000000000000000000000000000000001");
+    let mut recorder = SlotRecorder::new(token);
+    balance_of(db, block, token, account, Some(&mut recorder))?;
+
+    for slot in &recorder.loaded {
+        let Some(preimage) = recorder.preimages.get(slot) else {
+            continue;
+        };
+        let Some((layout, index)) = mapping_key(preimage, account) else {
+            continue;
+        };
+        for offset in PACKED_OFFSETS {
+            let candidate = BalanceSlot { layout, index, offset };
+            if verify(db, block, token, candidate, account)? && verify(db, block, token, candidate, PROBE)? {
+                return Ok(candidate);
+            }
+        }
+    }
+    Err(anyhow!("No verified slot among {} loads", recorder.loaded.len()))
+}
+
+// Split a mapping key preimage into its layout and index if it was keyed by the account
+fn mapping_key(preimage: &[u8], account: Address) -> Option<(MappingLayout, U256)> {
+    if preimage.len() != 64 {
+        return None;
+    }
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(account.as_slice());
+    if preimage[..32] == word {
+        return Some((MappingLayout::Solidity, U256::from_be_slice(&preimage[32..])));
+    }
+    if preimage[32..] == word {
+        return Some((MappingLayout::Vyper, U256::from_be_slice(&preimage[..32])));
+    }
+    None
+}
+
+// Write the sentinel, read it back, then restore the slot
+fn verify<DB: SlotStore>(
+    db: &mut DB,
+    block: &BlockContext,
+    token: Address,
+    candidate: BalanceSlot,
+    account: Address,
+) -> Result<bool>
+where
+    DB::Error: std::fmt::Debug,
+{
+    let slot = candidate.slot(account);
+    let original = db.storage(token, slot).map_err(|e| anyhow!("{e:?}"))?;
+    db.write_slot(token, slot, candidate.pack(original, U256::from(SENTINEL)))?;
+    let balance = balance_of(db, block, token, account, None);
+    db.write_slot(token, slot, original)?;
+    Ok(balance.is_ok_and(|balance| balance == U256::from(SENTINEL)))
+}
+
+fn balance_of<DB: SlotStore>(
+    db: &mut DB,
+    block: &BlockContext,
+    token: Address,
+    account: Address,
+    recorder: Option<&mut SlotRecorder>,
+) -> Result<U256>
+where
+    DB::Error: std::fmt::Debug,
+{
+    let calldata = ERC20Token::balanceOfCall { account }.abi_encode();
+    let mut fallback = SlotRecorder::new(token);
+    let recorder = recorder.unwrap_or(&mut fallback);
+    let mut evm = sim_evm(&mut *db, block)
+        .with_external_context(recorder)
+        .modify_tx_env(|tx| {
+            tx.caller = account;
+            tx.transact_to = TransactTo::Call(token);
+            tx.data = calldata.into();
+            tx.value = U256::ZERO;
+        })
+        .build();
+    match evm.transact().map_err(|e| anyhow!("{e:?}"))?.result {
+        ExecutionResult::Success { output, .. } => Ok(U256::abi_decode(output.data())?),
+        _ => Err(anyhow!("balanceOf reverted")),
+    }
+}
+
+// Records the slots loaded from the token storage in order, and the preimage of every hash
+// so mapping keys can be traced back to their index
+struct SlotRecorder {
+    token: Address,
+    loaded: Vec<U256>,
+    preimages: HashMap<U256, Vec<u8>>,
+    // preimage of the hash that is being executed
+    hashing: Option<Vec<u8>>,
+}
+
+impl SlotRecorder {
+    fn new(token: Address) -> Self {
+        Self {
+            token,
+            loaded: Vec::new(),
+            preimages: HashMap::new(),
+            hashing: None,
+        }
+    }
+}
+
+impl<DB: Database> Inspector<DB> for SlotRecorder {
+    fn step(&mut self, interp: &mut Interpreter, _context: &mut EvmContext<DB>) {
+        match interp.current_opcode() {
+            opcode::KECCAK256 => {
+                let (Ok(offset), Ok(len)) = (interp.stack().peek(0), interp.stack().peek(1)) else {
+                    return;
+                };
+                let (offset, len) = (offset.saturating_to::<usize>(), len.saturating_to::<usize>());
+                if len == 64 && offset + len <= interp.shared_memory.len() {
+                    self.hashing = Some(interp.shared_memory.slice(offset, len).to_vec());
+                }
+            }
+            opcode::SLOAD if interp.contract.target_address == self.token => {
+                if let Ok(slot) = interp.stack().peek(0) {
+                    if !self.loaded.contains(&slot) {
+                        self.loaded.push(slot);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn step_end(&mut self, interp: &mut Interpreter, _context: &mut EvmContext<DB>) {
+        if let Some(preimage) = self.hashing.take() {
+            if let Ok(hash) = interp.stack().peek(0) {
+                self.preimages.insert(hash, preimage);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedSlot {
+    code_hash: B256,
+    slot: BalanceSlot,
+}
+
+fn write_slots_to_file(slots: &HashMap<B256, BalanceSlot>, filename: &str) -> std::io::Result<()> {
+    let file = File::create(filename)?;
+    let writer = BufWriter::new(file);
+    let slots: Vec<CachedSlot> = slots
+        .iter()
+        .map(|(code_hash, slot)| CachedSlot {
+            code_hash: *code_hash,
+            slot: *slot,
+        })
+        .collect();
+    serde_json::to_writer(writer, &slots)?;
+    Ok(())
+}
+
+fn read_slots_from_file(filename: &str) -> Result<HashMap<B256, BalanceSlot>> {
+    if !Path::new(filename).exists() {
+        return Ok(HashMap::new());
+    }
+    let file = File::open(filename)?;
+    let reader = BufReader::new(file);
+    let slots: Vec<CachedSlot> = serde_json::from_reader(reader)?;
+    Ok(slots.into_iter().map(|cached| (cached.code_hash, cached.slot)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapping_key_layouts() {
+        let account = address!("d8da6bf26964af9d7eed9e03e53415d37aa96045");
+        let index = U256::from(3);
+
+        let solidity = (account, index).abi_encode();
+        assert_eq!(mapping_key(&solidity, account), Some((MappingLayout::Solidity, index)));
+        let vyper = (index, account).abi_encode();
+        assert_eq!(mapping_key(&vyper, account), Some((MappingLayout::Vyper, index)));
+        assert_eq!(mapping_key(&solidity, PROBE), None);
+
+        let slot = BalanceSlot { layout: MappingLayout::Solidity, index, offset: 0 };
+        assert_eq!(slot.slot(account), U256::from_be_bytes(keccak256(solidity).0));
+    }
+
+    #[test]
+    fn test_pack_keeps_other_bits() {
+        // a balance packed above an address keeps the address
+        let owner = U256::from_be_slice(address!("d8da6bf26964af9d7eed9e03e53415d37aa96045").as_slice());
+        let slot = BalanceSlot { layout: MappingLayout::Solidity, index: U256::ZERO, offset: 160 };
+        let packed = slot.pack(owner, U256::from(SENTINEL));
+        assert_eq!(packed >> 160, U256::from(SENTINEL));
+        assert_eq!(packed & ((U256::from(1) << 160) - U256::from(1)), owner);
+    }
+}
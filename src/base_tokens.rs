@@ -0,0 +1,73 @@
+use alloy::network::Network;
+use alloy::primitives::{Address, U256};
+use alloy::providers::Provider;
+use alloy::transports::Transport;
+use log::{info, warn};
+
+use crate::estimator::Estimator;
+use crate::AMOUNT;
+
+// An asset that cycles can start and end in
+#[derive(Debug, Clone)]
+pub struct BaseToken {
+    pub token: Address,
+    // input amount for cycles starting in this token, the AMOUNT equivalent
+    pub amount: U256,
+    // asset that is borrowed to fund the cycle
+    pub flash_loan_asset: Address,
+}
+
+impl BaseToken {
+    // The deployed executor and quoter only borrow and quote from weth
+    pub fn is_executable(&self, weth: Address) -> bool {
+        self.flash_loan_asset == weth
+    }
+}
+
+// Load the base tokens from BASE_TOKENS, a comma separated list of `token` or `token:amount`.
+// Tokens without an amount are sized to the eth value of AMOUNT through the estimator rates.
+// Bases the executor can not borrow are skipped, there is no point searching cycles through
+// them. Defaults to only weth
+pub fn load_base_tokens<T, N, P>(estimator: &Estimator<T, N, P>) -> Vec<BaseToken>
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    let weth: Address = std::env::var("WETH").unwrap().parse().unwrap();
+    let configured = std::env::var("BASE_TOKENS").unwrap_or_else(|_| weth.to_string());
+
+    let mut bases = Vec::new();
+    for entry in configured.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (token, amount) = match entry.split_once(':') {
+            Some((token, amount)) => (token, Some(amount)),
+            None => (entry, None),
+        };
+        let token: Address = token.parse().expect("Invalid base token address");
+
+        let amount = match amount {
+            Some(amount) => amount.parse().expect("Invalid base token amount"),
+            None if token == weth => *AMOUNT,
+            None => match estimator.eth_equivalent(token, *AMOUNT) {
+                Some(amount) => amount,
+                None => {
+                    warn!("No weth rate for base token {}, skipping", token);
+                    continue;
+                }
+            },
+        };
+
+        let base = BaseToken {
+            token,
+            amount,
+            flash_loan_asset: token,
+        };
+        if !base.is_executable(weth) {
+            warn!("Base token {} can not be borrowed by the executor, skipping", token);
+            continue;
+        }
+        info!("Base token {} with input amount {}", token, amount);
+        bases.push(base);
+    }
+    bases
+}
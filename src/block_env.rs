@@ -0,0 +1,165 @@
+use alloy::eips::calc_next_block_base_fee;
+use alloy::eips::BlockNumberOrTag;
+use alloy::eips::eip1559::BaseFeeParams;
+use alloy::primitives::{address, Address, Bytes, B256, U256};
+use alloy::providers::{Provider, ProviderBuilder};
+use alloy::rpc::types::Header;
+use alloy::sol_types::{SolCall, SolValue};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use revm::{
+    builder::{EvmBuilder, SetGenericStage},
+    context::Evm,
+    context_interface::{result::ExecutionResult, Database, TransactTo},
+};
+
+use crate::gen_::ERC20Token;
+
+// Base mainnet
+pub const CHAIN_ID: u64 = 8453;
+const BLOCK_TIME: u64 = 2;
+
+// Caller for view calls
+const VIEW_CALLER: Address = address!("d8da6bf26964af9d7eed9e03e53415d37aa96045");
+
+// Which block a simulation runs in. Anything we send lands in the next block at the earliest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimulateAt {
+    Latest,
+    NextBlock,
+}
+
+// The parts of a header the evm exposes to contracts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockContext {
+    pub number: u64,
+    pub timestamp: u64,
+    pub basefee: u64,
+    pub gas_limit: u64,
+    pub gas_used: u64,
+    pub coinbase: Address,
+    pub prevrandao: B256,
+}
+
+impl BlockContext {
+    pub fn from_header(header: &Header) -> Self {
+        Self {
+            number: header.inner.number,
+            timestamp: header.inner.timestamp,
+            basefee: header.inner.base_fee_per_gas.unwrap_or_default(),
+            gas_limit: header.inner.gas_limit,
+            gas_used: header.inner.gas_used,
+            coinbase: header.inner.beneficiary,
+            prevrandao: header.inner.mix_hash,
+        }
+    }
+
+    // The block as seen by a simulation, the next block follows the chain block time and basefee
+    pub fn at(&self, at: SimulateAt) -> Self {
+        match at {
+            SimulateAt::Latest => *self,
+            SimulateAt::NextBlock => Self {
+                number: self.number + 1,
+                timestamp: self.timestamp + BLOCK_TIME,
+                basefee: calc_next_block_base_fee(
+                    self.gas_used,
+                    self.gas_limit,
+                    self.basefee,
+                    BaseFeeParams::optimism_canyon(),
+                ),
+                gas_used: 0,
+                ..*self
+            },
+        }
+    }
+}
+
+// The block the node is at, for simulations over a db that only has state
+pub async fn latest_block_context() -> Result<BlockContext> {
+    let url = std::env::var("FULL")?.parse()?;
+    let provider = ProviderBuilder::new().on_http(url);
+    let block = provider
+        .get_block_by_number(BlockNumberOrTag::Latest, false)
+        .await?
+        .ok_or_else(|| anyhow!("No latest block"))?;
+    Ok(BlockContext::from_header(&block.header))
+}
+
+// Evm over the db with the chain and block filled in. Every simulation goes through this so
+// timestamps, block numbers and the basefee match what the contracts see on chain. The txs
+// have no gas price so the basefee is not charged, and the block gas limit is only exposed
+// to contracts, not enforced
+pub fn sim_evm<'a, DB: Database>(db: DB, block: &BlockContext) -> EvmBuilder<'a, SetGenericStage, (), DB> {
+    let block = *block;
+    Evm::builder()
+        .with_db(db)
+        .modify_cfg_env(|cfg| {
+            cfg.chain_id = CHAIN_ID;
+            cfg.disable_base_fee = true;
+            cfg.disable_block_gas_limit = true;
+        })
+        .modify_block_env(|env| {
+            env.number = U256::from(block.number);
+            env.timestamp = U256::from(block.timestamp);
+            env.basefee = U256::from(block.basefee);
+            env.gas_limit = U256::from(block.gas_limit);
+            env.coinbase = block.coinbase;
+            env.prevrandao = Some(block.prevrandao);
+        })
+}
+
+// Make a view call in the block, nothing is committed
+pub fn view_call<DB>(db: DB, block: &BlockContext, to: Address, calldata: Vec<u8>) -> Result<Bytes>
+where
+    DB: Database,
+    DB::Error: std::fmt::Debug,
+{
+    let mut evm = sim_evm(db, block)
+        .modify_tx_env(|tx| {
+            tx.caller = VIEW_CALLER;
+            tx.transact_to = TransactTo::Call(to);
+            tx.data = calldata.into();
+            tx.value = U256::ZERO;
+        })
+        .build();
+    match evm.transact().map_err(|e| anyhow!("{e:?}"))?.result {
+        ExecutionResult::Success { output, .. } => Ok(output.into_data()),
+        ExecutionResult::Revert { output, .. } => Err(anyhow!("Call to {to} reverted {output}")),
+        _ => Err(anyhow!("Call to {to} failed")),
+    }
+}
+
+// Token balance of the account in the block
+pub fn balance_of<DB>(db: DB, block: &BlockContext, token: Address, account: Address) -> Result<U256>
+where
+    DB: Database,
+    DB::Error: std::fmt::Debug,
+{
+    let output = view_call(db, block, token, ERC20Token::balanceOfCall { account }.abi_encode())?;
+    Ok(U256::abi_decode(&output)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The next block moves the number and time forward and adjusts the basefee to the usage
+    #[test]
+    fn test_next_block() {
+        let block = BlockContext {
+            number: 100,
+            timestamp: 1_000,
+            basefee: 1_000_000,
+            gas_limit: 120_000_000,
+            gas_used: 120_000_000,
+            ..Default::default()
+        };
+        assert_eq!(block.at(SimulateAt::Latest), block);
+
+        let next = block.at(SimulateAt::NextBlock);
+        assert_eq!(next.number, 101);
+        assert_eq!(next.timestamp, 1_002);
+        assert!(next.basefee > block.basefee);
+        assert_eq!(next.coinbase, block.coinbase);
+    }
+}
@@ -1,17 +1,558 @@
-use alloy::primitives::B256;
+use alloy::network::Network;
+use alloy::primitives::{address, keccak256, Address, B256, U256};
+use alloy::providers::Provider;
+use alloy::sol;
+use alloy::sol_types::SolCall;
+use alloy::transports::Transport;
+use anyhow::{anyhow, Result};
 use lazy_static::lazy_static;
-use revm::primitives::Bytes;
-use revm::bytecode::Bytecode;
-
+use log::{debug, info, warn};
+use pool_sync::{Pool, PoolInfo, PoolType};
 use revm::{
-    state::{AccountInfo},
+    context_interface::{result::ExecutionResult, Database, TransactTo},
 };
-use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File};
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use crate::balance_slot::{balance_slot, save_balance_slots};
+use crate::block_env::sim_evm;
+use crate::protocol::protocol_spec;
+use crate::state_db::layout::{storage_layout, v2_layout};
+use crate::state_db::{BlockStateDB, InsertionType};
+
+// Where the classified code fingerprints are persisted between runs
+const CODE_CACHE: &str = "cache/pool_code.json";
+
+// Fingerprints of the reviewed deployments of each protocol, recorded with
+// `cargo test record_known_pool_code -- --ignored`
+const SEEDED_CODE: &str = include_str!("known_pool_code.json");
+
+// Account the fee discovery swaps are sent from and paid out to
+const SWAPPER: Address = address!("00000000000000000000000000000000000c0de1");
+
+// Range of input multipliers out of 10000 that fee discovery searches
+const MIN_FEE: u64 = 9000;
+const MAX_FEE: u64 = 10000;
+
+sol!(
+    #[sol(rpc)]
+    contract V2Pair {
+        function swap(uint256 amount0Out, uint256 amount1Out, address to, bytes data) external;
+    }
+);
 
 lazy_static! {
-    pub static ref UNISWAP_V2_BYTECODE: Bytecode = {
-        let bytecode_hex = "";
-        Bytecode::new_raw(Bytes::from_str(bytecode_hex).expect("failed to decode bytecode"))
+    // code fingerprint => what the code is, trusted over anything learned
+    static ref SEEDED: HashMap<B256, CodeClass> = serde_json::from_str::<Vec<CachedCode>>(SEEDED_CODE)
+        .unwrap()
+        .into_iter()
+        .map(|cached| (cached.fingerprint, cached.class))
+        .collect();
+    // code fingerprint => what the code is, learned from unknown code
+    static ref KNOWN_CODE: RwLock<HashMap<B256, CodeClass>> =
+        RwLock::new(read_code_from_file(CODE_CACHE).unwrap_or_default());
+    static ref POOL_CODES: RwLock<Arc<PoolCodes>> = RwLock::new(Arc::new(HashMap::new()));
+}
+
+// What the code of a pool says it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodeClass {
+    pub pool_type: PoolType,
+    // input multiplier out of 10000 found by swapping, constant product pairs only
+    pub v2_fee: Option<u64>,
+    // the code is not seeded, the type is what most of the pools sharing it claim
+    #[serde(default)]
+    pub voted: bool,
+}
+
+// Classification of a tracked pool
+#[derive(Debug, Clone, Copy)]
+pub struct PoolCode {
+    pub fingerprint: B256,
+    pub class: CodeClass,
+    // the code does not match the type pool_sync labeled the pool with
+    pub mismatch: bool,
+}
+
+pub type PoolCodes = HashMap<Address, PoolCode>;
+
+// Snapshot of the classified pools
+pub fn pool_codes() -> Arc<PoolCodes> {
+    POOL_CODES.read().unwrap().clone()
+}
+
+// Pools that were not classified are let through
+pub fn is_code_consistent(codes: &PoolCodes, pool: &Pool) -> bool {
+    codes.get(&pool.address()).map_or(true, |code| !code.mismatch)
+}
+
+// Fee of a v2 pool, the discovered fee if there is one and the registry fee for the label otherwise
+#[inline]
+pub fn v2_fee(codes: &PoolCodes, pool: &Address, pool_type: PoolType) -> U256 {
+    codes
+        .get(pool)
+        .and_then(|code| code.class.v2_fee)
+        .map(U256::from)
+        .or_else(|| protocol_spec(pool_type, false).ok().and_then(|spec| spec.v2_fee))
+        .unwrap_or(U256::from(9970))
+}
+
+// Hash of the runtime code with the PUSH32 immediates zeroed. Immutables are pushed as 32 byte
+// words, so every pool deployed from the same code shares a fingerprint even when the tokens
+// and fee are baked into it
+pub fn code_fingerprint(code: &[u8]) -> B256 {
+    let mut normalized = code.to_vec();
+    let mut pc = 0;
+    while pc < normalized.len() {
+        let op = normalized[pc];
+        if (0x60..=0x7f).contains(&op) {
+            let len = (op - 0x5f) as usize;
+            if op == 0x7f {
+                let end = (pc + 1 + len).min(normalized.len());
+                normalized[pc + 1..end].fill(0);
+            }
+            pc += len;
+        }
+        pc += 1;
+    }
+    keccak256(normalized)
+}
+
+// Classify the code of every pool. Seeded fingerprints are trusted, code that is not seeded is
+// labeled with the type most of the pools sharing it claim and flagged as voted. Constant product
+// pairs get their fee by swapping. Pools whose code has a different storage layout or fee than
+// their label are flagged
+pub fn classify_pools<T, N, P>(db: &mut BlockStateDB<T, N, P>, pools: &[Pool])
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    let mut fingerprints: HashMap<B256, Vec<&Pool>> = HashMap::new();
+    for pool in pools {
+        match pool_code(db, pool.address()) {
+            Ok(code) => fingerprints.entry(code_fingerprint(&code)).or_default().push(pool),
+            Err(e) => warn!("No code for pool {}: {}", pool.address(), e),
+        }
+    }
+
+    let mut codes: PoolCodes = (*pool_codes()).clone();
+    for (fingerprint, claimants) in fingerprints {
+        let known = known_class(&SEEDED, &KNOWN_CODE.read().unwrap(), &fingerprint);
+        let class = match known {
+            Some(class) => class,
+            None => {
+                let class = learn_class(db, &claimants);
+                warn!(
+                    "Unknown pool code {} voted {:?} by {} pools",
+                    fingerprint,
+                    class,
+                    claimants.len()
+                );
+                KNOWN_CODE.write().unwrap().insert(fingerprint, class);
+                class
+            }
+        };
+
+        for pool in claimants {
+            codes.insert(pool.address(), classify_pool(fingerprint, class, pool));
+        }
+    }
+
+    let flagged = codes.values().filter(|code| code.mismatch).count();
+    let voted = codes.values().filter(|code| code.class.voted).count();
+    info!(
+        "Classified the code of {} pools, {} flagged, {} with unseeded code",
+        codes.len(),
+        flagged,
+        voted
+    );
+    *POOL_CODES.write().unwrap() = Arc::new(codes);
+
+    save_balance_slots();
+    create_dir_all("cache").unwrap();
+    if let Err(e) = write_code_to_file(&KNOWN_CODE.read().unwrap(), CODE_CACHE) {
+        warn!("Failed to write pool code: {}", e);
+    }
+}
+
+// What a fingerprint is known to be, seeded code is trusted over anything learned
+fn known_class(
+    seeded: &HashMap<B256, CodeClass>,
+    learned: &HashMap<B256, CodeClass>,
+    fingerprint: &B256,
+) -> Option<CodeClass> {
+    seeded.get(fingerprint).or_else(|| learned.get(fingerprint)).copied()
+}
+
+// Check the label of a pool against the class of its code
+fn classify_pool(fingerprint: B256, class: CodeClass, pool: &Pool) -> PoolCode {
+    let mismatch = !matches_label(&class, pool.pool_type());
+    if mismatch {
+        warn!(
+            "Pool {} is labeled {:?} but its code is {:?}",
+            pool.address(),
+            pool.pool_type(),
+            class
+        );
+    }
+    PoolCode {
+        fingerprint,
+        class,
+        mismatch,
+    }
+}
+
+// Forks that share storage layout and fee are interchangeable, the executor and calculators
+// treat them the same. The fee the executor uses comes from the label
+fn matches_label(class: &CodeClass, label: PoolType) -> bool {
+    let same_layout = class.pool_type == label
+        || match (storage_layout(class.pool_type), storage_layout(label)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        };
+    let registry_fee = |pool_type| protocol_spec(pool_type, false).ok().and_then(|spec| spec.v2_fee);
+    let class_fee = class.v2_fee.map(U256::from).or_else(|| registry_fee(class.pool_type));
+    same_layout && class_fee == registry_fee(label)
+}
+
+// Label an unseeded fingerprint by majority and discover the fee from the first pool it can be found on
+fn learn_class<T, N, P>(db: &mut BlockStateDB<T, N, P>, claimants: &[&Pool]) -> CodeClass
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    let mut votes: Vec<(PoolType, usize)> = Vec::new();
+    for pool in claimants {
+        match votes.iter_mut().find(|(pool_type, _)| *pool_type == pool.pool_type()) {
+            Some((_, count)) => *count += 1,
+            None => votes.push((pool.pool_type(), 1)),
+        }
+    }
+    let pool_type = votes
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|(pool_type, _)| pool_type)
+        .unwrap();
+
+    CodeClass {
+        pool_type,
+        v2_fee: discover_fee(db, claimants, pool_type),
+        voted: true,
+    }
+}
+
+// Fee of a constant product code from the first pool it can be found on
+fn discover_fee<T, N, P>(db: &mut BlockStateDB<T, N, P>, claimants: &[&Pool], pool_type: PoolType) -> Option<u64>
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    if !is_constant_product(pool_type) {
+        return None;
+    }
+    // a few tries, pools with dust reserves can not resolve the fee
+    claimants.iter().take(3).find_map(|pool| match discover_v2_fee(db, pool) {
+        Ok(fee) => Some(fee),
+        Err(e) => {
+            debug!("Fee discovery failed on {}: {}", pool.address(), e);
+            None
+        }
+    })
+}
+
+fn is_constant_product(pool_type: PoolType) -> bool {
+    v2_layout(pool_type).is_ok()
+        && protocol_spec(pool_type, false)
+            .ok()
+            .and_then(|spec| spec.v2_fee)
+            .is_some()
+}
+
+// Runtime code of a pool from the contracts in the db, loading the account if needed
+fn pool_code<T, N, P>(db: &mut BlockStateDB<T, N, P>, pool: Address) -> Result<Vec<u8>>
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    let info = db
+        .basic(pool)
+        .map_err(|e| anyhow!("{e:?}"))?
+        .ok_or_else(|| anyhow!("No account"))?;
+    if let Some(code) = db.contracts.get(&info.code_hash) {
+        return Ok(code.original_bytes().to_vec());
+    }
+    let code = info.code.ok_or_else(|| anyhow!("No code"))?;
+    db.contracts.insert(info.code_hash, code.clone());
+    Ok(code.original_bytes().to_vec())
+}
+
+// Send token0 into the pair and ask for token1 out. The pair only lets the swap through if the
+// output is at most what its fee allows, so the largest multiplier whose output passes the k
+// check is the fee
+fn discover_v2_fee<T, N, P>(db: &mut BlockStateDB<T, N, P>, pool: &Pool) -> Result<u64>
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    let address = pool.address();
+    let token0 = pool.token0_address();
+    let (reserve0, reserve1) = db.get_reserves(&address);
+    let amount_in = reserve0 / U256::from(100);
+    if amount_in == U256::ZERO || reserve1 < U256::from(1_000_000) {
+        return Err(anyhow!("Reserves too small"));
+    }
+    let out = |fee: u64| {
+        let amount_in_with_fee = amount_in * U256::from(fee);
+        amount_in_with_fee * reserve1 / (reserve0 * U256::from(10000) + amount_in_with_fee)
     };
-    pub static ref UNISWAP_V2_CODE_HASH: B256 = UNISWAP_V2_BYTECODE.hash_slow();
+
+    // credit the pair with the input, restored once the search is done
+    let block = db.block;
+    let balance_slot = balance_slot(db, &block, token0).ok_or_else(|| anyhow!("No balance slot for {token0}"))?;
+    let slot = balance_slot.slot(address);
+    let original = db.storage(token0, slot).map_err(|e| anyhow!("{e:?}"))?;
+    db.insert_account_storage(
+        token0,
+        slot,
+        balance_slot.pack(original, reserve0 + amount_in),
+        InsertionType::OnChain,
+    )?;
+
+    let mut search = || -> Result<u64> {
+        if !swap_passes(db, address, out(MIN_FEE))? {
+            return Err(anyhow!("Swap reverts at the lowest fee"));
+        }
+        let (mut low, mut high) = (MIN_FEE, MAX_FEE);
+        while low < high {
+            let mid = (low + high + 1) / 2;
+            if swap_passes(db, address, out(mid))? {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        // the next multiplier has to ask for more, otherwise the search could not tell them apart
+        if low < MAX_FEE && out(low + 1) == out(low) {
+            return Err(anyhow!("Amount too small to resolve the fee"));
+        }
+        Ok(low)
+    };
+    let fee = search();
+
+    db.insert_account_storage(token0, slot, original, InsertionType::OnChain)?;
+    fee
+}
+
+fn swap_passes<T, N, P>(db: &mut BlockStateDB<T, N, P>, pool: Address, amount_out: U256) -> Result<bool>
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    let calldata = V2Pair::swapCall {
+        amount0Out: U256::ZERO,
+        amount1Out: amount_out,
+        to: SWAPPER,
+        data: Default::default(),
+    }
+    .abi_encode();
+    let block = db.block;
+    let mut evm = sim_evm(&mut *db, &block)
+        .modify_tx_env(|tx| {
+            tx.caller = SWAPPER;
+            tx.transact_to = TransactTo::Call(pool);
+            tx.data = calldata.into();
+            tx.value = U256::ZERO;
+        })
+        .build();
+    let result = evm.transact().map_err(|e| anyhow!("{e:?}"))?;
+    Ok(matches!(result.result, ExecutionResult::Success { .. }))
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedCode {
+    fingerprint: B256,
+    class: CodeClass,
+}
+
+fn write_code_to_file(code: &HashMap<B256, CodeClass>, filename: &str) -> std::io::Result<()> {
+    let file = File::create(filename)?;
+    let writer = BufWriter::new(file);
+    let code: Vec<CachedCode> = code
+        .iter()
+        .map(|(fingerprint, class)| CachedCode {
+            fingerprint: *fingerprint,
+            class: *class,
+        })
+        .collect();
+    serde_json::to_writer(writer, &code)?;
+    Ok(())
+}
+
+fn read_code_from_file(filename: &str) -> Result<HashMap<B256, CodeClass>> {
+    if !Path::new(filename).exists() {
+        return Ok(HashMap::new());
+    }
+    let file = File::open(filename)?;
+    let reader = BufReader::new(file);
+    let code: Vec<CachedCode> = serde_json::from_reader(reader)?;
+    // everything in the cache was learned
+    Ok(code
+        .into_iter()
+        .map(|cached| (cached.fingerprint, CodeClass { voted: true, ..cached.class }))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::refresh::build_pool_sync;
+    use alloy::providers::ProviderBuilder;
+    use pool_sync::UniswapV2Pool;
+
+    #[test]
+    fn test_fingerprint_ignores_push32_immediates() {
+        // PUSH32 <word> PUSH1 0x00 SSTORE
+        let code = |word: u8| {
+            let mut code = vec![0x7f];
+            code.extend([word; 32]);
+            code.extend([0x60, 0x00, 0x55]);
+            code
+        };
+        assert_eq!(code_fingerprint(&code(1)), code_fingerprint(&code(2)));
+
+        // other immediates are part of the code
+        let mut other = code(1);
+        other[34] = 0x01;
+        assert_ne!(code_fingerprint(&code(1)), code_fingerprint(&other));
+    }
+
+    #[test]
+    fn test_interchangeable_forks_match() {
+        let uniswap = CodeClass {
+            pool_type: PoolType::UniswapV2,
+            v2_fee: Some(9970),
+            voted: false,
+        };
+        assert!(matches_label(&uniswap, PoolType::SushiSwapV2));
+        assert!(!matches_label(&uniswap, PoolType::PancakeSwapV2));
+        assert!(!matches_label(&uniswap, PoolType::Aerodrome));
+
+        // same code but the pairs charge more than the label says
+        let repriced = CodeClass {
+            pool_type: PoolType::UniswapV2,
+            v2_fee: Some(9975),
+            voted: false,
+        };
+        assert!(!matches_label(&repriced, PoolType::UniswapV2));
+    }
+
+    #[test]
+    fn test_seeded_code_is_trusted() {
+        assert!(SEEDED.values().all(|class| !class.voted));
+    }
+
+    // A seeded fingerprint wins over what the pools voted and flags the pools whose label
+    // does not match it
+    #[test]
+    fn test_seeded_code_overrides_label() {
+        let fingerprint = code_fingerprint(&[0x60, 0x00, 0x55]);
+        let seeded: HashMap<B256, CodeClass> = [(
+            fingerprint,
+            CodeClass {
+                pool_type: PoolType::UniswapV2,
+                v2_fee: Some(9970),
+                voted: false,
+            },
+        )]
+        .into_iter()
+        .collect();
+        let learned: HashMap<B256, CodeClass> = [(
+            fingerprint,
+            CodeClass {
+                pool_type: PoolType::UniswapV3,
+                v2_fee: None,
+                voted: true,
+            },
+        )]
+        .into_iter()
+        .collect();
+
+        let class = known_class(&seeded, &learned, &fingerprint).unwrap();
+        assert_eq!(class, seeded[&fingerprint]);
+        assert_eq!(known_class(&HashMap::new(), &learned, &fingerprint), Some(learned[&fingerprint]));
+
+        let pool = |pool_type: PoolType| {
+            let v2 = UniswapV2Pool {
+                address: address!("00000000000000000000000000000000000000a1"),
+                token0: address!("00000000000000000000000000000000000000b1"),
+                token1: address!("00000000000000000000000000000000000000b2"),
+                token0_name: String::new(),
+                token1_name: String::new(),
+                token0_decimals: 18,
+                token1_decimals: 18,
+                token0_reserves: U256::ZERO,
+                token1_reserves: U256::ZERO,
+                stable: None,
+                fee: None,
+            };
+            match pool_type {
+                PoolType::SushiSwapV2 => Pool::SushiSwapV2(v2),
+                _ => Pool::Aerodrome(v2),
+            }
+        };
+        let mislabeled = classify_pool(fingerprint, class, &pool(PoolType::Aerodrome));
+        assert!(mislabeled.mismatch);
+        assert_eq!(mislabeled.class.pool_type, PoolType::UniswapV2);
+        assert!(!classify_pool(fingerprint, class, &pool(PoolType::SushiSwapV2)).mismatch);
+        assert!(!is_code_consistent(
+            &[(address!("00000000000000000000000000000000000000a1"), mislabeled)].into_iter().collect(),
+            &pool(PoolType::Aerodrome)
+        ));
+    }
+
+    // Fingerprint the pools of every protocol from a live node. Code that only one label claims
+    // is written to the seeded table, the output has to be reviewed before it is committed
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn record_known_pool_code() {
+        dotenv::dotenv().ok();
+        let (pools, _) = build_pool_sync().unwrap().sync_pools().await.unwrap();
+        let url = std::env::var("FULL").unwrap().parse().unwrap();
+        let mut db = BlockStateDB::new(ProviderBuilder::new().on_http(url)).unwrap();
+
+        let mut fingerprints: HashMap<B256, Vec<&Pool>> = HashMap::new();
+        for pool in &pools {
+            if let Ok(code) = pool_code(&mut db, pool.address()) {
+                fingerprints.entry(code_fingerprint(&code)).or_default().push(pool);
+            }
+        }
+
+        let mut seeded = Vec::new();
+        for (fingerprint, claimants) in fingerprints {
+            let pool_type = claimants[0].pool_type();
+            if claimants.iter().any(|pool| pool.pool_type() != pool_type) {
+                println!("Code {fingerprint} is claimed by more than one label, not seeded");
+                continue;
+            }
+            let class = CodeClass {
+                pool_type,
+                v2_fee: discover_fee(&mut db, &claimants, pool_type),
+                voted: false,
+            };
+            seeded.push(CachedCode { fingerprint, class });
+        }
+        seeded.sort_by_key(|cached| cached.fingerprint);
+        let writer = BufWriter::new(File::create("src/known_pool_code.json").unwrap());
+        serde_json::to_writer_pretty(writer, &seeded).unwrap();
+    }
 }
@@ -1,4 +1,5 @@
 use super::Calculator;
+use crate::state_db::PoolState;
 use crate::traits::*;
 use crate::types::*;
 use alloy::sol;
@@ -25,11 +26,19 @@ where
     pub fn aerodrome_out(&self, amount_in: U256, token_in: Address, pool_address: Address) -> U256 {
         // get all of the state
         let db_read = self.market_state.db.read().unwrap();
-        let (reserve0, reserve1) = db_read.get_reserves(&pool_address);
-        let pool_fee = db_read.get_fee(&pool_address);
-        let (dec_0, dec_1) = db_read.get_decimals(&pool_address);
-        let stable = db_read.get_stable(&pool_address);
-        let token0 = db_read.get_token0(pool_address);
+        let (reserve0, reserve1, pool_fee, dec_0, dec_1, stable, token0) =
+            match db_read.pool_state(&pool_address) {
+                Ok(PoolState::Aerodrome {
+                    reserve0,
+                    reserve1,
+                    fee,
+                    decimals0,
+                    decimals1,
+                    stable,
+                    token0,
+                }) => (*reserve0, *reserve1, *fee, *decimals0, *decimals1, *stable, *token0),
+                _ => return U256::ZERO,
+            };
 
         let mut _reserve0 = U256::from(reserve0);
         let mut _reserve1 = U256::from(reserve1);
@@ -1,5 +1,6 @@
 use super::Calculator;
 use alloy::primitives::Address;
+use pool_sync::Pool;
 use alloy::primitives::{I256, U256};
 use std::ops::Neg;
 use std::str::FromStr;
@@ -20,33 +21,43 @@ where
         token_out: Address,
         pool_address: Address,
     ) -> U256 {
-        let pool = self.pool_manager.get_balancer_pool(&pool_address);
-        let token_in_index = pool.get_token_index(&token_in).unwrap();
-        let token_out_index = pool.get_token_index(&token_out).unwrap();
-        let balance_in = pool.balances[token_in_index];
-        let balance_out = pool.balances[token_out_index];
+        // the balances live in the vault and are not followed, this is the synced state
+        let db_read = self.market_state.db.read().unwrap();
+        let Some(Pool::BalancerV2(pool)) = db_read.pool_info.get(&pool_address) else {
+            return U256::ZERO;
+        };
+        let tokens = pool.get_tokens();
+        let decimals: Vec<u8> = [pool.token0_decimals, pool.token1_decimals]
+            .into_iter()
+            .chain(pool.additional_token_decimals.iter().copied())
+            .collect();
+        let (Some(token_in_index), Some(token_out_index)) = (
+            tokens.iter().position(|token| *token == token_in),
+            tokens.iter().position(|token| *token == token_out),
+        ) else {
+            return U256::ZERO;
+        };
+        let (Some(decimals_in), Some(decimals_out)) = (decimals.get(token_in_index), decimals.get(token_out_index)) else {
+            return U256::ZERO;
+        };
+
+        // the math runs on balances and amounts upscaled to 18 decimals
+        let scale_in = U256::from(10).pow(U256::from(18 - *decimals_in.min(&18)));
+        let scale_out = U256::from(10).pow(U256::from(18 - *decimals_out.min(&18)));
+        let balance_in = pool.balances[token_in_index] * scale_in;
+        let balance_out = pool.balances[token_out_index] * scale_out;
         let weight_in = pool.weights[token_in_index];
         let weight_out = pool.weights[token_out_index];
-        let swap_fee_percentage = pool.swap_fee;
 
-        let scaling_factor = 18 - pool.token0_decimals as i8;
-        let scaled_amount_in = Self::scale(amount_in, scaling_factor);
-        let scaled_amount_in_without_fees = Self::sub(
-            scaled_amount_in,
-            Self::mul_up(scaled_amount_in, swap_fee_percentage),
-        );
-        let amount_in = Self::scale(scaled_amount_in_without_fees, scaling_factor);
+        let scaled_amount_in = amount_in * scale_in;
+        let amount_in = Self::sub(scaled_amount_in, Self::mul_up(scaled_amount_in, pool.swap_fee));
 
         let denominator = Self::add(balance_in, amount_in);
         let base = Self::div_up(balance_in, denominator);
         let exponent = Self::div_down(weight_in, weight_out);
         let power = Self::pow_up(base, exponent);
 
-        Self::mul_down(balance_out, Self::complement(power))
-    }
-
-    fn scale(value: U256, decimals: i8) -> U256 {
-        value * (U256::from(10).pow(U256::from(decimals)))
+        Self::mul_down(balance_out, Self::complement(power)) / scale_out
     }
 
     fn add(a: U256, b: U256) -> U256 {
@@ -5,10 +5,12 @@ use alloy::providers::Provider;
 use pool_sync::PoolType;
 use std::sync::Arc;
 
+use super::evm::{has_native_math, EvmSwapCalculator};
 use crate::cache::Cache;
 use crate::market_state::MarketState;
 use crate::swap::*;
-use crate::AMOUNT;
+use crate::bytecode::{pool_codes, v2_fee, PoolCodes};
+use crate::state_db::InsufficientTickData;
 
 // Calculator for getting the amount
 pub struct Calculator<T, N, P>
@@ -19,7 +21,10 @@ where
 {
     pub market_state: Arc<MarketState<T, N, P>>,
     pub cache: Arc<Cache>,
-    pub pool_manager: PoolManager, // Added pool_manager field for balancer pools
+    // classified pool code, v2 fees come from here
+    pub pool_codes: Arc<PoolCodes>,
+    // quotes pools without native math by running their swap
+    pub evm_calculator: EvmSwapCalculator<T, N, P>,
 }
 
 impl<T, N, P> Calculator<T, N, P>
@@ -32,17 +37,18 @@ where
     // contains the market state to access pool info and a cache for calculations
     pub fn new(market_state: Arc<MarketState<T, N, P>>) -> Self {
         Self {
+            evm_calculator: EvmSwapCalculator::new(market_state.clone()),
             market_state,
             cache: Arc::new(Cache::new(500)),
-            pool_manager: PoolManager::new(), // Initialize pool_manager
+            pool_codes: pool_codes(),
         }
     }
 
     // calculate the output amount
     // we can get read access to the db since we know it will not change for duration of calculation
     #[inline]
-    pub fn calculate_output(&self, path: &SwapPath) -> U256 {
-        let mut amount = *AMOUNT;
+    pub fn calculate_output(&self, path: &SwapPath, amount_in: U256) -> U256 {
+        let mut amount = amount_in;
 
         // for each step, calculate the amount out
         for swap_step in &path.steps {
@@ -57,6 +63,7 @@ where
                     amount,
                     pool_address,
                     swap_step.token_in,
+                    swap_step.token_out,
                     swap_step.protocol,
                     swap_step.fee,
                 );
@@ -73,9 +80,9 @@ where
         amount
     }
 
-    pub fn debug_calculation(&self, path: &SwapPath) -> Vec<U256> {
+    pub fn debug_calculation(&self, path: &SwapPath, amount_in: U256) -> Vec<U256> {
         let mut path_calc: Vec<U256> = Vec::new();
-        let mut amount = *AMOUNT;
+        let mut amount = amount_in;
         path_calc.push(amount);
 
         for swap_step in &path.steps {
@@ -84,6 +91,7 @@ where
                 amount,
                 pool_address,
                 swap_step.token_in,
+                swap_step.token_out,
                 swap_step.protocol,
                 swap_step.fee,
             );
@@ -98,11 +106,12 @@ where
         &self,
         pool_addr: Address,
         token_in: Address,
+        token_out: Address,
         protocol: PoolType,
         fee: u32,
         input: U256,
     ) -> U256 {
-        self.compute_amount_out(input, pool_addr, token_in, protocol, fee)
+        self.compute_amount_out(input, pool_addr, token_in, token_out, protocol, fee)
     }
 
     pub fn compute_amount_out(
@@ -110,18 +119,29 @@ where
         input_amount: U256,
         pool_address: Address,
         token_in: Address,
+        token_out: Address,
         pool_type: PoolType,
         fee: u32,
     ) -> U256 {
+        if !has_native_math(pool_type) {
+            return self
+                .evm_calculator
+                .quote(pool_address, token_in, token_out, input_amount)
+                .map(|quote| quote.amount_out)
+                .unwrap_or(U256::ZERO);
+        }
+
         match pool_type {
-            PoolType::UniswapV2 | PoolType::SushiSwapV2 | PoolType::SwapBasedV2 => {
-                self.uniswap_v2_out(input_amount, &pool_address, &token_in, U256::from(9970))
-            }
-            PoolType::PancakeSwapV2 | PoolType::BaseSwapV2 | PoolType::DackieSwapV2 => {
-                self.uniswap_v2_out(input_amount, &pool_address, &token_in, U256::from(9975))
-            }
-            PoolType::AlienBaseV2 => {
-                self.uniswap_v2_out(input_amount, &pool_address, &token_in, U256::from(9984))
+            PoolType::UniswapV2
+            | PoolType::SushiSwapV2
+            | PoolType::SwapBasedV2
+            | PoolType::PancakeSwapV2
+            | PoolType::BaseSwapV2
+            | PoolType::DackieSwapV2
+            | PoolType::AlienBaseV2 => {
+                // the fee found by swapping on the pool code, or the registry fee for the label
+                let fee = v2_fee(&self.pool_codes, &pool_address, pool_type);
+                self.uniswap_v2_out(input_amount, &pool_address, &token_in, fee)
             }
             PoolType::UniswapV3
             | PoolType::SushiSwapV3
@@ -130,30 +150,20 @@ where
             | PoolType::PancakeSwapV3
             | PoolType::AlienBaseV3
             | PoolType::SwapBasedV3
-            | PoolType::DackieSwapV3 => match self
-                .uniswap_v3_out(input_amount, &pool_address, &token_in, fee)
-            {
+            | PoolType::DackieSwapV3 => match self.uniswap_v3_out(input_amount, &pool_address, &token_in, fee) {
                 Ok(amount) => amount,
-                Err(_) => U256::ZERO,
+                Err(e) => {
+                    // the state updater loads the word and touches the pool
+                    if let Some(missing) = e.downcast_ref::<InsufficientTickData>() {
+                        self.market_state.queue_tick_word(missing.pool, missing.word);
+                    }
+                    U256::ZERO
+                }
             },
             PoolType::Aerodrome => self.aerodrome_out(input_amount, token_in, pool_address),
-            PoolType::MaverickV1 | PoolType::MaverickV2 => {
-                // Assuming zero_for_one and tick_limit are known or defaulted here
-                let zero_for_one = true; // placeholder, adjust as needed
-                let tick_limit = 0; // placeholder, adjust as needed
-                self.maverick_v2_out(input_amount, pool_address, zero_for_one, tick_limit)
-            }
-            PoolType::BalancerV2 => {
-                // Assuming token_out is known or can be derived, placeholder here
-                let token_out = Address::zero(); // placeholder, adjust as needed
-                self.balancer_v2_out(input_amount, token_in, token_out, pool_address)
-            }
-            PoolType::CurveTwoCrypto | PoolType::CurveTriCrypto => {
-                // Assuming index_in and index_out are known or defaulted here
-                let index_in = U256::zero(); // placeholder, adjust as needed
-                let index_out = U256::one(); // placeholder, adjust as needed
-                self.curve_out(index_in, index_out, input_amount, pool_address)
-            }
+            PoolType::BalancerV2 => self.balancer_v2_out(input_amount, token_in, token_out, pool_address),
+            // quoted by running the pool code above
+            _ => U256::ZERO,
         }
     }
 }
@@ -1,4 +1,5 @@
 use super::Calculator;
+use crate::block_env::{sim_evm, SimulateAt};
 use alloy::sol;
 use alloy::network::Network;
 use alloy::primitives::U256;
@@ -8,7 +9,6 @@ use alloy::sol_types::SolCall;
 use alloy::transports::Transport;
 use alloy::providers::Provider;
 use revm::{
-    context::{ Evm},
     context_interface::{
         result::{ ExecutionResult},
         TransactTo,
@@ -46,8 +46,8 @@ where
 
         // get the db and construct our evm
         let mut db = self.market_state.db.write().unwrap();
-        let mut evm = Evm::builder()
-            .with_db(&mut *db)
+        let block = db.block.at(SimulateAt::NextBlock);
+        let mut evm = sim_evm(&mut *db, &block)
             .modify_tx_env(|tx| {
                 tx.caller = address!("0000000000000000000000000000000000000001");
                 tx.transact_to = TransactTo::Call(pool);
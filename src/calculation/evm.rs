@@ -0,0 +1,523 @@
+use alloy::network::Network;
+use alloy::primitives::{address, Address, Bytes, FixedBytes, U256};
+use alloy::providers::Provider;
+use alloy::sol;
+use alloy::sol_types::{SolCall, SolValue};
+use alloy::transports::Transport;
+use anyhow::{anyhow, Result};
+use pool_sync::{Pool, PoolInfo, PoolType};
+use revm::{
+    context_interface::{result::ExecutionResult, Database, TransactTo},
+    database::CacheDB,
+    state::{AccountInfo, Bytecode},
+    DatabaseRef,
+};
+use std::str::FromStr;
+use std::sync::Arc;
+use uniswap_v3_math::tick_math::{MAX_SQRT_RATIO, MIN_SQRT_RATIO};
+
+use crate::balance_slot::{balance_slot, SlotStore};
+use crate::block_env::{sim_evm, BlockContext, SimulateAt};
+use crate::gen_::ERC20Token;
+use crate::market_state::MarketState;
+use crate::state_db::layout::{storage_layout, StorageLayout};
+
+// Accounts the fallback quotes run from. The helper is injected into the overlay, the owner is
+// the only account it forwards calls for
+pub(crate) const HELPER: Address = address!("0000000000000000000000000000000000005a9e");
+const OWNER: Address = address!("00000000000000000000000000000000000a11ce");
+const BALANCER_VAULT: Address = address!("BA12222222228d8Ba445958a75a0704d566BF2C8");
+
+// Runtime code of the swap helper. Storage: 0 token in, 1 amount in, 2 owner, 3 spender
+//   called by the owner: approve the spender for the amount if one is set, then call the
+//     address in the first calldata word with the rest of the calldata and bubble the result
+//   called by anyone else: transfer the amount of the token in to the caller, this pays
+//     any swap callback (uniswapV3SwapCallback, pancakeV3SwapCallback, maverickV2SwapCallback..)
+const HELPER_CODE: &str = "0x336002541460365763a9059cbb60e01b60005233600452600154602452600060006044600060006000545af160345760006000fd5b005b6003541560655763095ea7b360e01b600052600354600452600154602452600060006044600060006000545af1505b6020360360206000376000600060203603600060006000355af13d600060003e608d573d6000fd5b3d6000f3";
+
+sol!(
+    #[sol(rpc)]
+    contract SwapEntrypoints {
+        // uniswap v3 and its forks
+        function swap(
+            address recipient,
+            bool zeroForOne,
+            int256 amountSpecified,
+            uint160 sqrtPriceLimitX96,
+            bytes data
+        ) external returns (int256 amount0, int256 amount1);
+    }
+);
+
+sol!(
+    #[sol(rpc)]
+    contract MaverickV2Pool {
+        struct SwapParams {
+            uint256 amount;
+            bool tokenAIn;
+            bool exactOutput;
+            int32 tickLimit;
+        }
+        function swap(address recipient, SwapParams params, bytes data)
+            external returns (uint256 amountIn, uint256 amountOut);
+    }
+);
+
+sol!(
+    #[sol(rpc)]
+    contract BalancerVault {
+        struct SingleSwap {
+            bytes32 poolId;
+            uint8 kind;
+            address assetIn;
+            address assetOut;
+            uint256 amount;
+            bytes userData;
+        }
+        struct FundManagement {
+            address sender;
+            bool fromInternalBalance;
+            address recipient;
+            bool toInternalBalance;
+        }
+        function swap(SingleSwap singleSwap, FundManagement funds, uint256 limit, uint256 deadline)
+            external returns (uint256);
+        function getPoolId() external view returns (bytes32);
+    }
+);
+
+sol!(
+    #[sol(rpc)]
+    contract CurveCryptoPool {
+        function exchange(uint256 i, uint256 j, uint256 dx, uint256 min_dy) external returns (uint256);
+    }
+);
+
+// Output of a swap run through the pool code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EvmQuote {
+    pub amount_out: U256,
+    pub gas_used: u64,
+}
+
+// How the helper has to call the pool
+struct SwapCall {
+    target: Address,
+    // approved for the input when the pool pulls it instead of calling back
+    spender: Option<Address>,
+    calldata: Vec<u8>,
+}
+
+// If the native calculators implement the math of the protocol, the protocols with a storage
+// layout and balancer from its synced pool. Everything else is quoted by running the pool code
+pub fn has_native_math(pool_type: PoolType) -> bool {
+    pool_type == PoolType::BalancerV2
+        || matches!(storage_layout(pool_type), Ok(StorageLayout::V2(_) | StorageLayout::V3(_)))
+}
+
+// Quotes a pool by running its real swap in revm. The swap runs against an overlay of the
+// market state so nothing it writes is kept, the helper funds the swap and pays any callback.
+// Slower than native math but exact for any protocol the swap can be encoded for
+pub struct EvmSwapCalculator<T, N, P>
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    market_state: Arc<MarketState<T, N, P>>,
+    helper_code: Bytecode,
+}
+
+impl<T, N, P> EvmSwapCalculator<T, N, P>
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    pub fn new(market_state: Arc<MarketState<T, N, P>>) -> Self {
+        Self {
+            market_state,
+            helper_code: helper_bytecode(),
+        }
+    }
+
+    // Exact output and gas used of swapping the amount of token in for token out through the pool
+    pub fn quote(&self, pool_address: Address, token_in: Address, token_out: Address, amount_in: U256) -> Result<EvmQuote> {
+        let db = self.market_state.db.read().unwrap();
+        let pool = db.get_pool(&pool_address).clone();
+        let block = db.block.at(SimulateAt::NextBlock);
+        let mut overlay = CacheDB::new(&*db);
+        swap_on(&mut overlay, &block, &self.helper_code, &pool, token_in, token_out, amount_in)
+    }
+}
+
+pub(crate) fn helper_bytecode() -> Bytecode {
+    Bytecode::new_raw(Bytes::from_str(HELPER_CODE).unwrap())
+}
+
+// Run the swap through the helper. The state the swap leaves behind stays in the overlay
+pub(crate) fn swap_on<DB>(
+    overlay: &mut CacheDB<DB>,
+    block: &BlockContext,
+    helper_code: &Bytecode,
+    pool: &Pool,
+    token_in: Address,
+    token_out: Address,
+    amount_in: U256,
+) -> Result<EvmQuote>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    let pool_address = pool.address();
+    let tokens = pool_tokens(pool);
+    if token_in == token_out || !tokens.contains(&token_in) || !tokens.contains(&token_out) {
+        return Err(anyhow!("No swap from {token_in} to {token_out} in {pool_address}"));
+    }
+    let call = swap_call(overlay, block, pool, token_in, token_out, amount_in)?;
+    setup_helper(overlay, block, helper_code, token_in, amount_in, call.spender)?;
+
+    let balance_before = balance_of(overlay, block, token_out)?;
+    let mut calldata = call.target.into_word().to_vec();
+    calldata.extend(call.calldata);
+    let mut evm = sim_evm(&mut *overlay, block)
+        .modify_tx_env(|tx| {
+            tx.caller = OWNER;
+            tx.transact_to = TransactTo::Call(HELPER);
+            tx.data = calldata.into();
+            tx.value = U256::ZERO;
+        })
+        .build();
+    let result = evm.transact_commit().map_err(|e| anyhow!("{e:?}"))?;
+    drop(evm);
+    let gas_used = match result {
+        ExecutionResult::Success { gas_used, .. } => gas_used,
+        ExecutionResult::Revert { output, .. } => return Err(anyhow!("Swap on {pool_address} reverted {output}")),
+        ExecutionResult::Halt { reason, .. } => return Err(anyhow!("Swap on {pool_address} halted {reason:?}")),
+    };
+
+    let balance_after = balance_of(overlay, block, token_out)?;
+    Ok(EvmQuote {
+        amount_out: balance_after.saturating_sub(balance_before),
+        gas_used,
+    })
+}
+
+// Inject the helper and fund it with the input
+fn setup_helper<DB>(
+    overlay: &mut CacheDB<DB>,
+    block: &BlockContext,
+    helper_code: &Bytecode,
+    token_in: Address,
+    amount_in: U256,
+    spender: Option<Address>,
+) -> Result<()>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    inject_helper(overlay, helper_code, token_in, amount_in, spender)?;
+
+    let slot = balance_slot(overlay, block, token_in).ok_or_else(|| anyhow!("No balance slot for {token_in}"))?;
+    let helper_slot = slot.slot(HELPER);
+    let current = overlay.storage(token_in, helper_slot).map_err(|e| anyhow!("{e:?}"))?;
+    overlay.write_slot(token_in, helper_slot, slot.pack(current, amount_in))
+}
+
+// Deploy the helper into the overlay with its storage set up for the swap
+fn inject_helper<DB>(
+    overlay: &mut CacheDB<DB>,
+    helper_code: &Bytecode,
+    token_in: Address,
+    amount_in: U256,
+    spender: Option<Address>,
+) -> Result<()>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    overlay.insert_account_info(
+        HELPER,
+        AccountInfo {
+            nonce: 0,
+            balance: U256::ZERO,
+            code_hash: helper_code.hash_slow(),
+            code: Some(helper_code.clone()),
+        },
+    );
+    let storage = [
+        (U256::from(0), token_in.into_word().into()),
+        (U256::from(1), amount_in),
+        (U256::from(2), OWNER.into_word().into()),
+        (U256::from(3), spender.unwrap_or_default().into_word().into()),
+    ];
+    for (slot, value) in storage {
+        overlay.write_slot(HELPER, slot, value)?;
+    }
+    Ok(())
+}
+
+// Encode the swap for the protocol, the helper is always the recipient
+fn swap_call<DB>(
+    overlay: &mut CacheDB<DB>,
+    block: &BlockContext,
+    pool: &Pool,
+    token_in: Address,
+    token_out: Address,
+    amount_in: U256,
+) -> Result<SwapCall>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    let address = pool.address();
+    if let Ok(StorageLayout::V3(_)) = storage_layout(pool.pool_type()) {
+        let zero_for_one = token_in == pool.token0_address();
+        let limit = if zero_for_one {
+            U256::from(MIN_SQRT_RATIO) + U256::from(1)
+        } else {
+            MAX_SQRT_RATIO - U256::from(1)
+        };
+        let calldata = SwapEntrypoints::swapCall {
+            recipient: HELPER,
+            zeroForOne: zero_for_one,
+            amountSpecified: amount_in.try_into()?,
+            sqrtPriceLimitX96: limit.to(),
+            data: Bytes::new(),
+        };
+        return Ok(SwapCall {
+            target: address,
+            spender: None,
+            calldata: calldata.abi_encode(),
+        });
+    }
+
+    let call = match pool.pool_type() {
+        // v1 pools have a different swap signature, they are not quoted
+        PoolType::MaverickV2 => {
+            let token_a_in = token_in == pool.token0_address();
+            let calldata = MaverickV2Pool::swapCall {
+                recipient: HELPER,
+                params: MaverickV2Pool::SwapParams {
+                    amount: amount_in,
+                    tokenAIn: token_a_in,
+                    exactOutput: false,
+                    tickLimit: if token_a_in { i32::MAX } else { i32::MIN },
+                },
+                data: Bytes::new(),
+            };
+            SwapCall {
+                target: address,
+                spender: None,
+                calldata: calldata.abi_encode(),
+            }
+        }
+        PoolType::BalancerV2 => {
+            let pool_id = view(overlay, block, address, BalancerVault::getPoolIdCall {}.abi_encode())?;
+            let calldata = BalancerVault::swapCall {
+                singleSwap: BalancerVault::SingleSwap {
+                    poolId: FixedBytes::<32>::abi_decode(&pool_id)?,
+                    kind: 0,
+                    assetIn: token_in,
+                    assetOut: token_out,
+                    amount: amount_in,
+                    userData: Bytes::new(),
+                },
+                funds: BalancerVault::FundManagement {
+                    sender: HELPER,
+                    fromInternalBalance: false,
+                    recipient: HELPER,
+                    toInternalBalance: false,
+                },
+                limit: U256::ZERO,
+                deadline: U256::MAX,
+            };
+            SwapCall {
+                target: BALANCER_VAULT,
+                spender: Some(BALANCER_VAULT),
+                calldata: calldata.abi_encode(),
+            }
+        }
+        PoolType::CurveTwoCrypto | PoolType::CurveTriCrypto => {
+            let tokens = pool_tokens(pool);
+            let i = tokens.iter().position(|token| *token == token_in).unwrap();
+            let j = tokens.iter().position(|token| *token == token_out).unwrap();
+            let calldata = CurveCryptoPool::exchangeCall {
+                i: U256::from(i),
+                j: U256::from(j),
+                dx: amount_in,
+                min_dy: U256::ZERO,
+            };
+            SwapCall {
+                target: address,
+                spender: Some(address),
+                calldata: calldata.abi_encode(),
+            }
+        }
+        // v2 style pairs need the output up front, they all have native math
+        other => return Err(anyhow!("No swap encoding for {other:?}")),
+    };
+    Ok(call)
+}
+
+fn pool_tokens(pool: &Pool) -> Vec<Address> {
+    match pool {
+        Pool::BalancerV2(balancer_pool) => balancer_pool.get_tokens(),
+        Pool::CurveTriCrypto(curve_pool) => curve_pool.get_tokens(),
+        Pool::CurveTwoCrypto(curve_pool) => curve_pool.get_tokens(),
+        _ => vec![pool.token0_address(), pool.token1_address()],
+    }
+}
+
+fn balance_of<DB>(overlay: &mut CacheDB<DB>, block: &BlockContext, token: Address) -> Result<U256>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    let output = view(overlay, block, token, ERC20Token::balanceOfCall { account: HELPER }.abi_encode())?;
+    Ok(U256::abi_decode(&output)?)
+}
+
+fn view<DB>(overlay: &mut CacheDB<DB>, block: &BlockContext, to: Address, calldata: Vec<u8>) -> Result<Bytes>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    let mut evm = sim_evm(&mut *overlay, block)
+        .modify_tx_env(|tx| {
+            tx.caller = OWNER;
+            tx.transact_to = TransactTo::Call(to);
+            tx.data = calldata.into();
+            tx.value = U256::ZERO;
+        })
+        .build();
+    match evm.transact().map_err(|e| anyhow!("{e:?}"))?.result {
+        ExecutionResult::Success { output, .. } => Ok(output.into_data()),
+        _ => Err(anyhow!("Call to {to} failed")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::{context::Evm, database::EmptyDB};
+
+    // Stores the caller, the first two argument words and the selector, then returns true
+    const RECORDER_CODE: &str = "0x3360005560043560015560243560025560003560e01c600355600160005260206000f3";
+    const TOKEN: Address = address!("00000000000000000000000000000000000070c0");
+    const POOL: Address = address!("0000000000000000000000000000000000000b0b");
+    const SPENDER: Address = address!("0000000000000000000000000000000000005bed");
+
+    fn deploy(db: &mut CacheDB<EmptyDB>, address: Address, code: &str) {
+        let code = Bytecode::new_raw(Bytes::from_str(code).unwrap());
+        db.insert_account_info(
+            address,
+            AccountInfo {
+                nonce: 0,
+                balance: U256::ZERO,
+                code_hash: code.hash_slow(),
+                code: Some(code),
+            },
+        );
+    }
+
+    fn helper_db(spender: Option<Address>) -> CacheDB<EmptyDB> {
+        let mut db = CacheDB::new(EmptyDB::default());
+        inject_helper(&mut db, &helper_bytecode(), TOKEN, U256::from(1000), spender).unwrap();
+        deploy(&mut db, TOKEN, RECORDER_CODE);
+        db
+    }
+
+    fn call(db: &mut CacheDB<EmptyDB>, caller: Address, calldata: Vec<u8>) -> ExecutionResult {
+        let mut evm = Evm::builder()
+            .with_db(&mut *db)
+            .modify_tx_env(|tx| {
+                tx.caller = caller;
+                tx.transact_to = TransactTo::Call(HELPER);
+                tx.data = calldata.into();
+                tx.value = U256::ZERO;
+                tx.gas_limit = 1_000_000;
+            })
+            .build();
+        evm.transact_commit().unwrap()
+    }
+
+    // The slots the recorder wrote: caller, first word, second word, selector
+    fn recorded(db: &mut CacheDB<EmptyDB>, address: Address) -> [U256; 4] {
+        [0, 1, 2, 3].map(|slot| db.storage(address, U256::from(slot)).unwrap())
+    }
+
+    // Balancer is quoted natively from its synced pool, pools without native math run their code
+    #[test]
+    fn test_native_math_protocols() {
+        assert!(has_native_math(PoolType::UniswapV2));
+        assert!(has_native_math(PoolType::Slipstream));
+        assert!(has_native_math(PoolType::BalancerV2));
+        assert!(!has_native_math(PoolType::CurveTriCrypto));
+        assert!(!has_native_math(PoolType::MaverickV2));
+    }
+
+    // Any caller but the owner is paid the amount of the input token, this is the swap callback
+    #[test]
+    fn test_helper_pays_callbacks() {
+        let mut db = helper_db(None);
+        let result = call(&mut db, POOL, vec![0xfa, 0x46, 0x1e, 0x33]);
+        assert!(result.is_success(), "{result:?}");
+        assert_eq!(
+            recorded(&mut db, TOKEN),
+            [
+                HELPER.into_word().into(),
+                POOL.into_word().into(),
+                U256::from(1000),
+                U256::from(0xa9059cbbu32)
+            ]
+        );
+    }
+
+    // The owner call approves the spender on the input token, then forwards the rest of the
+    // calldata to the target and returns its output
+    #[test]
+    fn test_helper_forwards_owner_calls() {
+        let mut db = helper_db(Some(SPENDER));
+        deploy(&mut db, POOL, RECORDER_CODE);
+        let swap = CurveCryptoPool::exchangeCall {
+            i: U256::from(0),
+            j: U256::from(1),
+            dx: U256::from(1000),
+            min_dy: U256::ZERO,
+        };
+        let mut calldata = POOL.into_word().to_vec();
+        calldata.extend(swap.abi_encode());
+
+        let result = call(&mut db, OWNER, calldata);
+        assert_eq!(result.output().map(|output| U256::from_be_slice(output)), Some(U256::from(1)));
+        assert_eq!(
+            recorded(&mut db, TOKEN),
+            [
+                HELPER.into_word().into(),
+                SPENDER.into_word().into(),
+                U256::from(1000),
+                U256::from(0x095ea7b3u32)
+            ]
+        );
+        assert_eq!(
+            recorded(&mut db, POOL),
+            [
+                HELPER.into_word().into(),
+                U256::from(0),
+                U256::from(1),
+                U256::from_be_slice(&CurveCryptoPool::exchangeCall::SELECTOR)
+            ]
+        );
+    }
+
+    // A reverting target reverts the whole call
+    #[test]
+    fn test_helper_bubbles_reverts() {
+        let mut db = helper_db(None);
+        deploy(&mut db, POOL, "0x60006000fd");
+        let mut calldata = POOL.into_word().to_vec();
+        calldata.extend([0xde, 0xad, 0xbe, 0xef]);
+        assert!(matches!(call(&mut db, OWNER, calldata), ExecutionResult::Revert { .. }));
+    }
+}
@@ -0,0 +1,300 @@
+// Differential fuzzing of the native swap math against the pool bytecode. Every recorded fixture
+// is used as a base state, the pool state and input amount are then randomized and both the
+// native calculator and the evm are asked for the output. The first diverging input is shrunk
+// and reported by proptest
+use alloy::primitives::{Address, U160, U256};
+use pool_sync::{Pool, PoolInfo, PoolType};
+use proptest::prelude::*;
+use revm::database::CacheDB;
+use proptest::test_runner::{Config, TestError, TestRunner};
+use std::sync::Arc;
+use uniswap_v3_math::tick_math::{get_sqrt_ratio_at_tick, get_tick_at_sqrt_ratio, MAX_SQRT_RATIO, MIN_SQRT_RATIO};
+
+use crate::block_env::SimulateAt;
+use crate::calculation::evm::{helper_bytecode, swap_on, HELPER};
+use crate::calculation::Calculator;
+use crate::fixtures::{
+    evm_reference_out, load_fixtures, native_out, offline_market, reference_directions, setup_recorder,
+    OfflineMarket, PoolFixture, FIXTURE_DIR,
+};
+use crate::state_db::InsertionType;
+
+// Aerodrome pools keep 10^decimals of each token in storage, the stable curve scales by them
+const AERODROME_DECIMALS0_SLOT: u64 = 16;
+const AERODROME_DECIMALS1_SLOT: u64 = 17;
+
+// A randomized pool state to apply on top of the fixture
+#[derive(Debug, Clone)]
+enum Mutation {
+    // keep the recorded state
+    Recorded,
+    // new reserves for a v2 style pool
+    Reserves { reserve0: U256, reserve1: U256 },
+    // move a v3 style pool to a price right around an initialized tick
+    Price { sqrt_price: U256, liquidity: u128 },
+    // swap through the pool code first, the state it leaves behind is the new pool state
+    Swap { token_in: Address, token_out: Address, amount: U256 },
+    // tokens with decimals far from 18, on top of new reserves
+    Decimals {
+        decimals0: u8,
+        decimals1: u8,
+        reserve0: U256,
+        reserve1: U256,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct FuzzInput {
+    direction: usize,
+    amount_in: U256,
+    mutation: Mutation,
+}
+
+// A value with a random bit length up to max_bits, this puts equal weight on dust and on huge values
+fn log_uniform(max_bits: u32) -> impl Strategy<Value = U256> {
+    (0..=max_bits, any::<[u8; 32]>()).prop_map(|(bits, bytes)| {
+        let mask = if bits == 256 { U256::MAX } else { (U256::from(1) << bits) - U256::from(1) };
+        U256::from_be_bytes(bytes) & mask
+    })
+}
+
+// Swap a random amount in a random direction before the compared swap
+fn swap_strategy(directions: &[(Address, Address)]) -> BoxedStrategy<Mutation> {
+    (prop::sample::select(directions.to_vec()), log_uniform(128))
+        .prop_map(|((token_in, token_out), amount)| Mutation::Swap {
+            token_in,
+            token_out,
+            amount,
+        })
+        .boxed()
+}
+
+// Pool state mutations that make sense for the protocol
+fn mutation_strategy(pool: &Pool, directions: &[(Address, Address)]) -> BoxedStrategy<Mutation> {
+    match pool.pool_type() {
+        PoolType::UniswapV2
+        | PoolType::SushiSwapV2
+        | PoolType::PancakeSwapV2
+        | PoolType::BaseSwapV2
+        | PoolType::SwapBasedV2
+        | PoolType::DackieSwapV2
+        | PoolType::AlienBaseV2 => prop_oneof![
+            Just(Mutation::Recorded),
+            (log_uniform(112), log_uniform(112))
+                .prop_map(|(reserve0, reserve1)| Mutation::Reserves { reserve0, reserve1 }),
+        ]
+        .boxed(),
+        // the stable curve depends on the reserves being in line with the balances, a swap
+        // moves both. It is also the only v2 style math that depends on the token decimals
+        PoolType::Aerodrome => prop_oneof![
+            Just(Mutation::Recorded),
+            (log_uniform(112), log_uniform(112))
+                .prop_map(|(reserve0, reserve1)| Mutation::Reserves { reserve0, reserve1 }),
+            swap_strategy(directions),
+            (0..=36u8, 0..=36u8, log_uniform(112), log_uniform(112)).prop_map(
+                |(decimals0, decimals1, reserve0, reserve1)| Mutation::Decimals {
+                    decimals0,
+                    decimals1,
+                    reserve0,
+                    reserve1,
+                }
+            ),
+        ]
+        .boxed(),
+        _ if pool.is_v3() => {
+            // initialized ticks are where the swap has to cross a boundary
+            let v3_pool = pool.get_v3().unwrap();
+            let mut ticks: Vec<i32> = v3_pool.ticks.keys().copied().collect();
+            ticks.push(v3_pool.tick);
+            ticks.sort();
+            let tick_spacing = v3_pool.tick_spacing;
+            prop_oneof![
+                Just(Mutation::Recorded),
+                (
+                    prop::sample::select(ticks),
+                    -2..=2i32,
+                    -3..=3i64,
+                    log_uniform(128),
+                )
+                    .prop_map(move |(tick, spacing_offset, nudge, liquidity)| {
+                        let tick = tick + spacing_offset * tick_spacing;
+                        let sqrt_price = get_sqrt_ratio_at_tick(tick).unwrap_or(U256::from(MIN_SQRT_RATIO));
+                        let sqrt_price = if nudge < 0 {
+                            sqrt_price.saturating_sub(U256::from(-nudge))
+                        } else {
+                            sqrt_price.saturating_add(U256::from(nudge))
+                        };
+                        let sqrt_price = sqrt_price.clamp(U256::from(MIN_SQRT_RATIO), MAX_SQRT_RATIO - U256::from(1));
+                        Mutation::Price {
+                            sqrt_price,
+                            liquidity: liquidity.saturating_to(),
+                        }
+                    }),
+            ]
+            .boxed()
+        }
+        // balancer state is spread over the pool and the vault, so it is only moved by
+        // swapping through the pool
+        _ => prop_oneof![Just(Mutation::Recorded), swap_strategy(directions)].boxed(),
+    }
+}
+
+// Apply the mutation to the pool in a freshly loaded market
+fn apply_mutation(market: &OfflineMarket, fixture: &PoolFixture, mutation: &Mutation) {
+    let mut db = market.db.write().unwrap();
+    let address = fixture.pool.address();
+    match mutation {
+        Mutation::Recorded => {}
+        Mutation::Reserves { reserve0, reserve1 } => {
+            db.insert_reserves(address, *reserve0, *reserve1);
+        }
+        Mutation::Price {
+            sqrt_price,
+            liquidity,
+        } => {
+            let tick = get_tick_at_sqrt_ratio(*sqrt_price).unwrap();
+            db.insert_slot0(address, U160::from(*sqrt_price), tick).unwrap();
+            db.insert_liquidity(address, *liquidity).unwrap();
+        }
+        Mutation::Swap {
+            token_in,
+            token_out,
+            amount,
+        } => {
+            let block = db.block.at(SimulateAt::NextBlock);
+            let mut overlay = CacheDB::new(&*db);
+            // a swap that reverts leaves the recorded state
+            if swap_on(&mut overlay, &block, &helper_bytecode(), &fixture.pool, *token_in, *token_out, *amount).is_err() {
+                return;
+            }
+            let written: Vec<(Address, U256, U256)> = overlay
+                .accounts
+                .into_iter()
+                .filter(|(account, _)| *account != HELPER)
+                .flat_map(|(account, state)| state.storage.into_iter().map(move |(slot, value)| (account, slot, value)))
+                .collect();
+            for (account, slot, value) in written {
+                db.insert_account_storage(account, slot, value, InsertionType::Custom).unwrap();
+            }
+            db.refresh_pool_state(address);
+        }
+        Mutation::Decimals {
+            decimals0,
+            decimals1,
+            reserve0,
+            reserve1,
+        } => {
+            // the pool code reads the scales from storage, the native math from the synced pool
+            for (slot, decimals) in [(AERODROME_DECIMALS0_SLOT, *decimals0), (AERODROME_DECIMALS1_SLOT, *decimals1)] {
+                let scale = U256::from(10).pow(U256::from(decimals));
+                db.insert_account_storage(address, U256::from(slot), scale, InsertionType::Custom).unwrap();
+            }
+            if let Some(Pool::Aerodrome(pool)) = db.pool_info.get_mut(&address) {
+                pool.token0_decimals = *decimals0;
+                pool.token1_decimals = *decimals1;
+            }
+            db.insert_reserves(address, *reserve0, *reserve1);
+        }
+    }
+}
+
+// Market over the recorded state with the recorder funded, every case starts from a fresh one
+// so nothing an earlier case committed leaks into it
+fn fresh_market(fixture: &PoolFixture, weth: Address) -> Arc<OfflineMarket> {
+    let market = offline_market(fixture);
+    setup_recorder(&mut market.db.write().unwrap(), weth).unwrap();
+    market
+}
+
+// Curve and maverick are quoted by running their code, comparing them against the evm would
+// compare the evm with itself
+fn is_fuzzed(pool_type: PoolType) -> bool {
+    !matches!(
+        pool_type,
+        PoolType::CurveTwoCrypto | PoolType::CurveTriCrypto | PoolType::MaverickV2
+    )
+}
+
+// Fuzz a single fixture, returns the first diverging input if there is one
+fn fuzz_fixture(fixture: &PoolFixture, weth: Address, cases: u32) -> Option<String> {
+    let directions = match reference_directions(&fixture.pool, weth) {
+        Ok(directions) => directions,
+        Err(_) => return None,
+    };
+
+    let strategy = (0..directions.len(), log_uniform(128), mutation_strategy(&fixture.pool, &directions)).prop_map(
+        |(direction, amount_in, mutation)| FuzzInput {
+            direction,
+            amount_in,
+            mutation,
+        },
+    );
+
+    let mut runner = TestRunner::new(Config {
+        cases,
+        failure_persistence: None,
+        ..Config::default()
+    });
+    let result = runner.run(&strategy, |input| {
+        let market = fresh_market(fixture, weth);
+        let calculator = Calculator::new(market.clone());
+        apply_mutation(&market, fixture, &input.mutation);
+        let (token_in, token_out) = directions[input.direction];
+
+        // the evm reverting means the input is not swappable at all, nothing to compare
+        let evm_out = {
+            let mut db = market.db.write().unwrap();
+            evm_reference_out(&mut db, &fixture.pool, token_in, token_out, input.amount_in)
+        };
+        let evm_out = match evm_out {
+            Ok(out) => out,
+            Err(_) => return Ok(()),
+        };
+
+        let native = native_out(&calculator, &fixture.pool, token_in, token_out, input.amount_in);
+        prop_assert_eq!(native, evm_out);
+        Ok(())
+    });
+
+    match result {
+        Err(TestError::Fail(reason, input)) => Some(format!(
+            "{:?} {}: {}\n  minimal input: {:?}",
+            fixture.pool.pool_type(),
+            fixture.pool.address(),
+            reason,
+            input
+        )),
+        Err(TestError::Abort(reason)) => Some(format!(
+            "{:?} {}: aborted {}",
+            fixture.pool.pool_type(),
+            fixture.pool.address(),
+            reason
+        )),
+        Ok(()) => None,
+    }
+}
+
+// Native math must agree with the pool bytecode for any pool state and amount. Ignored until
+// the fixtures it starts from are recorded into FIXTURE_DIR
+#[test]
+#[ignore]
+fn fuzz_native_against_evm() {
+    dotenv::dotenv().ok();
+    let weth: Address = std::env::var("WETH").unwrap().parse().unwrap();
+    let fixtures = load_fixtures(FIXTURE_DIR).unwrap();
+    assert!(
+        !fixtures.is_empty(),
+        "No fixtures in {FIXTURE_DIR}, record them with `cargo test record_fixtures -- --ignored`"
+    );
+
+    let cases: u32 = std::env::var("FUZZ_CASES")
+        .ok()
+        .and_then(|cases| cases.parse().ok())
+        .unwrap_or(256);
+    let divergences: Vec<String> = fixtures
+        .iter()
+        .filter(|fixture| is_fuzzed(fixture.pool.pool_type()))
+        .filter_map(|fixture| fuzz_fixture(fixture, weth, cases))
+        .collect();
+    assert!(divergences.is_empty(), "{}", divergences.join("\n"));
+}
@@ -1,4 +1,5 @@
 use super::Calculator;
+use crate::block_env::{sim_evm, SimulateAt};
 use crate::traits::*;
 use crate::types::*;
 use alloy::sol;
@@ -10,7 +11,7 @@ use alloy::transports::Transport;
 use alloy::providers::Provider;
 use alloy::dyn_abi::SolType;
 use revm::{
-    context::{ ContextTr, Evm},
+    context::ContextTr,
     context_interface::{
         result::{ ExecutionResult},
         TransactTo, JournalTr,
@@ -64,9 +65,9 @@ where
         .abi_encode();
 
         // get the db and construct our evm
-        let mut db = self.db.write().unwrap();
-        let mut evm = Evm::builder()
-            .with_db(&mut *db)
+        let mut db = self.market_state.db.write().unwrap();
+        let block = db.block.at(SimulateAt::NextBlock);
+        let mut evm = sim_evm(&mut *db, &block)
             .modify_tx_env(|tx| {
                 tx.caller = address!("0000000000000000000000000000000000000001");
                 tx.transact_to =
@@ -3,6 +3,9 @@ pub mod aerodrome;
 pub mod balancer;
 pub mod calculator;
 pub mod curve;
+pub mod evm;
+#[cfg(test)]
+mod fuzz;
 pub mod maverick;
 pub mod uniswap;
 pub use calculator::Calculator;
@@ -1,4 +1,5 @@
 use super::Calculator;
+use crate::state_db::PoolState;
 use crate::traits::*;
 use crate::types::*;
 use alloy::transports::Transport;
@@ -7,7 +8,6 @@ use alloy::primitives::Address;
 use alloy::primitives::{I256, U256};
 use alloy::providers::Provider;
 use anyhow::Result;
-use std::collections::HashMap;
 use uniswap_v3_math::tick_math::{MAX_SQRT_RATIO, MAX_TICK, MIN_SQRT_RATIO, MIN_TICK};
 
 pub const U256_1: U256 = U256::from_limbs([1, 0, 0, 0]);
@@ -31,11 +31,6 @@ pub struct StepComputations {
     pub fee_amount: U256,
 }
 
-//Computes the position in the mapping where the initialized bit for a tick lives
-pub fn position(tick: i32) -> (i16, u8) {
-    ((tick >> 8) as i16, (tick % 256) as u8)
-}
-
 impl<T, N, P> Calculator<T, N, P>
 where
     T: Transport + Clone,
@@ -54,7 +49,10 @@ where
         // get read access to db
         let db_read = self.market_state.db.read().unwrap();
         let zero_to_one = db_read.zero_to_one(pool_address, *token_in).unwrap();
-        let (reserve0, reserve1) = db_read.get_reserves(pool_address);
+        let (reserve0, reserve1) = match db_read.pool_state(pool_address) {
+            Ok(PoolState::V2 { reserve0, reserve1 }) => (*reserve0, *reserve1),
+            _ => return U256::ZERO,
+        };
 
         let scalar = U256::from(10000);
 
@@ -70,7 +68,8 @@ where
         numerator / denominator
     }
 
-    // calculate the amount out for a uniswapv3 swap
+    // calculate the amount out for a uniswapv3 swap. A swap that walks into a bitmap word that is
+    // not loaded fails with `InsufficientTickData`
     #[inline]
     pub fn uniswap_v3_out(
         &self,
@@ -86,9 +85,12 @@ where
         // acquire db read access and get all our state information
         let db_read = self.market_state.db.read().unwrap();
         let zero_to_one = db_read.zero_to_one(pool_address, *token_in).unwrap();
-        let slot0 = db_read.slot0(*pool_address)?;
-        let liquidity = db_read.liquidity(*pool_address)?;
+        let (sqrt_price, tick, liquidity) = match db_read.pool_state(pool_address)? {
+            PoolState::V3 { sqrt_price, tick, liquidity } => (*sqrt_price, *tick, *liquidity),
+            _ => return Err(anyhow::anyhow!("{pool_address} has no v3 state")),
+        };
         let tick_spacing = db_read.tick_spacing(pool_address)?;
+        let tick_index = db_read.tick_index(pool_address)?;
 
         // Set sqrt_price_limit_x_96 to the max or min sqrt price in the pool depending on zero_for_one
         let sqrt_price_limit_x_96 = if zero_to_one {
@@ -99,10 +101,10 @@ where
 
         // Initialize a mutable state state struct to hold the dynamic simulated state of the pool
         let mut current_state = CurrentState {
-            sqrt_price_x_96: slot0.sqrtPriceX96.to(), //Active price on the pool
+            sqrt_price_x_96: sqrt_price, //Active price on the pool
             amount_calculated: I256::ZERO,            //Amount of token_out that has been calculated
             amount_specified_remaining: I256::from_raw(amount_in), //Amount of token_in that has not been swapped
-            tick: slot0.tick.as_i32(),
+            tick,
             liquidity, //Current available liquidity in the tick range
         };
 
@@ -118,21 +120,13 @@ where
                 ..Default::default()
             };
 
-            let mut tick_bitmap: HashMap<i16, U256> = HashMap::new();
-            let (word_pos, _bit_pos) = position(current_state.tick / (tick_spacing));
-
-            for i in word_pos - 1..=word_pos + 1 {
-                tick_bitmap.insert(i, db_read.tick_bitmap(*pool_address, i).unwrap_or_default());
-            }
-
             // Get the next tick from the current tick
-            (step.tick_next, step.initialized) =
-                uniswap_v3_math::tick_bitmap::next_initialized_tick_within_one_word(
-                    &tick_bitmap,
-                    current_state.tick,
-                    tick_spacing,
-                    zero_to_one,
-                )?;
+            (step.tick_next, step.initialized) = tick_index.next_initialized_tick_within_one_word(
+                *pool_address,
+                current_state.tick,
+                tick_spacing,
+                zero_to_one,
+            )?;
 
             // ensure that we do not overshoot the min/max tick, as the tick bitmap is not aware of these bounds
             // Note: this could be removed as we are clamping in the batch contract
@@ -174,8 +168,7 @@ where
             // Update tick and liquidity only if needed for next iteration
             if current_state.sqrt_price_x_96 == step.sqrt_price_next_x96 {
                 if step.initialized {
-                    let mut liquidity_net: i128 =
-                        db_read.ticks_liquidity_net(*pool_address, step.tick_next)?;
+                    let mut liquidity_net: i128 = tick_index.liquidity_net(step.tick_next);
 
                     if zero_to_one {
                         liquidity_net = -liquidity_net;
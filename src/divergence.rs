@@ -0,0 +1,376 @@
+use alloy::primitives::{Address, U256};
+use dashmap::DashMap;
+use log::{info, warn};
+use pool_sync::PoolType;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::swap::SwapPath;
+
+// Why a pool or path was pulled out of the search
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuarantineReason {
+    // native math kept disagreeing with the evm quote
+    PersistentDivergence,
+    // the evm quote reverted
+    SimulationReverted,
+    // a transfer of the token reverted during a quote
+    TransferReverted,
+}
+
+// When a quarantine is lifted
+#[derive(Debug, Clone, Copy)]
+pub enum Expiry {
+    Block(u64),
+    Time(Instant),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Quarantine {
+    pub reason: QuarantineReason,
+    pub since_block: u64,
+    pub expiry: Expiry,
+}
+
+impl Quarantine {
+    fn expired(&self, block_number: u64) -> bool {
+        match self.expiry {
+            Expiry::Block(until) => block_number >= until,
+            Expiry::Time(until) => Instant::now() >= until,
+        }
+    }
+}
+
+// Running comparison of native and evm outputs for a pool
+#[derive(Debug, Default, Clone)]
+struct PoolDivergence {
+    samples: u64,
+    divergent: u64,
+    // consecutive divergent samples, resets on an agreeing sample
+    strikes: u32,
+    last_error_bps: u64,
+    max_error_bps: u64,
+}
+
+// Aggregated comparison for a whole protocol
+#[derive(Debug, Default, Clone)]
+struct ProtocolDivergence {
+    samples: u64,
+    divergent: u64,
+    total_error_bps: u64,
+    max_error_bps: u64,
+}
+
+// Tracks how far the native calculators are from the evm quotes and quarantines pools
+// whose math keeps disagreeing. Shared between the simulator, which records, and the
+// searcher, which excludes quarantined pools from its index
+pub struct DivergenceTracker {
+    pools: DashMap<Address, PoolDivergence>,
+    protocols: DashMap<PoolType, ProtocolDivergence>,
+    quarantined_pools: DashMap<Address, Quarantine>,
+    quarantined_paths: DashMap<u64, Quarantine>,
+    quarantined_tokens: DashMap<Address, Quarantine>,
+    // pools quarantined since the searcher last refreshed
+    pending: Mutex<Vec<Address>>,
+    tolerance_bps: u64,
+    strike_limit: u32,
+    quarantine_blocks: Option<u64>,
+    quarantine_duration: Duration,
+}
+
+impl DivergenceTracker {
+    pub fn new(
+        tolerance_bps: u64,
+        strike_limit: u32,
+        quarantine_blocks: Option<u64>,
+        quarantine_duration: Duration,
+    ) -> Self {
+        Self {
+            pools: DashMap::new(),
+            protocols: DashMap::new(),
+            quarantined_pools: DashMap::new(),
+            quarantined_paths: DashMap::new(),
+            quarantined_tokens: DashMap::new(),
+            pending: Mutex::new(Vec::new()),
+            tolerance_bps,
+            strike_limit,
+            quarantine_blocks,
+            quarantine_duration,
+        }
+    }
+
+    // Construct the tracker from the environment. Block based expiry is used if
+    // QUARANTINE_BLOCKS is set, otherwise QUARANTINE_SECS
+    pub fn from_env() -> Self {
+        let tolerance_bps = env_or("DIVERGENCE_TOLERANCE_BPS", 10);
+        let strike_limit = env_or("DIVERGENCE_STRIKES", 3) as u32;
+        let quarantine_blocks = std::env::var("QUARANTINE_BLOCKS")
+            .ok()
+            .and_then(|blocks| blocks.parse().ok());
+        let quarantine_secs = env_or("QUARANTINE_SECS", 600);
+        Self::new(
+            tolerance_bps,
+            strike_limit,
+            quarantine_blocks,
+            Duration::from_secs(quarantine_secs),
+        )
+    }
+
+    // Compare the calculated amounts along a path to the quoted amounts. Both vectors start
+    // with the input amount. Each hop is compared on its rate so error from an earlier hop
+    // is not attributed to the pools after it
+    pub fn record(&self, path: &SwapPath, calculated: &[U256], quoted: &[U256], block_number: u64) {
+        for (hop, step) in path.steps.iter().enumerate() {
+            let (Some(c_in), Some(c_out), Some(q_in), Some(q_out)) = (
+                calculated.get(hop),
+                calculated.get(hop + 1),
+                quoted.get(hop),
+                quoted.get(hop + 1),
+            ) else {
+                return;
+            };
+            let error_bps = relative_error_bps(*c_in, *c_out, *q_in, *q_out);
+            let divergent = error_bps > self.tolerance_bps;
+
+            let mut protocol = self.protocols.entry(step.protocol).or_default();
+            protocol.samples += 1;
+            protocol.total_error_bps = protocol.total_error_bps.saturating_add(error_bps);
+            protocol.max_error_bps = protocol.max_error_bps.max(error_bps);
+            if divergent {
+                protocol.divergent += 1;
+            }
+            drop(protocol);
+
+            let mut pool = self.pools.entry(step.pool_address).or_default();
+            pool.samples += 1;
+            pool.last_error_bps = error_bps;
+            pool.max_error_bps = pool.max_error_bps.max(error_bps);
+            if divergent {
+                pool.divergent += 1;
+                pool.strikes += 1;
+            } else {
+                pool.strikes = 0;
+            }
+            let quarantine = pool.strikes >= self.strike_limit;
+            drop(pool);
+
+            if quarantine {
+                warn!(
+                    "Quarantining pool {} ({:?}), native math off by {} bps",
+                    step.pool_address, step.protocol, error_bps
+                );
+                self.quarantine_pool(step.pool_address, QuarantineReason::PersistentDivergence, block_number);
+            }
+        }
+    }
+
+    // Pull a pool out of the search
+    pub fn quarantine_pool(&self, pool: Address, reason: QuarantineReason, block_number: u64) {
+        if self.quarantined_pools.contains_key(&pool) {
+            return;
+        }
+        self.quarantined_pools.insert(pool, self.new_quarantine(reason, block_number));
+        if let Some(mut pool) = self.pools.get_mut(&pool) {
+            pool.strikes = 0;
+        }
+        self.pending.lock().unwrap().push(pool);
+    }
+
+    // Stop simulating a path
+    pub fn quarantine_path(&self, hash: u64, reason: QuarantineReason, block_number: u64) {
+        self.quarantined_paths.insert(hash, self.new_quarantine(reason, block_number));
+    }
+
+    // Stop simulating every path that swaps through a token
+    pub fn quarantine_token(&self, token: Address, reason: QuarantineReason, block_number: u64) {
+        self.quarantined_tokens.insert(token, self.new_quarantine(reason, block_number));
+    }
+
+    // Check if the path swaps through a quarantined token, lifting expired quarantines
+    pub fn has_quarantined_token(&self, path: &SwapPath, block_number: u64) -> bool {
+        path.steps.iter().any(|step| {
+            [step.token_in, step.token_out].iter().any(|token| {
+                let expired = match self.quarantined_tokens.get(token) {
+                    Some(quarantine) => quarantine.expired(block_number),
+                    None => return false,
+                };
+                if expired {
+                    self.quarantined_tokens.remove(token);
+                }
+                !expired
+            })
+        })
+    }
+
+    // Check if the path is quarantined, lifting it if it has expired
+    pub fn is_path_quarantined(&self, hash: u64, block_number: u64) -> bool {
+        let expired = match self.quarantined_paths.get(&hash) {
+            Some(quarantine) => quarantine.expired(block_number),
+            None => return false,
+        };
+        if expired {
+            self.quarantined_paths.remove(&hash);
+        }
+        !expired
+    }
+
+    // Get the pools quarantined since the last refresh and the pools whose quarantine expired
+    pub fn refresh(&self, block_number: u64) -> (Vec<Address>, Vec<Address>) {
+        let quarantined = std::mem::take(&mut *self.pending.lock().unwrap());
+
+        let released: Vec<Address> = self
+            .quarantined_pools
+            .iter()
+            .filter(|entry| entry.value().expired(block_number))
+            .map(|entry| *entry.key())
+            .collect();
+        for pool in &released {
+            self.quarantined_pools.remove(pool);
+            info!("Released pool {} from quarantine", pool);
+        }
+        self.quarantined_paths
+            .retain(|_, quarantine| !quarantine.expired(block_number));
+        self.quarantined_tokens
+            .retain(|_, quarantine| !quarantine.expired(block_number));
+
+        (quarantined, released)
+    }
+
+    // Log the divergence stats per protocol and the current quarantine
+    pub fn log_stats(&self) {
+        for entry in self.protocols.iter() {
+            let stats = entry.value();
+            let mean_error_bps = stats.total_error_bps.checked_div(stats.samples).unwrap_or(0);
+            info!(
+                "Divergence {:?}: {} samples, {} divergent, mean {} bps, max {} bps",
+                entry.key(),
+                stats.samples,
+                stats.divergent,
+                mean_error_bps,
+                stats.max_error_bps
+            );
+        }
+        for entry in self.quarantined_pools.iter() {
+            let quarantine = entry.value();
+            let max_error_bps = self
+                .pools
+                .get(entry.key())
+                .map(|pool| pool.max_error_bps)
+                .unwrap_or_default();
+            info!(
+                "Quarantined pool {}: {:?} since block {}, max error {} bps",
+                entry.key(),
+                quarantine.reason,
+                quarantine.since_block,
+                max_error_bps
+            );
+        }
+        info!(
+            "{} pools, {} tokens and {} paths in quarantine",
+            self.quarantined_pools.len(),
+            self.quarantined_tokens.len(),
+            self.quarantined_paths.len()
+        );
+    }
+
+    fn new_quarantine(&self, reason: QuarantineReason, block_number: u64) -> Quarantine {
+        let expiry = match self.quarantine_blocks {
+            Some(blocks) => Expiry::Block(block_number + blocks),
+            None => Expiry::Time(Instant::now() + self.quarantine_duration),
+        };
+        Quarantine {
+            reason,
+            since_block: block_number,
+            expiry,
+        }
+    }
+}
+
+// Relative difference between the calculated and quoted rate of a hop in bps
+fn relative_error_bps(c_in: U256, c_out: U256, q_in: U256, q_out: U256) -> u64 {
+    // compare c_out / c_in against q_out / q_in without dividing
+    let calculated_rate = c_out.saturating_mul(q_in);
+    let quoted_rate = q_out.saturating_mul(c_in);
+    if calculated_rate == quoted_rate {
+        return 0;
+    }
+    if quoted_rate.is_zero() {
+        return u64::MAX;
+    }
+    let diff = calculated_rate.abs_diff(quoted_rate);
+    diff.saturating_mul(U256::from(10000))
+        .checked_div(quoted_rate)
+        .unwrap_or(U256::MAX)
+        .saturating_to()
+}
+
+fn env_or(key: &str, default: u64) -> u64 {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::swap::SwapStep;
+    use alloy::primitives::address;
+
+    fn path() -> SwapPath {
+        let weth = address!("4200000000000000000000000000000000000006");
+        let usdc = address!("833589fcd6edb6e08f4c7c32d4f71b54bda02913");
+        let step = |pool_address, token_in, token_out| SwapStep {
+            pool_address,
+            token_in,
+            token_out,
+            protocol: PoolType::UniswapV2,
+            fee: 0,
+            stable: false,
+        };
+        SwapPath::new(vec![
+            step(address!("00000000000000000000000000000000000000aa"), weth, usdc),
+            step(address!("00000000000000000000000000000000000000bb"), usdc, weth),
+        ])
+    }
+
+    // Error is measured on each hop's rate, an off first hop does not strike the second pool
+    #[test]
+    fn test_divergent_pool_is_quarantined_after_strikes() {
+        let tracker = DivergenceTracker::new(10, 2, Some(5), Duration::from_secs(600));
+        let path = path();
+        let amount = |v: u64| U256::from(v);
+        // the first hop is off by 1%, the second hop has the same rate in both
+        let calculated = [amount(1000), amount(2020), amount(1010)];
+        let quoted = [amount(1000), amount(2000), amount(1000)];
+
+        tracker.record(&path, &calculated, &quoted, 100);
+        assert_eq!(tracker.refresh(100), (vec![], vec![]));
+        tracker.record(&path, &calculated, &quoted, 101);
+
+        let pool = path.steps[0].pool_address;
+        assert_eq!(tracker.refresh(101), (vec![pool], vec![]));
+        assert!(tracker.quarantined_pools.contains_key(&pool));
+        assert!(!tracker.quarantined_pools.contains_key(&path.steps[1].pool_address));
+
+        // released once the quarantine blocks have passed
+        assert_eq!(tracker.refresh(106), (vec![], vec![pool]));
+    }
+
+    #[test]
+    fn test_path_quarantine_expires() {
+        let tracker = DivergenceTracker::new(10, 2, Some(5), Duration::from_secs(600));
+        tracker.quarantine_path(7, QuarantineReason::SimulationReverted, 100);
+        assert!(tracker.is_path_quarantined(7, 104));
+        assert!(!tracker.is_path_quarantined(7, 105));
+        assert!(!tracker.is_path_quarantined(7, 104));
+    }
+
+    #[test]
+    fn test_relative_error_bps() {
+        let v = U256::from;
+        assert_eq!(relative_error_bps(v(100), v(200), v(100), v(200)), 0);
+        assert_eq!(relative_error_bps(v(100), v(201), v(100), v(200)), 50);
+        assert_eq!(relative_error_bps(v(100), v(1), v(100), v(0)), u64::MAX);
+    }
+}
@@ -39,6 +39,7 @@ where
     aggregated_weth_rate: HashMap<Address, U256>,
     // Decimals in token
     token_decimals: HashMap<Address, u32>,
+    weth: Address,
 }
 
 impl<T, N, P> Estimator<T, N, P>
@@ -56,6 +57,7 @@ where
             calculator: Calculator::new(market_state.clone()),
             aggregated_weth_rate: HashMap::new(),
             token_decimals: HashMap::new(),
+            weth: std::env::var("WETH").unwrap().parse().unwrap(),
         }
     }
 
@@ -63,15 +65,19 @@ where
     pub fn update_rates(&mut self, pool_addrs: &HashSet<Address>) {
         // get all pools corresponding to updated pool addresses
         let db = self.market_state.db.read().unwrap();
-        let pools: Vec<Pool> = pool_addrs.iter().map(|p| db.get_pool(p).clone()).collect();
+        let pools: Vec<Pool> = pool_addrs
+            .iter()
+            .filter(|p| db.tracking_pool(p))
+            .map(|p| db.get_pool(p).clone())
+            .collect();
         drop(db);
 
         self.process_pools(pools);
     }
 
-    // Given a path, estimate the output
-    pub fn estimate_output_amount(&self, swap_path: &SwapPath) -> U256 {
-        let mut current_amount = *AMOUNT;
+    // Given a path and its input amount, estimate the output
+    pub fn estimate_output_amount(&self, swap_path: &SwapPath, amount_in: U256) -> U256 {
+        let mut current_amount = amount_in;
 
         // Follow the path and apply rates sequentially
         for step in &swap_path.steps {
@@ -92,6 +98,38 @@ where
         current_amount
     }
 
+    // The estimated rate for swapping token_in through the pool, scaled by RATE_SCALE_VALUE
+    pub fn rate(&self, pool: &Address, token_in: &Address) -> Option<U256> {
+        self.rates.get(pool)?.get(token_in).copied()
+    }
+
+    // Convert an amount of token into its value in weth
+    pub fn eth_value(&self, token: Address, amount: U256) -> Option<U256> {
+        if self.is_weth(token) {
+            return Some(amount);
+        }
+        let rate = self.aggregated_weth_rate.get(&token).filter(|rate| !rate.is_zero())?;
+        let decimals = *self.token_decimals.get(&token).unwrap_or(&18);
+        self.scale_to_rate(amount, decimals)
+            .checked_mul(*RATE_SCALE_VALUE)?
+            .checked_div(*rate)
+    }
+
+    // Convert an amount of weth into the equivalent amount of token
+    pub fn eth_equivalent(&self, token: Address, eth_amount: U256) -> Option<U256> {
+        if self.is_weth(token) {
+            return Some(eth_amount);
+        }
+        let rate = self.aggregated_weth_rate.get(&token).filter(|rate| !rate.is_zero())?;
+        let decimals = *self.token_decimals.get(&token).unwrap_or(&18);
+        let scaled = eth_amount.checked_mul(*rate)?.checked_div(*RATE_SCALE_VALUE)?;
+        Some(self.scale_from_rate(scaled, decimals))
+    }
+
+    fn is_weth(&self, token: Address) -> bool {
+        self.weth == token
+    }
+
     // Given a swappath, estimate if it is profitable based on calculated rates
     pub fn is_profitable(&self, swap_path: &SwapPath, min_profit_ratio: U256) -> bool {
         let mut cumulative_rate = *RATE_SCALE_VALUE; // Start with 1.0 in our scaled format
@@ -124,6 +162,15 @@ where
         }
     }
 
+    // Scale a number from our rate precision back to the token decimals
+    fn scale_from_rate(&self, amount: U256, token_decimals: u32) -> U256 {
+        if token_decimals <= RATE_SCALE {
+            amount / U256::from(10u64.pow(RATE_SCALE - token_decimals))
+        } else {
+            amount * U256::from(10u64.pow(token_decimals - RATE_SCALE))
+        }
+    }
+
     // Calculate the exchange rate with proper scaling
     fn calculate_rate(
         &self,
@@ -144,7 +191,7 @@ where
 
     // Given an initial set of filtered pools, estimate the exchange rates
     pub fn process_pools(&mut self, pools: Vec<Pool>) {
-        let weth: Address = std::env::var("WETH").unwrap().parse().unwrap();
+        let weth = self.weth;
         let mut alt_tokens: HashSet<Address> = HashSet::new();
         let mut weth_alt_cnt: HashMap<Address, u32> = HashMap::new();
 
@@ -213,6 +260,7 @@ where
         let alt_output = self.calculator.compute_pool_output(
             pool_address,
             weth,
+            alt,
             pool.pool_type(),
             pool.fee(),
             input,
@@ -225,6 +273,7 @@ where
         let other_output = self.calculator.compute_pool_output(
             pool_address,
             alt,
+            weth,
             pool.pool_type(),
             pool.fee(),
             alt_output,
@@ -266,6 +315,7 @@ where
             let output = self.calculator.compute_pool_output(
                 pool_address,
                 token0,
+                token1,
                 pool.pool_type(),
                 pool.fee(),
                 _input_rate,
@@ -276,6 +326,7 @@ where
             let other_output = self.calculator.compute_pool_output(
                 pool_address,
                 token1,
+                token0,
                 pool.pool_type(),
                 pool.fee(),
                 output,
@@ -428,6 +479,7 @@ mod estimator_tests {
                     token_out: address!("833589fCD6eDb6E08f4c7C32D4f71b54bdA02913"),
                     protocol: PoolType::UniswapV2,
                     fee: 0,
+                    stable: false,
                 },
                 SwapStep {
                     pool_address: address!("2F8818D1B0f3e3E295440c1C0cDDf40aAA21fA87"),
@@ -435,6 +487,7 @@ mod estimator_tests {
                     token_out: address!("4200000000000000000000000000000000000006"),
                     protocol: PoolType::SushiSwapV2,
                     fee: 0,
+                    stable: false,
                 },
             ],
             hash: 0,
@@ -447,6 +500,7 @@ mod estimator_tests {
                     token_out: address!("833589fCD6eDb6E08f4c7C32D4f71b54bdA02913"),
                     protocol: PoolType::SushiSwapV2,
                     fee: 0,
+                    stable: false,
                 },
                 SwapStep {
                     pool_address: address!("88A43bbDF9D098eEC7bCEda4e2494615dfD9bB9C"),
@@ -454,6 +508,7 @@ mod estimator_tests {
                     token_out: address!("4200000000000000000000000000000000000006"),
                     protocol: PoolType::UniswapV2,
                     fee: 0,
+                    stable: false,
                 },
             ],
             hash: 0,
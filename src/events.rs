@@ -1,15 +1,20 @@
 use alloy::primitives::Address;
 use alloy::primitives::U256;
 use alloy::rpc::types::Header;
+use pool_sync::Pool;
 use std::collections::HashSet;
 
+use crate::flash_loan::LoanQuote;
 use crate::gen_::FlashQuoter::SwapParams;
 use crate::swap::SwapPath;
 
 #[derive(Debug, Clone)]
 pub enum Event {
-    ArbPath((SwapPath, U256, u64)),
-    ValidPath((SwapParams, U256, u64)),
+    // path, input amount, calculated output, block, how it is funded
+    ArbPath((SwapPath, U256, U256, u64, LoanQuote)),
+    ValidPath((SwapParams, U256, u64, LoanQuote)),
     PoolsTouched(HashSet<Address>, u64),
+    PoolsAdded(Vec<Pool>, u64),
+    PoolsRemoved(Vec<Address>, u64),
     NewBlock(Header),
 }
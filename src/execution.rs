@@ -0,0 +1,228 @@
+use alloy::network::{Ethereum, TransactionBuilder};
+use alloy::eips::eip2930::AccessList;
+use alloy::primitives::{Address, U256};
+use alloy::providers::RootProvider;
+use alloy::rpc::types::TransactionRequest;
+use alloy::sol_types::decode_revert_reason;
+use alloy::transports::http::{Client, Http};
+use anyhow::{anyhow, Result};
+use revm::{
+    context_interface::{result::ExecutionResult, TransactTo},
+    database::CacheDB,
+    primitives::TxEnv,
+    DatabaseRef,
+};
+use revm_inspectors::access_list::AccessListInspector;
+
+use crate::block_env::{balance_of, sim_evm, BlockContext, SimulateAt};
+use crate::market_state::MarketState;
+
+// Percent added on top of the gas the transaction needs for its limit
+const GAS_LIMIT_MARGIN: u64 = 20;
+
+// Outcome of running the transaction we are about to send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionSim {
+    // after refunds, this is what is paid for
+    pub gas_used: u64,
+    pub gas_refunded: u64,
+    // what the executor transferred to our account
+    pub profit: U256,
+    // decoded reason if the transaction reverted or halted
+    pub revert: Option<String>,
+}
+
+impl ExecutionSim {
+    pub fn succeeded(&self) -> bool {
+        self.revert.is_none() && self.profit > U256::ZERO
+    }
+
+    // Limit to send the transaction with. Refunds are only paid out once it finished, while
+    // running it needs the refunded gas on top of what it used
+    pub fn gas_limit(&self) -> u64 {
+        (self.gas_used + self.gas_refunded) * (100 + GAS_LIMIT_MARGIN) / 100
+    }
+}
+
+// Run the transaction exactly as it will be signed against the market state in the next block.
+// Unlike the quoter this goes through the deployed executor, so the flash loan, its premium,
+// the repayment and the profit transfer are all executed. The executor and lender state is
+// loaded from chain as it is touched, nothing is written back to the market state
+pub fn simulate_execution(
+    market_state: &MarketState<Http<Client>, Ethereum, RootProvider<Http<Client>>>,
+    tx: &TransactionRequest,
+    account: Address,
+    profit_token: Address,
+) -> Result<ExecutionSim> {
+    let db = market_state.db.read().unwrap();
+    let block = db.block.at(SimulateAt::NextBlock);
+    simulate_on(&*db, &block, tx, account, profit_token)
+}
+
+// Run the transaction in the block over any db, the changes stay in an overlay
+fn simulate_on<DB>(
+    db: DB,
+    block: &BlockContext,
+    tx: &TransactionRequest,
+    account: Address,
+    profit_token: Address,
+) -> Result<ExecutionSim>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    let mut overlay = CacheDB::new(db);
+
+    let balance_before = balance_of(&mut overlay, block, profit_token, account)?;
+    let tx_env = tx_env(tx, account)?;
+    let mut evm = sim_evm(&mut overlay, block)
+        .modify_tx_env(|env| *env = tx_env)
+        .build();
+    let result = evm.transact_commit().map_err(|e| anyhow!("Failed to simulate execution {e:?}"))?;
+    drop(evm);
+
+    let (gas_used, gas_refunded, revert) = match result {
+        ExecutionResult::Success {
+            gas_used, gas_refunded, ..
+        } => (gas_used, gas_refunded, None),
+        ExecutionResult::Revert { gas_used, output } => (
+            gas_used,
+            0,
+            Some(decode_revert_reason(&output).unwrap_or_else(|| output.to_string())),
+        ),
+        ExecutionResult::Halt { gas_used, reason } => (gas_used, 0, Some(format!("{reason:?}"))),
+    };
+    let profit = match revert {
+        Some(_) => U256::ZERO,
+        None => balance_of(&mut overlay, block, profit_token, account)?.saturating_sub(balance_before),
+    };
+
+    Ok(ExecutionSim {
+        gas_used,
+        gas_refunded,
+        profit,
+        revert,
+    })
+}
+
+// Access list of the transaction, the accounts and slots it touches in the next block. The
+// sender and target are warm anyway so the inspector leaves them out
+pub fn access_list_for(
+    market_state: &MarketState<Http<Client>, Ethereum, RootProvider<Http<Client>>>,
+    tx: &TransactionRequest,
+    account: Address,
+) -> Result<AccessList> {
+    let db = market_state.db.read().unwrap();
+    let block = db.block.at(SimulateAt::NextBlock);
+    let mut overlay = CacheDB::new(&*db);
+
+    let tx_env = tx_env(tx, account)?;
+    let mut inspector = AccessListInspector::new(AccessList::default());
+    let mut evm = sim_evm(&mut overlay, &block)
+        .with_external_context(&mut inspector)
+        .modify_tx_env(|env| *env = tx_env)
+        .build();
+    evm.transact().map_err(|e| anyhow!("Failed to trace the access list {e:?}"))?;
+    drop(evm);
+    Ok(inspector.into_access_list())
+}
+
+// The transaction as the evm sees it once signed by the account
+fn tx_env(tx: &TransactionRequest, account: Address) -> Result<TxEnv> {
+    let to = tx.to().ok_or_else(|| anyhow!("Transaction has no target"))?;
+    let gas_limit = tx.gas_limit().ok_or_else(|| anyhow!("Transaction has no gas limit"))?;
+    Ok(TxEnv {
+        caller: account,
+        transact_to: TransactTo::Call(to),
+        data: tx.input().cloned().unwrap_or_default(),
+        value: tx.value().unwrap_or_default(),
+        gas_limit,
+        gas_price: U256::from(tx.max_fee_per_gas().unwrap_or_default()),
+        gas_priority_fee: tx.max_priority_fee_per_gas().map(U256::from),
+        chain_id: tx.chain_id(),
+        access_list: tx.access_list().cloned().unwrap_or_default().0,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{address, Bytes};
+    use revm::database::EmptyDB;
+    use revm::state::{AccountInfo, Bytecode};
+    use std::str::FromStr;
+
+    // Token and executor in one: a balanceOf call returns slot 0, a single word of calldata
+    // becomes the new balance and anything else reverts
+    const EXECUTOR_CODE: &str = "0x36602414601b573660201460135760006000fd5b600035600055005b60005460005260206000f3";
+    const EXECUTOR: Address = address!("0000000000000000000000000000000000000e4e");
+    const ACCOUNT: Address = address!("000000000000000000000000000000000000acc0");
+
+    fn executor_db() -> CacheDB<EmptyDB> {
+        let mut db = CacheDB::new(EmptyDB::default());
+        let code = Bytecode::new_raw(Bytes::from_str(EXECUTOR_CODE).unwrap());
+        db.insert_account_info(
+            EXECUTOR,
+            AccountInfo {
+                nonce: 0,
+                balance: U256::ZERO,
+                code_hash: code.hash_slow(),
+                code: Some(code),
+            },
+        );
+        db.insert_account_storage(EXECUTOR, U256::ZERO, U256::from(100)).unwrap();
+        db
+    }
+
+    fn simulate_with(calldata: Vec<u8>, gas_limit: u64) -> ExecutionSim {
+        let tx = TransactionRequest::default()
+            .with_to(EXECUTOR)
+            .with_input(calldata)
+            .with_gas_limit(gas_limit);
+        simulate_on(&executor_db(), &BlockContext::default(), &tx, ACCOUNT, EXECUTOR).unwrap()
+    }
+
+    fn simulate(calldata: Vec<u8>) -> ExecutionSim {
+        simulate_with(calldata, 1_000_000)
+    }
+
+    // Only a transaction that goes through and leaves us with more of the token is sent
+    #[test]
+    fn test_sim_gates_on_profit() {
+        let sim = simulate(U256::from(150).to_be_bytes_vec());
+        assert_eq!(sim.profit, U256::from(50));
+        assert!(sim.revert.is_none());
+        assert!(sim.gas_used > 0);
+        assert!(sim.succeeded());
+
+        let sim = simulate(U256::from(80).to_be_bytes_vec());
+        assert_eq!(sim.profit, U256::ZERO);
+        assert!(!sim.succeeded());
+
+        let sim = simulate(vec![0x01]);
+        assert_eq!(sim.profit, U256::ZERO);
+        assert!(sim.revert.is_some());
+        assert!(!sim.succeeded());
+    }
+
+    // The limit covers the gas refunded at the end, the gas used alone runs out
+    #[test]
+    fn test_gas_limit_covers_refunds() {
+        let sim = ExecutionSim {
+            gas_used: 100_000,
+            gas_refunded: 20_000,
+            profit: U256::ZERO,
+            revert: None,
+        };
+        assert_eq!(sim.gas_limit(), 144_000);
+
+        // clearing the balance slot refunds gas
+        let cleared = U256::ZERO.to_be_bytes_vec();
+        let sim = simulate(cleared.clone());
+        assert!(sim.revert.is_none());
+        assert!(sim.gas_refunded > 0);
+        assert!(simulate_with(cleared.clone(), sim.gas_limit()).revert.is_none());
+        assert!(simulate_with(cleared, sim.gas_used).revert.is_some());
+    }
+}
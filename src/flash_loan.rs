@@ -0,0 +1,495 @@
+use alloy::network::Network;
+use alloy::primitives::{address, Address, Bytes, U256};
+use alloy::providers::Provider;
+use alloy::sol;
+use alloy::sol_types::{SolCall, SolValue};
+use alloy::transports::Transport;
+use anyhow::{anyhow, Result};
+use log::debug;
+use pool_sync::PoolType;
+use revm::database::CacheDB;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::block_env::{balance_of, view_call, BlockContext};
+use crate::gen_::{FlashExecutor, FlashSwap};
+use crate::market_state::MarketState;
+use crate::state_db::layout::{storage_layout, StorageLayout};
+use crate::state_db::BlockStateDB;
+use crate::swap::SwapPath;
+
+// Lenders on Base
+const AAVE_POOL: Address = address!("A238Dd80C259a72e81d7e4664a9801593F98d1c5");
+const BALANCER_VAULT: Address = address!("BA12222222228d8Ba445958a75a0704d566BF2C8");
+const MORPHO: Address = address!("BBBBBbbBBb9cC5e90e3b3Af64bdAF62C37EEFFCb");
+
+sol!(
+    contract AavePool {
+        struct ReserveConfigurationMap {
+            uint256 data;
+        }
+        struct ReserveData {
+            ReserveConfigurationMap configuration;
+            uint128 liquidityIndex;
+            uint128 currentLiquidityRate;
+            uint128 variableBorrowIndex;
+            uint128 currentVariableBorrowRate;
+            uint128 currentStableBorrowRate;
+            uint40 lastUpdateTimestamp;
+            uint16 id;
+            address aTokenAddress;
+            address stableDebtTokenAddress;
+            address variableDebtTokenAddress;
+            address interestRateStrategyAddress;
+            uint128 accruedToTreasury;
+            uint128 unbacked;
+            uint128 isolationModeTotalDebt;
+        }
+        function FLASHLOAN_PREMIUM_TOTAL() external view returns (uint128);
+        function getReserveData(address asset) external view returns (ReserveData memory);
+    }
+);
+
+sol!(
+    contract BalancerVault {
+        function getProtocolFeesCollector() external view returns (address);
+    }
+);
+
+sol!(
+    contract BalancerFeesCollector {
+        function getFlashLoanFeePercentage() external view returns (uint256);
+    }
+);
+
+// Where the capital for an arb comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FlashLoanSource {
+    Aave,
+    Balancer,
+    Morpho,
+    // borrow the asset from the last pool of the cycle and repay it with the pools input token,
+    // the swap fee of that pool is already part of the path output
+    V2FlashSwap,
+    V3FlashSwap,
+}
+
+// The cheapest way to fund an arb
+#[derive(Debug, Clone, Copy)]
+pub struct LoanQuote {
+    pub source: FlashLoanSource,
+    pub premium: U256,
+}
+
+// Overlay of the market state the providers read through, nothing is written back
+pub type LoanDB<'a, T, N, P> = CacheDB<&'a BlockStateDB<T, N, P>>;
+
+// A source of flash loans. Everything is read from chain state through the db so the
+// quote matches the block we are searching on
+pub trait FlashLoanProvider<T, N, P>: Send + Sync
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    fn source(&self) -> FlashLoanSource;
+
+    // The account the asset is borrowed from for this path, None if the provider can not fund it
+    fn lender(&self, path: &SwapPath) -> Option<Address>;
+
+    // Fee owed on top of the borrowed amount
+    fn premium(&self, db: &mut LoanDB<T, N, P>, block: &BlockContext, asset: Address, amount: U256) -> Result<U256>;
+
+    // Most of the asset that can be borrowed from the lender
+    fn max_borrow(&self, db: &mut LoanDB<T, N, P>, block: &BlockContext, lender: Address, asset: Address) -> Result<U256>;
+}
+
+pub struct Aave;
+impl<T, N, P> FlashLoanProvider<T, N, P> for Aave
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    fn source(&self) -> FlashLoanSource {
+        FlashLoanSource::Aave
+    }
+
+    fn lender(&self, _path: &SwapPath) -> Option<Address> {
+        Some(AAVE_POOL)
+    }
+
+    // premium is in bps
+    fn premium(&self, db: &mut LoanDB<T, N, P>, block: &BlockContext, _asset: Address, amount: U256) -> Result<U256> {
+        let output = view_call(&mut *db, block, AAVE_POOL, AavePool::FLASHLOAN_PREMIUM_TOTALCall {}.abi_encode())?;
+        let premium = u128::abi_decode(&output)?;
+        Ok(amount * U256::from(premium) / U256::from(10000))
+    }
+
+    // the liquidity sits in the aToken
+    fn max_borrow(&self, db: &mut LoanDB<T, N, P>, block: &BlockContext, _lender: Address, asset: Address) -> Result<U256> {
+        let output = view_call(&mut *db, block, AAVE_POOL, AavePool::getReserveDataCall { asset }.abi_encode())?;
+        let reserve = AavePool::ReserveData::abi_decode(&output)?;
+        if reserve.aTokenAddress == Address::ZERO {
+            return Ok(U256::ZERO);
+        }
+        balance_of(&mut *db, block, asset, reserve.aTokenAddress)
+    }
+}
+
+pub struct Balancer;
+impl<T, N, P> FlashLoanProvider<T, N, P> for Balancer
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    fn source(&self) -> FlashLoanSource {
+        FlashLoanSource::Balancer
+    }
+
+    fn lender(&self, _path: &SwapPath) -> Option<Address> {
+        Some(BALANCER_VAULT)
+    }
+
+    // the fee percentage is set on the protocol fees collector and scaled by 1e18, it is zero
+    // on Base but governance can turn it on
+    fn premium(&self, db: &mut LoanDB<T, N, P>, block: &BlockContext, _asset: Address, amount: U256) -> Result<U256> {
+        let output = view_call(
+            &mut *db,
+            block,
+            BALANCER_VAULT,
+            BalancerVault::getProtocolFeesCollectorCall {}.abi_encode(),
+        )?;
+        let collector = Address::abi_decode(&output)?;
+        let output = view_call(
+            &mut *db,
+            block,
+            collector,
+            BalancerFeesCollector::getFlashLoanFeePercentageCall {}.abi_encode(),
+        )?;
+        let fee_percentage = U256::abi_decode(&output)?;
+        Ok(amount * fee_percentage / U256::from(1e18))
+    }
+
+    fn max_borrow(&self, db: &mut LoanDB<T, N, P>, block: &BlockContext, lender: Address, asset: Address) -> Result<U256> {
+        balance_of(&mut *db, block, asset, lender)
+    }
+}
+
+pub struct Morpho;
+impl<T, N, P> FlashLoanProvider<T, N, P> for Morpho
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    fn source(&self) -> FlashLoanSource {
+        FlashLoanSource::Morpho
+    }
+
+    fn lender(&self, _path: &SwapPath) -> Option<Address> {
+        Some(MORPHO)
+    }
+
+    // morpho flash loans are free
+    fn premium(&self, _db: &mut LoanDB<T, N, P>, _block: &BlockContext, _asset: Address, _amount: U256) -> Result<U256> {
+        Ok(U256::ZERO)
+    }
+
+    fn max_borrow(&self, db: &mut LoanDB<T, N, P>, block: &BlockContext, lender: Address, asset: Address) -> Result<U256> {
+        balance_of(&mut *db, block, asset, lender)
+    }
+}
+
+// Borrow from the last pool of the path through its own swap. V2 pools pay out before the
+// callback and v3 pools call back for the input, so the two need their own entrypoints
+pub struct PoolFlashSwap {
+    source: FlashLoanSource,
+}
+
+impl PoolFlashSwap {
+    pub fn v2() -> Self {
+        Self {
+            source: FlashLoanSource::V2FlashSwap,
+        }
+    }
+
+    pub fn v3() -> Self {
+        Self {
+            source: FlashLoanSource::V3FlashSwap,
+        }
+    }
+
+    // If the executor can flash swap out of a pool of the protocol. Aerodrome pools call a
+    // different hook than the v2 callback, so they are left out
+    fn can_flash_swap(&self, protocol: PoolType) -> bool {
+        match (self.source, storage_layout(protocol)) {
+            (FlashLoanSource::V2FlashSwap, Ok(StorageLayout::V2(_))) => protocol != PoolType::Aerodrome,
+            (FlashLoanSource::V3FlashSwap, Ok(StorageLayout::V3(_))) => true,
+            _ => false,
+        }
+    }
+}
+
+impl<T, N, P> FlashLoanProvider<T, N, P> for PoolFlashSwap
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    fn source(&self) -> FlashLoanSource {
+        self.source
+    }
+
+    fn lender(&self, path: &SwapPath) -> Option<Address> {
+        let last = path.steps.last()?;
+        self.can_flash_swap(last.protocol).then_some(last.pool_address)
+    }
+
+    fn premium(&self, _db: &mut LoanDB<T, N, P>, _block: &BlockContext, _asset: Address, _amount: U256) -> Result<U256> {
+        Ok(U256::ZERO)
+    }
+
+    fn max_borrow(&self, db: &mut LoanDB<T, N, P>, block: &BlockContext, lender: Address, asset: Address) -> Result<U256> {
+        balance_of(&mut *db, block, asset, lender)
+    }
+}
+
+// All of the enabled flash loan providers
+pub struct FlashLoans<T, N, P>
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    providers: Vec<Box<dyn FlashLoanProvider<T, N, P>>>,
+    market_state: Arc<MarketState<T, N, P>>,
+    // (source, lender, asset, amount) => block read at and the premium, None if it can not lend it
+    quotes: Mutex<HashMap<(FlashLoanSource, Address, Address, U256), (u64, Option<U256>)>>,
+}
+
+impl<T, N, P> FlashLoans<T, N, P>
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    // Construct from FLASH_LOAN_SOURCES, a comma separated list of aave, balancer, morpho,
+    // v2_flash_swap and v3_flash_swap. Only enable the sources the deployed executor has
+    // entrypoints for, defaults to aave
+    pub fn from_env(market_state: Arc<MarketState<T, N, P>>) -> Self {
+        let sources = std::env::var("FLASH_LOAN_SOURCES").unwrap_or_else(|_| "aave".to_string());
+        let providers = sources
+            .split(',')
+            .map(str::trim)
+            .map(|source| -> Box<dyn FlashLoanProvider<T, N, P>> {
+                match source {
+                    "aave" => Box::new(Aave),
+                    "balancer" => Box::new(Balancer),
+                    "morpho" => Box::new(Morpho),
+                    "v2_flash_swap" => Box::new(PoolFlashSwap::v2()),
+                    "v3_flash_swap" => Box::new(PoolFlashSwap::v3()),
+                    other => panic!("Unknown flash loan source {other}"),
+                }
+            })
+            .collect();
+        Self::new(providers, market_state)
+    }
+
+    pub fn new(providers: Vec<Box<dyn FlashLoanProvider<T, N, P>>>, market_state: Arc<MarketState<T, N, P>>) -> Self {
+        Self {
+            providers,
+            market_state,
+            quotes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The cheapest source that can lend the amount for this path. Premiums and liquidity are
+    // read once per block under the read lock
+    pub fn cheapest(&self, path: &SwapPath, asset: Address, amount: U256) -> Option<LoanQuote> {
+        let db = self.market_state.db.read().unwrap();
+        let block = db.block;
+        let mut overlay = CacheDB::new(&*db);
+        let mut quotes = self.quotes.lock().unwrap();
+
+        self.providers
+            .iter()
+            .filter_map(|provider| {
+                let lender = provider.lender(path)?;
+                let key = (provider.source(), lender, asset, amount);
+                let premium = match quotes.get(&key) {
+                    Some((number, premium)) if *number == block.number => *premium,
+                    _ => {
+                        let quote = provider.max_borrow(&mut overlay, &block, lender, asset).and_then(|max_borrow| {
+                            if max_borrow < amount {
+                                return Err(anyhow!("Can only borrow {max_borrow}"));
+                            }
+                            provider.premium(&mut overlay, &block, asset, amount)
+                        });
+                        let premium = quote
+                            .map_err(|e| debug!("{:?} can not lend {amount} of {asset}: {e}", provider.source()))
+                            .ok();
+                        quotes.insert(key, (block.number, premium));
+                        premium
+                    }
+                };
+                premium.map(|premium| LoanQuote {
+                    source: provider.source(),
+                    premium,
+                })
+            })
+            .min_by_key(|quote| quote.premium)
+    }
+}
+
+// Encode the executor call for the flash loan source, every source has its own entrypoint
+pub fn encode_execution(source: FlashLoanSource, arb: FlashSwap::SwapParams) -> Bytes {
+    let params = FlashExecutor::SwapParams {
+        pools: arb.pools.clone(),
+        poolVersions: arb.poolVersions.clone(),
+        amountIn: arb.amountIn,
+    };
+    let calldata = match source {
+        FlashLoanSource::Aave => FlashSwap::executeArbitrageCall { arb }.abi_encode(),
+        FlashLoanSource::Balancer => FlashExecutor::executeWithBalancerCall { arb: params }.abi_encode(),
+        FlashLoanSource::Morpho => FlashExecutor::executeWithMorphoCall { arb: params }.abi_encode(),
+        FlashLoanSource::V2FlashSwap => FlashExecutor::executeWithV2FlashSwapCall { arb: params }.abi_encode(),
+        FlashLoanSource::V3FlashSwap => FlashExecutor::executeWithV3FlashSwapCall { arb: params }.abi_encode(),
+    };
+    Bytes::from(calldata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{empty_market, OfflineMarket};
+    use crate::swap::SwapStep;
+    use alloy::network::Ethereum;
+    use alloy::providers::RootProvider;
+    use alloy::transports::http::{Client, Http};
+
+    const ASSET: Address = address!("4200000000000000000000000000000000000006");
+
+    // Lender with a fixed premium and liquidity
+    struct Fixed {
+        source: FlashLoanSource,
+        premium: u64,
+        liquidity: u64,
+    }
+
+    impl FlashLoanProvider<Http<Client>, Ethereum, RootProvider<Http<Client>>> for Fixed {
+        fn source(&self) -> FlashLoanSource {
+            self.source
+        }
+
+        fn lender(&self, _path: &SwapPath) -> Option<Address> {
+            Some(Address::ZERO)
+        }
+
+        fn premium(
+            &self,
+            _db: &mut LoanDB<Http<Client>, Ethereum, RootProvider<Http<Client>>>,
+            _block: &BlockContext,
+            _asset: Address,
+            _amount: U256,
+        ) -> Result<U256> {
+            Ok(U256::from(self.premium))
+        }
+
+        fn max_borrow(
+            &self,
+            _db: &mut LoanDB<Http<Client>, Ethereum, RootProvider<Http<Client>>>,
+            _block: &BlockContext,
+            _lender: Address,
+            _asset: Address,
+        ) -> Result<U256> {
+            Ok(U256::from(self.liquidity))
+        }
+    }
+
+    fn path(last: PoolType) -> SwapPath {
+        let usdc = address!("833589fcd6edb6e08f4c7c32d4f71b54bda02913");
+        let step = |pool_address, token_in, token_out, protocol| SwapStep {
+            pool_address,
+            token_in,
+            token_out,
+            protocol,
+            fee: 0,
+            stable: false,
+        };
+        SwapPath::new(vec![
+            step(address!("00000000000000000000000000000000000000aa"), ASSET, usdc, PoolType::UniswapV2),
+            step(address!("00000000000000000000000000000000000000bb"), usdc, ASSET, last),
+        ])
+    }
+
+    fn loans(providers: Vec<Fixed>, market: Arc<OfflineMarket>) -> FlashLoans<Http<Client>, Ethereum, RootProvider<Http<Client>>> {
+        FlashLoans::new(
+            providers
+                .into_iter()
+                .map(|provider| Box::new(provider) as Box<dyn FlashLoanProvider<_, _, _>>)
+                .collect(),
+            market,
+        )
+    }
+
+    // The cheapest source that has enough liquidity wins
+    #[test]
+    fn test_cheapest_source_with_liquidity() {
+        let loans = loans(
+            vec![
+                Fixed { source: FlashLoanSource::Aave, premium: 9, liquidity: 1000 },
+                Fixed { source: FlashLoanSource::Balancer, premium: 0, liquidity: 10 },
+                Fixed { source: FlashLoanSource::Morpho, premium: 3, liquidity: 1000 },
+            ],
+            empty_market(),
+        );
+        let quote = loans.cheapest(&path(PoolType::UniswapV2), ASSET, U256::from(100)).unwrap();
+        assert_eq!(quote.source, FlashLoanSource::Morpho);
+        assert_eq!(quote.premium, U256::from(3));
+
+        // a small enough amount fits in the free balancer loan
+        let quote = loans.cheapest(&path(PoolType::UniswapV2), ASSET, U256::from(10)).unwrap();
+        assert_eq!(quote.source, FlashLoanSource::Balancer);
+
+        assert!(loans.cheapest(&path(PoolType::UniswapV2), ASSET, U256::from(10_000)).is_none());
+    }
+
+    // Flash swaps borrow from the last pool, only when the executor can call back into it
+    #[test]
+    fn test_flash_swap_lender() {
+        type Lender = dyn FlashLoanProvider<Http<Client>, Ethereum, RootProvider<Http<Client>>>;
+        let v2: Box<Lender> = Box::new(PoolFlashSwap::v2());
+        let v3: Box<Lender> = Box::new(PoolFlashSwap::v3());
+        let last_pool = address!("00000000000000000000000000000000000000bb");
+
+        assert_eq!(v2.lender(&path(PoolType::UniswapV2)), Some(last_pool));
+        assert_eq!(v2.lender(&path(PoolType::UniswapV3)), None);
+        assert_eq!(v2.lender(&path(PoolType::Aerodrome)), None);
+        assert_eq!(v3.lender(&path(PoolType::UniswapV3)), Some(last_pool));
+        assert_eq!(v3.lender(&path(PoolType::UniswapV2)), None);
+    }
+
+    // Every source calls its own executor entrypoint
+    #[test]
+    fn test_sources_have_their_own_entrypoint() {
+        let arb = FlashSwap::SwapParams {
+            pools: vec![],
+            poolVersions: vec![],
+            amountIn: U256::from(1),
+        };
+        let sources = [
+            FlashLoanSource::Aave,
+            FlashLoanSource::Balancer,
+            FlashLoanSource::Morpho,
+            FlashLoanSource::V2FlashSwap,
+            FlashLoanSource::V3FlashSwap,
+        ];
+        let selectors: std::collections::HashSet<Vec<u8>> = sources
+            .iter()
+            .map(|source| encode_execution(*source, arb.clone())[..4].to_vec())
+            .collect();
+        assert_eq!(selectors.len(), sources.len());
+    }
+}
@@ -18,11 +18,12 @@ impl GasStation {
         }
     }
 
-    // Get gas fees based off percentage of total profit
-    pub fn get_gas_fees(&self, profit: U256) -> (u128, u128) {
+    // Get gas fees based off percentage of total profit, spread over the gas the
+    // transaction was measured to use
+    pub fn get_gas_fees(&self, profit: U256, gas_used: u64) -> (u128, u128) {
         let base_fee = self.base_fee.load(Ordering::Relaxed) as u128;
         let max_total_gas_spend: u128 = (profit / U256::from(2)).try_into().unwrap();
-        let priority_fee = max_total_gas_spend / 350_000;
+        let priority_fee = max_total_gas_spend / gas_used.max(1) as u128;
 
         (base_fee + priority_fee, priority_fee)
     }
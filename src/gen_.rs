@@ -15,6 +15,46 @@ sol!(
     "src/abi/FlashQuoter.json"
 );
 
+// Batched executor entrypoint. Runs several independent cycles in one transaction, either
+// under one flash loan for the summed input or one loan per route. With allowPartial set a
+// reverted route is skipped and reported instead of reverting the whole batch
+sol!(
+    #[derive(Debug)]
+    #[sol(rpc)]
+    contract BatchFlashSwap {
+        struct Route {
+            address[] pools;
+            uint8[] poolVersions;
+            uint256 amountIn;
+        }
+        struct BatchParams {
+            Route[] routes;
+            bool singleLoan;
+            bool allowPartial;
+        }
+        event RouteFailed(uint256 index, bytes reason);
+        function executeBatch(BatchParams calldata batch) external;
+    }
+);
+
+// Executor entrypoints for the flash loan sources other than aave, the aave entrypoint is
+// FlashSwap::executeArbitrage
+sol!(
+    #[derive(Debug)]
+    #[sol(rpc)]
+    contract FlashExecutor {
+        struct SwapParams {
+            address[] pools;
+            uint8[] poolVersions;
+            uint256 amountIn;
+        }
+        function executeWithBalancer(SwapParams calldata arb) external;
+        function executeWithMorpho(SwapParams calldata arb) external;
+        function executeWithV2FlashSwap(SwapParams calldata arb) external;
+        function executeWithV3FlashSwap(SwapParams calldata arb) external;
+    }
+);
+
 // Abi Generation an ERC20 token
 sol!(
     #[sol(rpc)]
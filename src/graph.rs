@@ -1,250 +1,459 @@
+use crate::protocol::is_stable;
 use crate::swap::{SwapPath, SwapStep};
+use crate::bytecode::{is_code_consistent, pool_codes};
+use crate::token_profile::{is_pool_tradeable, token_profiles, TokenProfiles};
 use alloy::primitives::Address;
-use petgraph::graph::UnGraph;
 use petgraph::prelude::*;
+use petgraph::stable_graph::StableUnGraph;
 use pool_sync::{BalancerV2Pool, CurveTriCryptoPool, Pool, PoolInfo};
-use std::collections::HashSet;
-use std::hash::Hash;
-use std::hash::{DefaultHasher, Hasher};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+// A hop along a cycle, from node, the edge taken, to node
+type Hop = (NodeIndex, EdgeIndex, NodeIndex);
+
+// Token graph that can be updated at runtime. Tokens are nodes and every pool is one or
+// more edges. Adding a pool only searches for the cycles that go through its edges so the
+// hop limit can be raised without regenerating every cycle
+pub struct ArbGraph {
+    graph: StableUnGraph<Address, Pool>,
+    // token => node in the graph
+    nodes: HashMap<Address, NodeIndex>,
+    // pool => all of the edges it contributes
+    pool_edges: HashMap<Address, Vec<EdgeIndex>>,
+    // base tokens that cycles start and end in
+    start_tokens: Vec<Address>,
+    // pools with a token that can not be traded are left out
+    token_profiles: Arc<TokenProfiles>,
+    max_hops: usize,
+}
 
-pub struct ArbGraph;
 impl ArbGraph {
-    // Constructor, takes the set of working tokens we are interested in searching over
-    pub async fn generate_cycles(working_pools: Vec<Pool>) -> Vec<SwapPath> {
-        // build the graph
-        let token: Address = std::env::var("WETH").unwrap().parse().unwrap();
-        let graph = ArbGraph::build_graph(working_pools);
-
-        // get start node and construct cycles
-        let start_node = graph
-            .node_indices()
-            .find(|node| graph[*node] == token)
-            .unwrap();
-        let cycles = ArbGraph::find_all_arbitrage_paths(&graph, start_node, 2);
-
-        // form our swappaths
-        let swappaths: Vec<SwapPath> = cycles
-            .iter()
-            .map(|cycle| {
-                let mut hasher = DefaultHasher::new();
-                cycle.iter().for_each(|step| step.hash(&mut hasher));
-                let output_hash = hasher.finish();
-                SwapPath {
-                    steps: cycle.clone(),
-                    hash: output_hash,
-                }
-            })
-            .collect();
-        swappaths
+    // Construct the graph from the working set of pools. Cycles start from each of the
+    // base tokens and are at most MAX_HOPS long
+    pub fn new(working_pools: Vec<Pool>, start_tokens: Vec<Address>) -> Self {
+        let max_hops: usize = std::env::var("MAX_HOPS")
+            .ok()
+            .and_then(|hops| hops.parse().ok())
+            .unwrap_or(2);
+
+        let mut arb_graph = Self {
+            graph: StableUnGraph::default(),
+            nodes: HashMap::new(),
+            pool_edges: HashMap::new(),
+            start_tokens,
+            token_profiles: token_profiles(),
+            max_hops,
+        };
+        for pool in working_pools {
+            arb_graph.insert_pool(pool);
+        }
+        arb_graph
     }
 
-    // Build the graph from the working set of pools
-    pub fn build_graph(working_pools: Vec<Pool>) -> UnGraph<Address, Pool> {
-        let mut graph: UnGraph<Address, Pool> = UnGraph::new_undirected();
-        let mut inserted_nodes: HashSet<Address> = HashSet::new();
+    // Generate every cycle in the graph
+    pub fn generate_cycles(&self) -> Vec<SwapPath> {
+        let mut all_paths: Vec<Vec<Hop>> = Vec::new();
+        for start_node in self.start_nodes() {
+            let mut current_path = Vec::new();
+            let mut visited = HashSet::new();
 
-        for pool in working_pools {
-            // add the nodes ot the graph if they have not already been added
-            match pool {
-                Pool::BalancerV2(balancer_pool) => {
-                    Self::add_balancer_pool_to_graph(
-                        &mut graph,
-                        &mut inserted_nodes,
-                        balancer_pool,
-                    );
-                }
-                Pool::CurveTriCrypto(curve_pool) => {
-                    Self::add_curve_pool_to_graph(&mut graph, &mut inserted_nodes, curve_pool);
-                }
-                _ => {
-                    Self::add_simple_pool_to_graph(&mut graph, &mut inserted_nodes, pool);
+            self.construct_cycles(
+                start_node,
+                start_node,
+                &mut current_path,
+                &mut visited,
+                &mut all_paths,
+            );
+        }
+
+        all_paths.iter().map(|cycle| self.to_swap_path(cycle)).collect()
+    }
+
+    // Add a pool to the graph and return all of the new cycles that go through it
+    pub fn add_pool(&mut self, pool: Pool) -> Vec<SwapPath> {
+        let pool_address = pool.address();
+        if self.pool_edges.contains_key(&pool_address) {
+            return Vec::new();
+        }
+        self.insert_pool(pool);
+        let edges = self.pool_edges.get(&pool_address).cloned().unwrap_or_default();
+
+        // a cycle may use more than one edge of a multi token pool, dedup on the hash
+        let mut seen: HashSet<u64> = HashSet::new();
+        let mut new_paths = Vec::new();
+        for start_node in self.start_nodes() {
+            for &edge in &edges {
+                let (a, b) = self.graph.edge_endpoints(edge).unwrap();
+                for (from, to) in [(a, b), (b, a)] {
+                    for cycle in self.cycles_through(start_node, from, edge, to) {
+                        let path = self.to_swap_path(&cycle);
+                        if seen.insert(path.hash) {
+                            new_paths.push(path);
+                        }
+                    }
                 }
             }
         }
-        graph
+        new_paths
     }
 
-    fn add_simple_pool_to_graph(
-        graph: &mut UnGraph<Address, Pool>,
-        inserted_nodes: &mut HashSet<Address>,
-        pool: Pool,
-    ) {
-        let token0 = pool.token0_address();
-        let token1 = pool.token1_address();
+    // Remove a pool from the graph. The caller drops the cycles through the pool
+    pub fn remove_pool(&mut self, pool_address: Address) -> bool {
+        let Some(edges) = self.pool_edges.remove(&pool_address) else {
+            return false;
+        };
+        for edge in edges {
+            self.graph.remove_edge(edge);
+        }
+        true
+    }
+
+    // Nodes of the base tokens that are in the graph
+    fn start_nodes(&self) -> Vec<NodeIndex> {
+        self.start_tokens
+            .iter()
+            .filter_map(|token| self.nodes.get(token).copied())
+            .collect()
+    }
 
-        // Add nodes if they don't exist
-        if !inserted_nodes.contains(&token0) {
-            graph.add_node(token0);
-            inserted_nodes.insert(token0);
+    // Add all of the edges for a pool, pools with a token that can not be traded or with code
+    // that does not match their label are left out
+    fn insert_pool(&mut self, pool: Pool) {
+        if !is_pool_tradeable(&self.token_profiles, &pool) || !is_code_consistent(&pool_codes(), &pool) {
+            return;
         }
-        if !inserted_nodes.contains(&token1) {
-            graph.add_node(token1);
-            inserted_nodes.insert(token1);
+        match pool {
+            Pool::BalancerV2(balancer_pool) => self.add_balancer_pool_to_graph(balancer_pool),
+            Pool::CurveTriCrypto(curve_pool) => self.add_curve_pool_to_graph(curve_pool),
+            _ => self.add_simple_pool_to_graph(pool),
         }
+    }
 
-        // Get the node indices
-        let node0 = graph.node_indices().find(|&n| graph[n] == token0).unwrap();
-        let node1 = graph.node_indices().find(|&n| graph[n] == token1).unwrap();
+    // Get the node for a token, adding it if it does not exist
+    fn node(&mut self, token: Address) -> NodeIndex {
+        *self
+            .nodes
+            .entry(token)
+            .or_insert_with(|| self.graph.add_node(token))
+    }
 
-        // Add the edge (pool)
-        graph.add_edge(node0, node1, pool);
+    fn add_edge(&mut self, token0: Address, token1: Address, pool: Pool) {
+        let node0 = self.node(token0);
+        let node1 = self.node(token1);
+        let pool_address = pool.address();
+        let edge = self.graph.add_edge(node0, node1, pool);
+        self.pool_edges.entry(pool_address).or_default().push(edge);
     }
 
-    fn add_curve_pool_to_graph(
-        graph: &mut UnGraph<Address, Pool>,
-        inserted_nodes: &mut HashSet<Address>,
-        curve_pool: CurveTriCryptoPool,
-    ) {
-        let tokens = curve_pool.get_tokens();
+    fn add_simple_pool_to_graph(&mut self, pool: Pool) {
+        let token0 = pool.token0_address();
+        let token1 = pool.token1_address();
+        self.add_edge(token0, token1, pool);
+    }
 
-        // Add nodes for all tokens in the pool
-        for &token in &tokens {
-            if !inserted_nodes.contains(&token) {
-                graph.add_node(token);
-                inserted_nodes.insert(token);
-            }
-        }
+    fn add_curve_pool_to_graph(&mut self, curve_pool: CurveTriCryptoPool) {
+        let tokens = curve_pool.get_tokens();
 
-        // Add edges for all possible token pairs with non-zero balances
+        // Add edges for all possible token pairs
         for (i, &token_in) in tokens.iter().enumerate() {
             for &token_out in tokens.iter().skip(i + 1) {
-                // Only add edge if both balances are non-zero
-                let node_in = graph
-                    .node_indices()
-                    .find(|&n| graph[n] == token_in)
-                    .unwrap();
-                let node_out = graph
-                    .node_indices()
-                    .find(|&n| graph[n] == token_out)
-                    .unwrap();
-
-                // Create a new Pool::BalancerV2 for each edge
                 let pool = Pool::CurveTriCrypto(curve_pool.clone());
-                graph.add_edge(node_in, node_out, pool);
+                self.add_edge(token_in, token_out, pool);
             }
         }
     }
 
-    fn add_balancer_pool_to_graph(
-        graph: &mut UnGraph<Address, Pool>,
-        inserted_nodes: &mut HashSet<Address>,
-        balancer_pool: BalancerV2Pool,
-    ) {
+    fn add_balancer_pool_to_graph(&mut self, balancer_pool: BalancerV2Pool) {
         let tokens = balancer_pool.get_tokens();
 
-        // Add nodes for all tokens in the pool
-        for &token in &tokens {
-            if !inserted_nodes.contains(&token) {
-                graph.add_node(token);
-                inserted_nodes.insert(token);
-            }
-        }
-
         // Add edges for all possible token pairs with non-zero balances
         for (i, &token_in) in tokens.iter().enumerate() {
             for &token_out in tokens.iter().skip(i + 1) {
                 let balance_in = balancer_pool.get_balance(&token_in);
                 let balance_out = balancer_pool.get_balance(&token_out);
 
-                // Only add edge if both balances are non-zero
                 if !balance_in.is_zero() && !balance_out.is_zero() {
-                    let node_in = graph
-                        .node_indices()
-                        .find(|&n| graph[n] == token_in)
-                        .unwrap();
-                    let node_out = graph
-                        .node_indices()
-                        .find(|&n| graph[n] == token_out)
-                        .unwrap();
-
-                    // Create a new Pool::BalancerV2 for each edge
                     let pool = Pool::BalancerV2(balancer_pool.clone());
-                    graph.add_edge(node_in, node_out, pool);
+                    self.add_edge(token_in, token_out, pool);
                 }
             }
         }
     }
 
-    fn find_all_arbitrage_paths(
-        graph: &UnGraph<Address, Pool>,
-        start_node: NodeIndex,
-        max_hops: usize,
-    ) -> Vec<Vec<SwapStep>> {
-        //let mut all_paths = Vec::new();
-        let mut all_paths: Vec<Vec<SwapStep>> = Vec::new();
-        let mut current_path = Vec::new();
-        let mut visited = HashSet::new();
-
-        Self::construct_cycles(
-            graph,
-            start_node,
-            start_node,
-            max_hops,
-            &mut current_path,
-            &mut visited,
-            &mut all_paths,
-        );
-
-        all_paths
-    }
-
     // Build all of the cycles
     fn construct_cycles(
-        graph: &UnGraph<Address, Pool>,
+        &self,
         current_node: NodeIndex,
         start_node: NodeIndex,
-        max_hops: usize,
-        current_path: &mut Vec<(NodeIndex, Pool, NodeIndex)>,
+        current_path: &mut Vec<Hop>,
         visited: &mut HashSet<NodeIndex>,
-        all_paths: &mut Vec<Vec<SwapStep>>,
+        all_paths: &mut Vec<Vec<Hop>>,
     ) {
-        if current_path.len() >= max_hops {
+        if current_path.len() >= self.max_hops {
             return;
         }
 
-        for edge in graph.edges(current_node) {
+        for edge in self.graph.edges(current_node) {
             let next_node = edge.target();
-            let protocol = edge.weight().clone();
+            let hop = (current_node, edge.id(), next_node);
 
             if next_node == start_node {
-                if current_path.len() >= 2
-                    || (current_path.len() == 1
-                        && current_path[0].1.pool_type() != protocol.pool_type())
-                {
-                    let mut new_path = current_path.clone();
-                    new_path.push((current_node, protocol, next_node));
-
-                    let mut swap_path = Vec::new();
-                    for (base, pool, quote) in new_path.iter() {
-                        let swap = SwapStep {
-                            pool_address: pool.address(),
-                            token_in: graph[*base],
-                            token_out: graph[*quote],
-                            protocol: pool.pool_type(),
-                            fee: pool.fee(),
-                        };
-                        swap_path.push(swap);
-                    }
-
-                    all_paths.push(swap_path);
+                let mut new_path = current_path.clone();
+                new_path.push(hop);
+                if self.is_valid_cycle(&new_path) {
+                    all_paths.push(new_path);
                 }
             } else if !visited.contains(&next_node) {
-                current_path.push((current_node, protocol, next_node));
+                current_path.push(hop);
                 visited.insert(next_node);
 
-                Self::construct_cycles(
-                    graph,
-                    next_node,
-                    start_node,
-                    max_hops,
-                    current_path,
-                    visited,
-                    all_paths,
-                );
+                self.construct_cycles(next_node, start_node, current_path, visited, all_paths);
+
+                current_path.pop();
+                visited.remove(&next_node);
+            }
+        }
+    }
+
+    // All cycles from the start node that take the edge from => to. The cycle is split into
+    // a path start => from, the edge, and a path to => start that share no nodes
+    fn cycles_through(
+        &self,
+        start_node: NodeIndex,
+        from: NodeIndex,
+        edge: EdgeIndex,
+        to: NodeIndex,
+    ) -> Vec<Vec<Hop>> {
+        let mut cycles = Vec::new();
+        let middle = (from, edge, to);
 
+        let prefixes = self.simple_paths(start_node, from, self.max_hops - 1, &HashSet::new());
+        for prefix in prefixes {
+            // nodes on the prefix besides the start can not be revisited
+            let used: HashSet<NodeIndex> = prefix
+                .iter()
+                .map(|(_, _, node)| *node)
+                .chain(std::iter::once(from))
+                .filter(|node| *node != start_node)
+                .collect();
+            if to != start_node && used.contains(&to) {
+                continue;
+            }
+            let remaining = self.max_hops - prefix.len() - 1;
+            let suffixes = if to == start_node {
+                vec![Vec::new()]
+            } else {
+                self.simple_paths(to, start_node, remaining, &used)
+            };
+            for suffix in suffixes {
+                let mut cycle = prefix.clone();
+                cycle.push(middle);
+                cycle.extend(suffix);
+                if self.is_valid_cycle(&cycle) {
+                    cycles.push(cycle);
+                }
+            }
+        }
+        cycles
+    }
+
+    // All simple paths from => to of at most max_len hops that avoid the excluded nodes
+    fn simple_paths(
+        &self,
+        from: NodeIndex,
+        to: NodeIndex,
+        max_len: usize,
+        excluded: &HashSet<NodeIndex>,
+    ) -> Vec<Vec<Hop>> {
+        let mut paths = Vec::new();
+        if from == to {
+            paths.push(Vec::new());
+            return paths;
+        }
+        let mut current_path = Vec::new();
+        let mut visited: HashSet<NodeIndex> = excluded.clone();
+        visited.insert(from);
+        self.walk(from, to, max_len, &mut current_path, &mut visited, &mut paths);
+        paths
+    }
+
+    fn walk(
+        &self,
+        current_node: NodeIndex,
+        to: NodeIndex,
+        max_len: usize,
+        current_path: &mut Vec<Hop>,
+        visited: &mut HashSet<NodeIndex>,
+        paths: &mut Vec<Vec<Hop>>,
+    ) {
+        if current_path.len() >= max_len {
+            return;
+        }
+        for edge in self.graph.edges(current_node) {
+            let next_node = edge.target();
+            let hop = (current_node, edge.id(), next_node);
+            if next_node == to {
+                let mut path = current_path.clone();
+                path.push(hop);
+                paths.push(path);
+            } else if !visited.contains(&next_node) {
+                current_path.push(hop);
+                visited.insert(next_node);
+                self.walk(next_node, to, max_len, current_path, visited, paths);
                 current_path.pop();
                 visited.remove(&next_node);
             }
         }
     }
+
+    // A two hop cycle through the same protocol can not be profitable
+    fn is_valid_cycle(&self, cycle: &[Hop]) -> bool {
+        match cycle.len() {
+            0 | 1 => false,
+            2 => self.graph[cycle[0].1].pool_type() != self.graph[cycle[1].1].pool_type(),
+            _ => true,
+        }
+    }
+
+    // Form a swappath from the hops
+    fn to_swap_path(&self, cycle: &[Hop]) -> SwapPath {
+        let steps: Vec<SwapStep> = cycle
+            .iter()
+            .map(|(base, edge, quote)| {
+                let pool = &self.graph[*edge];
+                SwapStep {
+                    pool_address: pool.address(),
+                    token_in: self.graph[*base],
+                    token_out: self.graph[*quote],
+                    protocol: pool.pool_type(),
+                    fee: pool.fee(),
+                    stable: is_stable(pool),
+                }
+            })
+            .collect();
+        SwapPath::new(steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{address, U256};
+    use crate::token_profile::TokenProfile;
+    use pool_sync::UniswapV2Pool;
+
+    const WETH: Address = address!("4200000000000000000000000000000000000006");
+    const USDC: Address = address!("833589fCD6eDb6E08f4c7C32D4f71b54bdA02913");
+    const DAI: Address = address!("50c5725949A6F0c72E6C4a641F24049A917DB0Cb");
+
+    fn v2_pool(address: Address, token0: Address, token1: Address) -> UniswapV2Pool {
+        UniswapV2Pool {
+            address,
+            token0,
+            token1,
+            token0_name: String::new(),
+            token1_name: String::new(),
+            token0_decimals: 18,
+            token1_decimals: 18,
+            token0_reserves: U256::from(1e21),
+            token1_reserves: U256::from(1e21),
+            stable: None,
+            fee: None,
+        }
+    }
+
+    fn pools() -> Vec<Pool> {
+        vec![
+            Pool::UniswapV2(v2_pool(address!("00000000000000000000000000000000000000a1"), WETH, USDC)),
+            Pool::SushiSwapV2(v2_pool(address!("00000000000000000000000000000000000000a2"), WETH, USDC)),
+            Pool::UniswapV2(v2_pool(address!("00000000000000000000000000000000000000a3"), USDC, DAI)),
+            Pool::UniswapV2(v2_pool(address!("00000000000000000000000000000000000000a4"), DAI, WETH)),
+        ]
+    }
+
+    fn graph(pools: Vec<Pool>, max_hops: usize) -> ArbGraph {
+        graph_with_profiles(pools, max_hops, TokenProfiles::new())
+    }
+
+    // The profiles are passed in so the cached profiles do not change what the tests see
+    fn graph_with_profiles(pools: Vec<Pool>, max_hops: usize, profiles: TokenProfiles) -> ArbGraph {
+        let mut graph = ArbGraph {
+            graph: StableUnGraph::default(),
+            nodes: HashMap::new(),
+            pool_edges: HashMap::new(),
+            start_tokens: vec![WETH],
+            token_profiles: Arc::new(profiles),
+            max_hops,
+        };
+        for pool in pools {
+            graph.insert_pool(pool);
+        }
+        graph
+    }
+
+    fn hashes(paths: &[SwapPath]) -> HashSet<u64> {
+        paths.iter().map(|path| path.hash).collect()
+    }
+
+    // Adding a pool finds exactly the cycles through it that a full regeneration finds
+    #[test]
+    fn test_add_pool_matches_regeneration() {
+        let mut pools = pools();
+        let added = pools.pop().unwrap();
+        let added_address = added.address();
+
+        let mut incremental = graph(pools.clone(), 3);
+        let before = hashes(&incremental.generate_cycles());
+        let new_paths = incremental.add_pool(added.clone());
+        assert!(new_paths
+            .iter()
+            .all(|path| path.steps.iter().any(|step| step.pool_address == added_address)));
+
+        pools.push(added.clone());
+        let full = hashes(&graph(pools, 3).generate_cycles());
+        let expected: HashSet<u64> = full.difference(&before).copied().collect();
+        assert_eq!(hashes(&new_paths), expected);
+        assert!(!expected.is_empty());
+
+        // adding it again finds nothing new
+        assert!(incremental.add_pool(added).is_empty());
+    }
+
+    // Two hop cycles through the same protocol are skipped and the hop limit is respected
+    #[test]
+    fn test_cycle_validity_and_hop_limit() {
+        let two_hops = graph(pools(), 2).generate_cycles();
+        assert!(two_hops.iter().all(|path| path.steps.len() == 2));
+        assert!(two_hops
+            .iter()
+            .all(|path| path.steps[0].protocol != path.steps[1].protocol));
+
+        let mut three_hops = graph(pools(), 3);
+        assert!(three_hops.generate_cycles().iter().any(|path| path.steps.len() == 3));
+        assert!(three_hops.remove_pool(address!("00000000000000000000000000000000000000a3")));
+        assert!(three_hops.generate_cycles().iter().all(|path| path.steps.len() == 2));
+    }
+
+    // Pools with a taxed token are left out of the graph
+    #[test]
+    fn test_untradeable_token_is_left_out() {
+        let profiles: TokenProfiles = [(
+            DAI,
+            TokenProfile {
+                token: DAI,
+                transfer_tax_bps: 300,
+                ..Default::default()
+            },
+        )]
+        .into_iter()
+        .collect();
+        let taxed = graph_with_profiles(pools(), 3, profiles);
+        assert!(!taxed.pool_edges.contains_key(&address!("00000000000000000000000000000000000000a3")));
+        assert!(!taxed.pool_edges.contains_key(&address!("00000000000000000000000000000000000000a4")));
+        assert!(taxed
+            .generate_cycles()
+            .iter()
+            .all(|path| path.steps.iter().all(|step| step.token_in != DAI && step.token_out != DAI)));
+    }
 }
@@ -0,0 +1,281 @@
+use alloy::primitives::{Address, Bytes, U256};
+use alloy::sol_types::{Panic, Revert, SolCall, SolError};
+use revm::{
+    context_interface::Database,
+    inspector::Inspector,
+    interpreter::{CallInputs, CallOutcome},
+    EvmContext,
+};
+use std::fmt;
+
+alloy::sol!(
+    contract TransferCalls {
+        function transfer(address to, uint256 amount) external returns (bool);
+        function transferFrom(address from, address to, uint256 amount) external returns (bool);
+    }
+);
+
+// Why a call reverted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RevertReason {
+    // Error(string)
+    Error(String),
+    // Panic(uint256)
+    Panic(U256),
+    // any other selector
+    Custom { selector: [u8; 4], data: Bytes },
+    Empty,
+    // out of gas, invalid opcode..
+    Halt(String),
+}
+
+impl RevertReason {
+    pub fn decode(output: &Bytes) -> Self {
+        if output.is_empty() {
+            return RevertReason::Empty;
+        }
+        if let Ok(revert) = Revert::abi_decode(output) {
+            return RevertReason::Error(revert.reason);
+        }
+        if let Ok(panic) = Panic::abi_decode(output) {
+            return RevertReason::Panic(panic.code);
+        }
+        match output.get(..4) {
+            Some(selector) => RevertReason::Custom {
+                selector: selector.try_into().unwrap(),
+                data: output.slice(4..),
+            },
+            None => RevertReason::Custom {
+                selector: [0; 4],
+                data: output.clone(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for RevertReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RevertReason::Error(reason) => write!(f, "Error({reason})"),
+            RevertReason::Panic(code) => write!(f, "Panic({code:#x})"),
+            RevertReason::Custom { selector, data } => {
+                write!(f, "Custom(0x{}, {data})", alloy::hex::encode(selector))
+            }
+            RevertReason::Empty => write!(f, "Empty revert"),
+            RevertReason::Halt(reason) => write!(f, "Halt({reason})"),
+        }
+    }
+}
+
+// What a hop of the path moved, from the token transfers into and out of its pool
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HopTrace {
+    pub pool: Address,
+    pub called: bool,
+    pub amount_in: Option<U256>,
+    pub amount_out: Option<U256>,
+}
+
+// The innermost call that reverted and the hop it happened in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HopFailure {
+    pub hop: Option<usize>,
+    pub pool: Option<Address>,
+    // set if the failing call was a token transfer
+    pub token: Option<Address>,
+    pub reason: RevertReason,
+}
+
+// Per hop amounts of a quote and the failure if it reverted
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathTrace {
+    pub hops: Vec<HopTrace>,
+    pub failure: Option<HopFailure>,
+}
+
+// A call that has not returned yet
+struct Frame {
+    target: Address,
+    // hop the call runs in, inherited from the call it is made from
+    hop: Option<usize>,
+    transfer: bool,
+}
+
+// Inspector that follows a quote or execution through the pools of the path. Calls into a
+// pool start its hop, token transfers to a pool are its input and transfers made by a pool
+// are its output, so the attribution does not depend on the protocol
+pub struct HopTracer {
+    trace: PathTrace,
+    frames: Vec<Frame>,
+}
+
+impl HopTracer {
+    pub fn new(pools: &[Address]) -> Self {
+        Self {
+            trace: PathTrace {
+                hops: pools
+                    .iter()
+                    .map(|pool| HopTrace {
+                        pool: *pool,
+                        ..Default::default()
+                    })
+                    .collect(),
+                failure: None,
+            },
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn into_trace(self) -> PathTrace {
+        self.trace
+    }
+
+    // A failed frame is the innermost failure unless one is already bubbling up through it. A
+    // frame that returns fine caught whatever failed below it, so nothing is pending anymore
+    fn frame_returned(&mut self, frame: Frame, failure: Option<RevertReason>) {
+        let Some(reason) = failure else {
+            self.trace.failure = None;
+            return;
+        };
+        if self.trace.failure.is_none() {
+            self.trace.failure = Some(HopFailure {
+                hop: frame.hop,
+                pool: frame.hop.map(|hop| self.trace.hops[hop].pool),
+                token: frame.transfer.then_some(frame.target),
+                reason,
+            });
+        }
+    }
+
+    fn hop_of(&self, address: Address) -> Option<usize> {
+        self.trace.hops.iter().position(|hop| hop.pool == address)
+    }
+
+    // Record the amounts of a token transfer, returns the hop it belongs to
+    fn record_transfer(&mut self, caller: Address, input: &[u8]) -> Option<usize> {
+        let (from, to, amount) = match input.get(..4)?.try_into().ok()? {
+            TransferCalls::transferCall::SELECTOR => {
+                let call = TransferCalls::transferCall::abi_decode(input).ok()?;
+                (caller, call.to, call.amount)
+            }
+            TransferCalls::transferFromCall::SELECTOR => {
+                let call = TransferCalls::transferFromCall::abi_decode(input).ok()?;
+                (call.from, call.to, call.amount)
+            }
+            _ => return None,
+        };
+        if let Some(hop) = self.hop_of(from) {
+            self.trace.hops[hop].amount_out = Some(amount);
+            return Some(hop);
+        }
+        let hop = self.hop_of(to)?;
+        self.trace.hops[hop].amount_in = Some(amount);
+        Some(hop)
+    }
+}
+
+impl<DB: Database> Inspector<DB> for HopTracer {
+    fn call(&mut self, _context: &mut EvmContext<DB>, inputs: &mut CallInputs) -> Option<CallOutcome> {
+        let parent_hop = self.frames.last().and_then(|frame| frame.hop);
+        let transfer_hop = self.record_transfer(inputs.caller, &inputs.input);
+        let pool_hop = self.hop_of(inputs.target_address);
+        if let Some(hop) = pool_hop {
+            self.trace.hops[hop].called = true;
+        }
+        self.frames.push(Frame {
+            target: inputs.target_address,
+            hop: pool_hop.or(parent_hop).or(transfer_hop),
+            transfer: transfer_hop.is_some(),
+        });
+        None
+    }
+
+    fn call_end(&mut self, _context: &mut EvmContext<DB>, _inputs: &CallInputs, outcome: CallOutcome) -> CallOutcome {
+        let Some(frame) = self.frames.pop() else {
+            return outcome;
+        };
+        let failure = if outcome.result.result.is_ok() {
+            None
+        } else if outcome.result.result.is_revert() {
+            Some(RevertReason::decode(&outcome.result.output))
+        } else {
+            Some(RevertReason::Halt(format!("{:?}", outcome.result.result)))
+        };
+        self.frame_returned(frame, failure);
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::address;
+
+    #[test]
+    fn test_decode_revert_reasons() {
+        let error: Bytes = Revert::from("UniswapV2: K").abi_encode().into();
+        assert_eq!(RevertReason::decode(&error), RevertReason::Error("UniswapV2: K".to_string()));
+
+        let panic: Bytes = Panic { code: U256::from(0x11) }.abi_encode().into();
+        assert_eq!(RevertReason::decode(&panic), RevertReason::Panic(U256::from(0x11)));
+
+        let custom = Bytes::from(vec![0xde, 0xad, 0xbe, 0xef, 0x01]);
+        assert_eq!(
+            RevertReason::decode(&custom),
+            RevertReason::Custom {
+                selector: [0xde, 0xad, 0xbe, 0xef],
+                data: Bytes::from(vec![0x01]),
+            }
+        );
+        assert_eq!(RevertReason::decode(&Bytes::new()), RevertReason::Empty);
+    }
+
+    // Transfers into a pool are its input, transfers made by the pool are its output
+    #[test]
+    fn test_transfers_are_attributed_to_hops() {
+        let pool_a = address!("00000000000000000000000000000000000000aa");
+        let pool_b = address!("00000000000000000000000000000000000000bb");
+        let executor = address!("0000000000000000000000000000000000001000");
+        let mut tracer = HopTracer::new(&[pool_a, pool_b]);
+
+        let into_a = TransferCalls::transferCall { to: pool_a, amount: U256::from(100) }.abi_encode();
+        assert_eq!(tracer.record_transfer(executor, &into_a), Some(0));
+        let a_to_b = TransferCalls::transferCall { to: pool_b, amount: U256::from(90) }.abi_encode();
+        assert_eq!(tracer.record_transfer(pool_a, &a_to_b), Some(0));
+
+        let trace = tracer.into_trace();
+        assert_eq!(trace.hops[0].amount_in, Some(U256::from(100)));
+        assert_eq!(trace.hops[0].amount_out, Some(U256::from(90)));
+        assert_eq!(trace.hops[1].amount_in, None);
+    }
+
+    // Only a failure that reaches the top level frame is kept, and it is the innermost one
+    #[test]
+    fn test_caught_failures_are_dropped() {
+        let pool_a = address!("00000000000000000000000000000000000000aa");
+        let pool_b = address!("00000000000000000000000000000000000000bb");
+        let token = address!("0000000000000000000000000000000000002000");
+        let frame = |target, hop, transfer| Frame { target, hop, transfer };
+        let mut tracer = HopTracer::new(&[pool_a, pool_b]);
+
+        // a failed transfer in the first hop that the pool catches
+        tracer.frame_returned(frame(token, Some(0), true), Some(RevertReason::Empty));
+        assert!(tracer.trace.failure.is_some());
+        tracer.frame_returned(frame(pool_a, Some(0), false), None);
+        assert_eq!(tracer.trace.failure, None);
+
+        // the second pool reverts and the executor bubbles it up
+        let reason = RevertReason::Error("K".to_string());
+        tracer.frame_returned(frame(pool_b, Some(1), false), Some(reason.clone()));
+        tracer.frame_returned(frame(token, None, false), Some(RevertReason::Empty));
+        assert_eq!(
+            tracer.into_trace().failure,
+            Some(HopFailure {
+                hop: Some(1),
+                pool: Some(pool_b),
+                token: None,
+                reason,
+            })
+        );
+    }
+}
@@ -10,11 +10,14 @@ use std::thread;
 use crate::estimator::Estimator;
 use crate::events::Event;
 use crate::filter::filter_pools;
+use crate::base_tokens::load_base_tokens;
+use crate::divergence::DivergenceTracker;
 use crate::gas_station::GasStation;
-use crate::graph::ArbGraph;
 use crate::market_state::MarketState;
+use crate::refresh::refresh_pools;
 use crate::searcher::Searchoor;
 use crate::simulator::simulate_paths;
+use crate::strategy::strategy_from_env;
 use crate::stream::stream_new_blocks;
 use crate::tx_sender::TransactionSender;
 
@@ -28,7 +31,7 @@ pub async fn start_workers(pools: Vec<Pool>, last_synced_block: u64) {
 
     // filter the pools here to smartly select the working set
     info!("Pool count before filter {}", pools.len());
-    let pools = filter_pools(pools, 4000, Chain::Base).await;
+    let pools = filter_pools(pools.clone(), &pools, 4000, Chain::Base).await;
     info!("Pool count after filter {}", pools.len());
 
     // start the block stream so we don't miss any blocks
@@ -57,7 +60,7 @@ pub async fn start_workers(pools: Vec<Pool>, last_synced_block: u64) {
     let market_state = MarketState::init_state_and_start_stream(
         pools.clone(),
         block_receiver,
-        address_sender,
+        address_sender.clone(),
         last_synced_block,
         provider,
         caught_up.clone(),
@@ -75,10 +78,17 @@ pub async fn start_workers(pools: Vec<Pool>, last_synced_block: u64) {
     estimator.process_pools(pools.clone());
     info!("Calculated initial rates!");
 
-    // generate the graph
-    info!("Generating cycles...");
-    let cycles = ArbGraph::generate_cycles(pools.clone()).await;
-    info!("Generated {} cycles", cycles.len());
+    // construct the search strategy over the base tokens
+    info!("Constructing search strategy...");
+    let bases = load_base_tokens(&estimator);
+    let start_tokens = bases.iter().map(|base| base.token).collect();
+    let strategy = strategy_from_env(pools.clone(), start_tokens);
+
+    // periodically refresh the working set, new and drained pools are sent to the searcher
+    tokio::spawn(refresh_pools(market_state.clone(), address_sender, pools.clone()));
+
+    // divergence tracker shared between the simulator and the searcher
+    let divergence = Arc::new(DivergenceTracker::from_env());
 
     // start the simulator
     info!("Starting the simulator...");
@@ -86,15 +96,17 @@ pub async fn start_workers(pools: Vec<Pool>, last_synced_block: u64) {
         profitable_sender,
         paths_receiver,
         market_state.clone(),
+        divergence.clone(),
+        bases.iter().flat_map(|base| [base.token, base.flash_loan_asset]).collect(),
     ));
 
     // start the searcher
     info!("Starting arbitrage searcher...");
-    let mut searcher = Searchoor::new(cycles, market_state.clone(), estimator);
+    let mut searcher = Searchoor::new(strategy, bases, market_state.clone(), estimator, divergence);
     thread::spawn(move || searcher.search_paths(paths_sender, address_receiver));
 
     // start the tx sender
     info!("Starting transaction sender...");
-    let mut tx_sender = TransactionSender::new(gas_station.clone()).await;
+    let mut tx_sender = TransactionSender::new(gas_station.clone(), market_state.clone()).await;
     tokio::spawn(async move { tx_sender.send_transactions(profitable_receiver).await });
 }
@@ -0,0 +1,422 @@
+use alloy::primitives::Address;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use pool_sync::{Pool, PoolInfo};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+
+use crate::universe::pool_liquidity;
+
+lazy_static! {
+    static ref SCORES: RwLock<Arc<PoolScores>> = RwLock::new(Arc::new(PoolScores::new()));
+}
+
+// pool => quality score between 0 and 1
+pub type PoolScores = HashMap<Address, f64>;
+
+// Keep/drop thresholds for a protocol
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LiquidityThresholds {
+    // weth value of the reserves
+    pub min_tvl_eth: f64,
+    // price impact at the smallest impact size
+    pub max_impact_bps: f64,
+    // share of the ticks around the current tick that have active liquidity
+    pub min_v3_coverage: f64,
+    // how many ticks each side of the current tick the coverage is measured over
+    pub coverage_ticks: i32,
+}
+
+impl Default for LiquidityThresholds {
+    fn default() -> Self {
+        Self {
+            min_tvl_eth: 1.0,
+            max_impact_bps: 500.0,
+            min_v3_coverage: 0.5,
+            coverage_ticks: 1000,
+        }
+    }
+}
+
+// Thresholds for each protocol, keyed by the pool type name with a `default` entry
+pub struct LiquidityConfig {
+    default: LiquidityThresholds,
+    protocols: HashMap<String, LiquidityThresholds>,
+    // trade sizes in eth the price impact is measured at
+    impact_sizes: Vec<f64>,
+}
+
+impl LiquidityConfig {
+    // Load the thresholds from the json file at LIQUIDITY_THRESHOLDS and the impact sizes from
+    // IMPACT_SIZES_ETH, a comma separated list that defaults to 0.01,0.1,1
+    pub fn from_env() -> Self {
+        let mut protocols: HashMap<String, LiquidityThresholds> = match std::env::var("LIQUIDITY_THRESHOLDS") {
+            Ok(path) => File::open(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|file| serde_json::from_reader(BufReader::new(file)).map_err(|e| e.to_string()))
+                .unwrap_or_else(|e| {
+                    warn!("Failed to read liquidity thresholds {}: {}", path, e);
+                    HashMap::new()
+                }),
+            Err(_) => HashMap::new(),
+        };
+        let default = protocols.remove("default").unwrap_or_default();
+
+        let mut impact_sizes: Vec<f64> = std::env::var("IMPACT_SIZES_ETH")
+            .unwrap_or_else(|_| "0.01,0.1,1".to_string())
+            .split(',')
+            .filter_map(|size| size.trim().parse().ok())
+            .collect();
+        impact_sizes.sort_by(f64::total_cmp);
+
+        Self {
+            default,
+            protocols,
+            impact_sizes,
+        }
+    }
+
+    fn thresholds(&self, pool: &Pool) -> &LiquidityThresholds {
+        self.protocols
+            .get(&format!("{:?}", pool.pool_type()))
+            .unwrap_or(&self.default)
+    }
+
+    // If the pool passes the thresholds for its protocol, pools we can not measure are kept
+    pub fn is_liquid(&self, pool: &Pool, prices: &HashMap<Address, f64>) -> bool {
+        if !has_depth_model(pool) {
+            return true;
+        }
+        let thresholds = self.thresholds(pool);
+        liquidity_metrics(pool, prices, &self.impact_sizes, thresholds)
+            .is_some_and(|metrics| metrics.passes(thresholds))
+    }
+}
+
+// Depth of a pool computed from the synced state
+#[derive(Debug, Clone)]
+pub struct LiquidityMetrics {
+    pub tvl_eth: f64,
+    // (size in eth, worst price impact of both directions in bps)
+    pub impact_bps: Vec<(f64, f64)>,
+    // only set for v3 pools
+    pub v3_coverage: Option<f64>,
+}
+
+impl LiquidityMetrics {
+    // Mean share of the spot price that is kept over the impact sizes, scaled by the coverage
+    pub fn score(&self) -> f64 {
+        if self.impact_bps.is_empty() {
+            return 0.0;
+        }
+        let kept: f64 = self
+            .impact_bps
+            .iter()
+            .map(|(_, bps)| 1.0 - (bps / 10000.0).min(1.0))
+            .sum::<f64>()
+            / self.impact_bps.len() as f64;
+        kept * self.v3_coverage.unwrap_or(1.0)
+    }
+
+    fn passes(&self, thresholds: &LiquidityThresholds) -> bool {
+        if self.tvl_eth < thresholds.min_tvl_eth {
+            return false;
+        }
+        if self.impact_bps.first().is_some_and(|(_, bps)| *bps > thresholds.max_impact_bps) {
+            return false;
+        }
+        self.v3_coverage.map_or(true, |coverage| coverage >= thresholds.min_v3_coverage)
+    }
+}
+
+// Snapshot of the quality scores of all filtered pools
+pub fn pool_scores() -> Arc<PoolScores> {
+    SCORES.read().unwrap().clone()
+}
+
+// Drop the pools that are too shallow for their protocol and record the quality score of the
+// ones that are kept. Prices are weth per token unit from the universe pricing
+pub fn filter_by_liquidity(pools: Vec<Pool>, prices: &HashMap<Address, f64>) -> Vec<Pool> {
+    let config = LiquidityConfig::from_env();
+    let mut scores: PoolScores = (*pool_scores()).clone();
+    let mut unmeasured = 0;
+
+    let kept: Vec<Pool> = pools
+        .into_iter()
+        .filter(|pool| {
+            // balancer, curve and maverick reserves are not synced, the pool goes through
+            // unscored and the quote decides
+            if !has_depth_model(pool) {
+                debug!("Keeping {:?} pool {} without liquidity metrics", pool.pool_type(), pool.address());
+                unmeasured += 1;
+                return true;
+            }
+            let thresholds = config.thresholds(pool);
+            let Some(metrics) = liquidity_metrics(pool, prices, &config.impact_sizes, thresholds) else {
+                debug!("No liquidity metrics for {}", pool.address());
+                return false;
+            };
+            if !metrics.passes(thresholds) {
+                debug!("Dropping {} with {:?}", pool.address(), metrics);
+                return false;
+            }
+            scores.insert(pool.address(), metrics.score());
+            true
+        })
+        .collect();
+
+    info!("Scored {} pools, kept {} that can not be measured", scores.len(), unmeasured);
+    *SCORES.write().unwrap() = Arc::new(scores);
+    kept
+}
+
+// If the depth of the pool can be measured from its synced state
+fn has_depth_model(pool: &Pool) -> bool {
+    pool.get_v2().is_some() || pool.get_v3().is_some()
+}
+
+// Compute the metrics of a pool, pools without a weth price for both tokens or an unsupported
+// curve have none
+pub fn liquidity_metrics(
+    pool: &Pool,
+    prices: &HashMap<Address, f64>,
+    impact_sizes: &[f64],
+    thresholds: &LiquidityThresholds,
+) -> Option<LiquidityMetrics> {
+    let tvl_eth = pool_liquidity(pool, prices)? / 1e18;
+    let price0 = *prices.get(&pool.token0_address())?;
+    let price1 = *prices.get(&pool.token1_address())?;
+
+    let mut impact_bps = Vec::new();
+    for size in impact_sizes {
+        // the worst of both directions
+        let amount0 = size * 1e18 / price0;
+        let amount1 = size * 1e18 / price1;
+        let impact = price_impact(pool, amount0, true)?.max(price_impact(pool, amount1, false)?);
+        impact_bps.push((*size, impact * 10000.0));
+    }
+
+    let v3_coverage = pool.get_v3().map(|v3| {
+        let ticks: Vec<(i32, i128)> = v3.ticks.iter().map(|(tick, info)| (*tick, info.liquidity_net)).collect();
+        tick_coverage(v3.tick, v3.liquidity, &ticks, thresholds.coverage_ticks)
+    });
+
+    Some(LiquidityMetrics {
+        tvl_eth,
+        impact_bps,
+        v3_coverage,
+    })
+}
+
+// Price impact of a swap before fees, as a fraction of the spot price. A swap that can not be
+// filled has an impact of one
+fn price_impact(pool: &Pool, amount_in: f64, zero_for_one: bool) -> Option<f64> {
+    if amount_in <= 0.0 {
+        return Some(0.0);
+    }
+    if let Some(v3) = pool.get_v3() {
+        let sqrt_price = (v3.sqrt_price >> 32).saturating_to::<u128>() as f64 / 2f64.powi(64);
+        let ticks: Vec<(i32, i128)> = v3.ticks.iter().map(|(tick, info)| (*tick, info.liquidity_net)).collect();
+        return Some(v3_impact(sqrt_price, v3.tick, v3.liquidity, &ticks, amount_in, zero_for_one));
+    }
+
+    let v2 = pool.get_v2()?;
+    let reserve0 = v2.token0_reserves.saturating_to::<u128>() as f64;
+    let reserve1 = v2.token1_reserves.saturating_to::<u128>() as f64;
+    let (reserve_in, reserve_out) = if zero_for_one { (reserve0, reserve1) } else { (reserve1, reserve0) };
+    if reserve_in <= 0.0 || reserve_out <= 0.0 {
+        return Some(1.0);
+    }
+
+    if v2.stable.unwrap_or(false) {
+        // x3y + y3x on the reserves normalized to whole tokens
+        let (decimals_in, decimals_out) = if zero_for_one {
+            (v2.token0_decimals, v2.token1_decimals)
+        } else {
+            (v2.token1_decimals, v2.token0_decimals)
+        };
+        let scale_in = 10f64.powi(decimals_in as i32);
+        let scale_out = 10f64.powi(decimals_out as i32);
+        let (x, y) = (reserve_in / scale_in, reserve_out / scale_out);
+        let dx = amount_in / scale_in;
+        let spot = (3.0 * x * x * y + y * y * y) / (x * x * x + 3.0 * y * y * x);
+        let out = y - stable_y(x + dx, x * x * x * y + y * y * y * x, y);
+        return Some((1.0 - out / (dx * spot)).clamp(0.0, 1.0));
+    }
+
+    // constant product, the spot price is reserve_out / reserve_in
+    Some(amount_in / (reserve_in + amount_in))
+}
+
+// Solve x3y + y3x = k for y with newton's method
+fn stable_y(x: f64, k: f64, mut y: f64) -> f64 {
+    for _ in 0..255 {
+        let f = x * x * x * y + y * y * y * x - k;
+        let d = x * x * x + 3.0 * y * y * x;
+        if d == 0.0 {
+            break;
+        }
+        let next = y - f / d;
+        if (next - y).abs() <= y * 1e-12 {
+            return next;
+        }
+        y = next;
+    }
+    y
+}
+
+// Walk a v3 swap through the initialized ticks with the liquidity in each range
+fn v3_impact(
+    sqrt_price: f64,
+    tick: i32,
+    liquidity: u128,
+    ticks: &[(i32, i128)],
+    amount_in: f64,
+    zero_for_one: bool,
+) -> f64 {
+    if sqrt_price <= 0.0 {
+        return 1.0;
+    }
+    let mut next_ticks: Vec<(i32, i128)> = ticks
+        .iter()
+        .filter(|(t, _)| if zero_for_one { *t <= tick } else { *t > tick })
+        .copied()
+        .collect();
+    if zero_for_one {
+        next_ticks.sort_by(|a, b| b.0.cmp(&a.0));
+    } else {
+        next_ticks.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+
+    let mut current = sqrt_price;
+    let mut liquidity = liquidity as f64;
+    let mut remaining = amount_in;
+    let mut out = 0.0;
+    for (next_tick, liquidity_net) in next_ticks {
+        let target = 1.0001f64.powf(next_tick as f64 / 2.0);
+        if liquidity > 0.0 {
+            if zero_for_one {
+                let to_target = liquidity * (1.0 / target - 1.0 / current);
+                if remaining <= to_target {
+                    let end = 1.0 / (1.0 / current + remaining / liquidity);
+                    out += liquidity * (current - end);
+                    remaining = 0.0;
+                    break;
+                }
+                out += liquidity * (current - target);
+                remaining -= to_target;
+            } else {
+                let to_target = liquidity * (target - current);
+                if remaining <= to_target {
+                    let end = current + remaining / liquidity;
+                    out += liquidity * (1.0 / current - 1.0 / end);
+                    remaining = 0.0;
+                    break;
+                }
+                out += liquidity * (1.0 / current - 1.0 / target);
+                remaining -= to_target;
+            }
+        }
+        // crossing down removes the net liquidity, crossing up adds it
+        liquidity = if zero_for_one {
+            liquidity - liquidity_net as f64
+        } else {
+            liquidity + liquidity_net as f64
+        };
+        current = target;
+    }
+    if remaining > 0.0 {
+        return 1.0;
+    }
+
+    let spot = if zero_for_one {
+        sqrt_price * sqrt_price
+    } else {
+        1.0 / (sqrt_price * sqrt_price)
+    };
+    (1.0 - out / (amount_in * spot)).clamp(0.0, 1.0)
+}
+
+// Share of the ticks within the window each side of the current tick that have liquidity
+fn tick_coverage(tick: i32, liquidity: u128, ticks: &[(i32, i128)], window: i32) -> f64 {
+    if window <= 0 {
+        return 0.0;
+    }
+    let mut covered: i64 = 0;
+
+    // walk up
+    let mut above: Vec<(i32, i128)> = ticks
+        .iter()
+        .filter(|(t, _)| *t > tick && *t <= tick + window)
+        .copied()
+        .collect();
+    above.sort_by_key(|(t, _)| *t);
+    let mut active = liquidity as i128;
+    let mut position = tick;
+    for (next, net) in above {
+        if active > 0 {
+            covered += (next - position) as i64;
+        }
+        active += net;
+        position = next;
+    }
+    if active > 0 {
+        covered += (tick + window - position) as i64;
+    }
+
+    // walk down
+    let mut below: Vec<(i32, i128)> = ticks
+        .iter()
+        .filter(|(t, _)| *t <= tick && *t >= tick - window)
+        .copied()
+        .collect();
+    below.sort_by(|a, b| b.0.cmp(&a.0));
+    let mut active = liquidity as i128;
+    let mut position = tick;
+    for (next, net) in below {
+        if active > 0 {
+            covered += (position - next) as i64;
+        }
+        active -= net;
+        position = next;
+    }
+    if active > 0 {
+        covered += (position - (tick - window)) as i64;
+    }
+
+    covered as f64 / (2 * window) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_liquidity_coverage() {
+        // one position from -500 to 500 around tick 0, half of a 1000 tick window
+        let ticks = vec![(-500, 1_000_000i128), (500, -1_000_000i128)];
+        let coverage = tick_coverage(0, 1_000_000, &ticks, 1000);
+        assert!((coverage - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_v3_impact_matches_constant_product_in_range() {
+        // inside a single wide range v3 behaves like constant product on the virtual reserves
+        let liquidity: u128 = 1_000_000_000_000_000_000;
+        let ticks = vec![(-887220, liquidity as i128), (887220, -(liquidity as i128))];
+        let amount_in = 1e16;
+        let impact = v3_impact(1.0, 0, liquidity, &ticks, amount_in, true);
+        let expected = amount_in / (liquidity as f64 + amount_in);
+        assert!((impact - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_unfilled_swap_has_full_impact() {
+        let ticks = vec![(-10, 1_000i128), (10, -1_000i128)];
+        assert_eq!(v3_impact(1.0, 0, 1_000, &ticks, 1e18, true), 1.0);
+    }
+}
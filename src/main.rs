@@ -9,30 +9,45 @@ use anyhow::Result;
 use ignition::start_workers;
 use lazy_static::lazy_static;
 use log::{info, LevelFilter};
-use pool_sync::{Chain, PoolType, PoolSync};
+use refresh::build_pool_sync;
 
+mod balance_slot;
+mod base_tokens;
+mod block_env;
 mod bytecode;
 mod cache;
 mod calculation;
+mod divergence;
 mod estimator;
 mod events;
+mod execution;
 mod filter;
+#[cfg(test)]
+mod fixtures;
+mod flash_loan;
 mod gas_station;
 mod gen_;
 mod graph;
 mod history_db;
+mod hop_trace;
 mod ignition;
+mod liquidity;
 mod market_state;
+mod protocol;
 mod quoter;
+mod refresh;
 mod searcher;
 mod simulator;
 mod state_db;
+mod strategy;
 mod stream;
 mod swap;
+mod token_profile;
 mod tracing;
 mod traits;
 mod tx_sender;
 mod types;
+mod universe;
 
 // initial amount we are trying to arb over
 lazy_static! {
@@ -49,23 +64,7 @@ async fn main() -> Result<()> {
 
     // Load in all the pools
     info!("Loading and syncing pools...");
-    let pool_sync = PoolSync::builder()
-        .add_pools(&[
-            PoolType::UniswapV2,
-            PoolType::PancakeSwapV2,
-            PoolType::SushiSwapV2,
-            PoolType::UniswapV3,
-            PoolType::SushiSwapV3,
-            PoolType::BaseSwapV2,
-            PoolType::BaseSwapV3,
-            PoolType::Aerodrome,
-            PoolType::Slipstream,
-            PoolType::AlienBaseV2,
-            PoolType::AlienBaseV3,
-        ])
-        .chain(Chain::Base)
-        .rate_limit(1000)
-        .build()?;
+    let pool_sync = build_pool_sync()?;
     let (pools, last_synced_block) = pool_sync.sync_pools().await?;
 
     start_workers(pools, last_synced_block).await;
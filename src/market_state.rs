@@ -4,13 +4,14 @@ use alloy::transports::Transport;
 use alloy::network::Network;
 use alloy::primitives::{address, Address, U256};
 use alloy::eips::BlockNumberOrTag;
+use alloy::rpc::types::Header;
 use alloy::sol_types::SolValue;
 use alloy::sol_types::SolCall;
 use anyhow::Result;
 use log::{debug, error, info};
 use pool_sync::Pool;
 use revm::{
-    context::{ ContextTr, Evm},
+    context::ContextTr,
     context_interface::{
         TransactTo, Database, JournalTr,
     },
@@ -36,7 +37,7 @@ use std::collections::HashSet;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc::Sender;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 use std::time::Instant;
 use tokio::sync::broadcast::Receiver;
 
@@ -45,10 +46,18 @@ use crate::types::*;
 use crate::events::Event;
 use crate::gen_::ERC20Token;
 use crate::gen_::FlashQuoter;
+use crate::protocol::{executor_code, is_stable};
+use crate::balance_slot::{balance_slot, save_balance_slots};
+use crate::bytecode::classify_pools;
 use crate::state_db::{BlockStateDB, InsertionType};
 use crate::tracing::debug_trace_block;
+use crate::universe::record_block_volume;
+use crate::block_env::{sim_evm, BlockContext, SimulateAt};
 use crate::AMOUNT;
 
+// Times the slots of added pools are read again when blocks keep landing during the read
+const MAX_RESYNC_ATTEMPTS: usize = 3;
+
 // Internal representation of the current state of the blockchain
 pub struct MarketState<T, N, P>
 where
@@ -57,6 +66,10 @@ where
     P: Provider<N>,
 {
     pub db: RwLock<BlockStateDB<T, N, P>>,
+    // header of the block the state is at
+    pub header: RwLock<Option<Header>>,
+    // bitmap words quotes walked into that are not loaded yet, loaded with the next block
+    pub missing_words: Mutex<HashSet<(Address, i16)>>,
 }
 
 
@@ -77,14 +90,28 @@ where
     ) -> Result<Arc<Self>> {
         debug!("Populating the db with {} pools", pools.len());
 
+        // the header of the synced block, simulations during the warm up run in it
+        let http_url = std::env::var("FULL").unwrap().parse().unwrap();
+        let http = ProviderBuilder::new().on_http(http_url);
+        let header = http
+            .get_block_by_number(BlockNumberOrTag::Number(last_synced_block), false)
+            .await?
+            .map(|block| block.header);
+
         // construct, warm up, and populate the db
         let mut db = BlockStateDB::new(provider).unwrap();
+        if let Some(header) = &header {
+            db.block = BlockContext::from_header(header);
+        }
         Self::warm_up_database(&pools, &mut db);
         Self::populate_db_with_pools(pools.clone(), &mut db);
+        classify_pools(&mut db, &pools);
 
         // init the market state with the db
         let market_state = Arc::new(Self {
             db: RwLock::new(db),
+            header: RwLock::new(header),
+            missing_words: Mutex::new(HashSet::new()),
         });
 
         // start the state updater
@@ -127,6 +154,13 @@ where
             last_synced_block = current_block;
             current_block = http.get_block_number().await.unwrap();
         }
+        match http
+            .get_block_by_number(BlockNumberOrTag::Number(last_synced_block), false)
+            .await
+        {
+            Ok(Some(block)) => self.set_header(block.header),
+            _ => error!("Failed to fetch the header of block {last_synced_block}"),
+        }
 
         // signal that we are caught up
         caught_up.store(true, Ordering::Relaxed);
@@ -146,6 +180,7 @@ where
             // update the state and get the list of updated pools
             debug!("Processing block {block_number}");
             let updated_pools = self.update_state(http.clone(), block_number).await;
+            self.set_header(block_header);
             debug!("Processed the block {block_number}");
 
             // send the updated pools
@@ -164,6 +199,17 @@ where
         }
     }
 
+    // Queue a bitmap word a quote needed, quotes only read the db so they can not load it
+    pub fn queue_tick_word(&self, pool: Address, word: i16) {
+        self.missing_words.lock().unwrap().insert((pool, word));
+    }
+
+    // Move the simulation block to the header, the state was just updated to it
+    pub fn set_header(&self, header: Header) {
+        self.db.write().unwrap().block = BlockContext::from_header(&header);
+        *self.header.write().unwrap() = Some(header);
+    }
+
     // after getting a new block, update our market state
     async fn update_state(
         &self,
@@ -176,6 +222,12 @@ where
         // trace the block to get all post state changes
         let updates = debug_trace_block(provider, BlockNumberOrTag::Number(block_num), true).await;
 
+        // the trace universe ranks tokens from what each block touched
+        let touched: HashSet<Address> = updates.iter().flat_map(|update| update.keys()).copied().collect();
+        if let Err(e) = record_block_volume(block_num, touched.into_iter().collect()) {
+            error!("Failed to record the volume of block {block_num}: {e}");
+        }
+
         // aquire write access so we can update the db and go over all updates
         let mut db = self.db.write().unwrap();
         for (address, account_state) in updates.iter().flat_map(|btree_map| btree_map.iter()) {
@@ -187,9 +239,73 @@ where
             }
         }
 
+        // the quotes through these pools came out empty, they are searched again once loaded
+        let missing: Vec<(Address, i16)> = self.missing_words.lock().unwrap().drain().collect();
+        for (pool, word) in missing {
+            if !db.tracking_pool(&pool) {
+                continue;
+            }
+            match db.load_tick_word(pool, word) {
+                Ok(()) => {
+                    updated_pools.insert(pool);
+                }
+                Err(e) => debug!("Failed to load tick word {word} of {pool}: {e:?}"),
+            }
+        }
+
         updated_pools
     }
 
+    // Add pools to the running market state, they are updated from the next block on. The
+    // pools are warmed up in a scratch db so quotes are not blocked while it runs, and their
+    // synced slots are read again at the latest block. Returns the pools that were added
+    pub fn add_pools(&self, pools: &Vec<Pool>) -> Vec<Pool>
+    where
+        P: Clone,
+    {
+        let Some(mut scratch) = self.db.read().unwrap().scratch() else {
+            error!("Failed to create a scratch db for {} pools", pools.len());
+            return Vec::new();
+        };
+        Self::warm_up_database(pools, &mut scratch);
+        Self::populate_db_with_pools(pools.clone(), &mut scratch);
+        classify_pools(&mut scratch, pools);
+
+        // a block the state updater processes while the slots are read is not applied to the
+        // scratch pools, read them again if one landed
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let block = self.db.read().unwrap().block.number;
+            for pool in pools {
+                if let Err(e) = scratch.resync_pool(pool.address()) {
+                    error!("Failed to resync pool {}: {e:?}", pool.address());
+                    scratch.remove_pool(&pool.address());
+                }
+            }
+
+            let mut db = self.db.write().unwrap();
+            if db.block.number != block && attempts < MAX_RESYNC_ATTEMPTS {
+                continue;
+            }
+            let added: Vec<Pool> = pools
+                .iter()
+                .filter(|pool| scratch.tracking_pool(&pool.address()))
+                .cloned()
+                .collect();
+            db.merge(scratch);
+            return added;
+        }
+    }
+
+    // Stop updating the pools
+    pub fn remove_pools(&self, pools: &[Address]) {
+        let mut db = self.db.write().unwrap();
+        for pool in pools {
+            db.remove_pool(pool);
+        }
+    }
+
     // Insert pool information into the database
     fn populate_db_with_pools(pools: Vec<Pool>, db: &mut BlockStateDB<T, N, P>) {
         for pool in pools {
@@ -197,6 +313,11 @@ where
                 db.insert_v2(pool);
             } else if pool.is_v3() {
                 db.insert_v3(pool).unwrap();
+            } else {
+                // no native slots to write, the pool is quoted from its typed state or its code
+                let address = pool.address();
+                db.add_pool(pool);
+                db.refresh_pool_state(address);
             }
         }
     }
@@ -209,9 +330,8 @@ where
         let account = address!("d8da6bf26964af9d7eed9e03e53415d37aa96045");
         let quoter: Address = address!("0000000000000000000000000000000000001000");
 
-        // how many tokens we want to insert and ERC20 balance slot
+        // how many tokens we want to insert
         let ten_units = U256::from(10_000_000_000_000_000_000u128);
-        let balance_slot = keccak256((account, U256::from(3)).abi_encode());
 
         // insert the quoter bytecode so we can make calles to it
         let quoter_bytecode = FlashQuoter::DEPLOYED_BYTECODE.clone();
@@ -227,11 +347,17 @@ where
         // we have already filtered all of these pools, so we can assume
         // that these are good to go and load up db with info
         for pool in pools {
-            // give some balance of the input token
+            // give some balance of the input token, through the discovered balance slot
+            let block = db.block;
+            let Some(balance_slot) = balance_slot(db, &block, pool.token0_address()) else {
+                continue;
+            };
+            let slot = balance_slot.slot(account);
+            let current = db.storage(pool.token0_address(), slot).unwrap_or_default();
             db.insert_account_storage(
                 pool.token0_address(),
-                balance_slot.into(),
-                ten_units,
+                slot,
+                balance_slot.pack(current, ten_units),
                 InsertionType::OnChain,
             )
             .unwrap();
@@ -242,8 +368,7 @@ where
                 amount: U256::from(1e18),
             }
             .abi_encode();
-            let mut evm = Evm::builder()
-                .with_db(&mut *db)
+            let mut evm = sim_evm(&mut *db, &block)
                 .modify_tx_env(|tx| {
                     tx.caller = account;
                     tx.data = approve_calldata.into();
@@ -252,12 +377,14 @@ where
                 .build();
             evm.transact_commit().unwrap();
 
-            // Try to do the swap from input to output token
-            let is_v3 = if pool.is_v3() { 1 } else { 0 };
+            // Try to do the swap from input to output token, if the quoter can swap through it
+            let Ok(pool_version) = executor_code(pool.pool_type(), is_stable(pool)) else {
+                continue;
+            };
 
             let quote_path = FlashQuoter::SwapParams {
                 pools: vec![pool.address()],
-                poolVersions: vec![is_v3],
+                poolVersions: vec![pool_version],
                 amountIn: *AMOUNT,
             };
 
@@ -270,5 +397,6 @@ where
             // transact
             evm.transact().unwrap();
         }
+        save_balance_slots();
     }
 }
@@ -0,0 +1,107 @@
+use alloy::primitives::U256;
+use anyhow::{anyhow, Result};
+use pool_sync::{Pool, PoolInfo, PoolType};
+
+// Protocol codes used in the poolVersions field of the executor and quoter SwapParams. The
+// deployed contracts only branch on V2 (0) and V3 (1), the other codes are reserved so an
+// executor that adds the protocol can use the same encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum ProtocolCode {
+    // uniswap v2 style pair, the fee is looked up from the factory
+    V2 = 0,
+    // uniswap v3 style pool that calls back uniswapV3SwapCallback
+    V3 = 1,
+    // v3 style pool that calls back pancakeV3SwapCallback
+    PancakeV3 = 2,
+    AerodromeVolatile = 3,
+    AerodromeStable = 4,
+    BalancerV2 = 5,
+    Curve = 6,
+    MaverickV2 = 7,
+}
+
+impl ProtocolCode {
+    // If the deployed executor and quoter can swap through the protocol
+    pub fn is_executable(self) -> bool {
+        matches!(self, ProtocolCode::V2 | ProtocolCode::V3)
+    }
+}
+
+// How a protocol is encoded and what fee it charges
+#[derive(Debug, Clone, Copy)]
+pub struct ProtocolSpec {
+    pub code: ProtocolCode,
+    // input multiplier out of 10000 for v2 forks, the same values as the executor factory fees
+    pub v2_fee: Option<U256>,
+}
+
+// Look up the protocol, stable only matters for aerodrome
+pub fn protocol_spec(protocol: PoolType, stable: bool) -> Result<ProtocolSpec> {
+    let spec = |code, v2_fee: Option<u64>| ProtocolSpec {
+        code,
+        v2_fee: v2_fee.map(U256::from),
+    };
+    let spec = match protocol {
+        PoolType::UniswapV2 | PoolType::SushiSwapV2 | PoolType::SwapBasedV2 => spec(ProtocolCode::V2, Some(9970)),
+        PoolType::PancakeSwapV2 | PoolType::BaseSwapV2 | PoolType::DackieSwapV2 => {
+            spec(ProtocolCode::V2, Some(9975))
+        }
+        PoolType::AlienBaseV2 => spec(ProtocolCode::V2, Some(9984)),
+        PoolType::UniswapV3
+        | PoolType::SushiSwapV3
+        | PoolType::BaseSwapV3
+        | PoolType::AlienBaseV3
+        | PoolType::SwapBasedV3
+        | PoolType::Slipstream => spec(ProtocolCode::V3, None),
+        PoolType::PancakeSwapV3 | PoolType::DackieSwapV3 => spec(ProtocolCode::PancakeV3, None),
+        PoolType::Aerodrome if stable => spec(ProtocolCode::AerodromeStable, None),
+        PoolType::Aerodrome => spec(ProtocolCode::AerodromeVolatile, None),
+        PoolType::BalancerV2 => spec(ProtocolCode::BalancerV2, None),
+        PoolType::CurveTwoCrypto | PoolType::CurveTriCrypto => spec(ProtocolCode::Curve, None),
+        PoolType::MaverickV2 => spec(ProtocolCode::MaverickV2, None),
+        other => return Err(anyhow!("No protocol code for {other:?}")),
+    };
+    Ok(spec)
+}
+
+// Protocol code for a step, failing if the executor can not swap through it
+pub fn executor_code(protocol: PoolType, stable: bool) -> Result<u8> {
+    let spec = protocol_spec(protocol, stable)?;
+    if !spec.code.is_executable() {
+        return Err(anyhow!("Executor can not swap through {protocol:?} ({:?})", spec.code));
+    }
+    Ok(spec.code as u8)
+}
+
+// If the pool is an aerodrome stable pool
+pub fn is_stable(pool: &Pool) -> bool {
+    pool.pool_type() == PoolType::Aerodrome && pool.get_v2().and_then(|pool| pool.stable).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Only v2 and v3 style pools are encoded for the deployed executor, the rest are rejected
+    #[test]
+    fn test_executor_code() {
+        assert_eq!(executor_code(PoolType::UniswapV2, false).unwrap(), 0);
+        assert_eq!(executor_code(PoolType::AlienBaseV2, false).unwrap(), 0);
+        assert_eq!(executor_code(PoolType::UniswapV3, false).unwrap(), 1);
+        assert_eq!(executor_code(PoolType::Slipstream, false).unwrap(), 1);
+
+        for protocol in [
+            PoolType::PancakeSwapV3,
+            PoolType::DackieSwapV3,
+            PoolType::Aerodrome,
+            PoolType::BalancerV2,
+            PoolType::CurveTwoCrypto,
+            PoolType::MaverickV2,
+        ] {
+            assert!(executor_code(protocol, false).is_err(), "{protocol:?}");
+        }
+        assert!(executor_code(PoolType::Aerodrome, true).is_err());
+        assert!(executor_code(PoolType::MaverickV1, false).is_err());
+    }
+}
@@ -111,7 +111,7 @@ use alloy::transports::http::{Client, Http};
     use alloy::network::Ethereum;
     use anyhow::{anyhow, Result};
     use revm::{
-        context::{ContextSetters, ContextTr, Evm},
+        context::{ContextSetters, ContextTr},
         context_interface::{
             result::{EVMError, ExecutionResult, ResultAndState},
             TransactTo, Database, JournalTr,
@@ -120,18 +120,22 @@ use alloy::transports::http::{Client, Http};
             instructions::{EthInstructions, InstructionProvider},
             EthPrecompiles, EvmTr,
         },
-        database::InMemoryDB,
+        database::{CacheDB, InMemoryDB},
         inspector::{inspect_instructions, InspectorEvmTr, JournalExt},
         interpreter::{interpreter::EthInterpreter, Interpreter, InterpreterTypes},
-        Inspector,
+        DatabaseCommit, DatabaseRef, Inspector,
     };
     
     use std::sync::Arc;
     use crate::traits::*;
     use crate::types::*;
     use crate::gen_::FlashQuoter;
+    use crate::hop_trace::{HopTracer, PathTrace};
+    use crate::block_env::{sim_evm, BlockContext, SimulateAt};
     use crate::market_state::MarketState;
-    use crate::AMOUNT;
+
+    // Each optimization step adds this fraction of the starting input
+    const OPTIMIZE_STEPS_PER_INPUT: u64 = 5;
     
     // Quoter. This is used to get a simulation quote before sending off a transaction.
     // This will confirm that our offchain calculations are reasonable and make sure we can swap the tokens
@@ -143,8 +147,9 @@ use alloy::transports::http::{Client, Http};
             market_state: Arc<MarketState<Http<Client>, Ethereum, RootProvider<Http<Client>>>>,
         ) -> Result<Vec<U256>> {
             let mut guard = market_state.db.write().unwrap();
-            // need to pass this as mut somehow
-            let mut evm = Evm::builder().with_db(&mut *guard).build();
+            // the transaction lands in the next block at the earliest
+            let block = guard.block.at(SimulateAt::NextBlock);
+            let mut evm = sim_evm(&mut *guard, &block).build();
             evm.tx_mut().caller = address!("d8da6bf26964af9d7eed9e03e53415d37aa96045");
             evm.tx_mut().transact_to =
                 TransactTo::Call(address!("0000000000000000000000000000000000001000"));
@@ -177,20 +182,105 @@ use alloy::transports::http::{Client, Http};
             }
         }
     
+        // Quote several routes as one batch. Every route runs on an overlay of the market state
+        // after the routes before it, so routes that share a pool see each others effects. A
+        // route that reverts is reported and its changes are dropped, the rest of the batch
+        // is still quoted
+        pub fn quote_batch(
+            routes: Vec<FlashQuoter::SwapParams>,
+            market_state: Arc<MarketState<Http<Client>, Ethereum, RootProvider<Http<Client>>>>,
+        ) -> Vec<Result<Vec<U256>>> {
+            let guard = market_state.db.read().unwrap();
+            let block = guard.block.at(SimulateAt::NextBlock);
+            Self::quote_batch_on(&*guard, &block, routes)
+        }
+
+        // Quote the routes one after the other on an overlay of the db
+        fn quote_batch_on<DB>(db: DB, block: &BlockContext, routes: Vec<FlashQuoter::SwapParams>) -> Vec<Result<Vec<U256>>>
+        where
+            DB: DatabaseRef,
+            DB::Error: std::fmt::Debug,
+        {
+            let mut overlay = CacheDB::new(db);
+
+            let mut quotes = Vec::with_capacity(routes.len());
+            for route in routes {
+                let quote_calldata = FlashQuoter::quoteArbitrageCall { params: route }.abi_encode();
+                let mut evm = sim_evm(&mut overlay, block)
+                    .modify_tx_env(|tx| {
+                        tx.caller = address!("d8da6bf26964af9d7eed9e03e53415d37aa96045");
+                        tx.transact_to =
+                            TransactTo::Call(address!("0000000000000000000000000000000000001000"));
+                        tx.data = quote_calldata.into();
+                    })
+                    .build();
+                let ResultAndState { result, state } = match evm.transact() {
+                    Ok(result_and_state) => result_and_state,
+                    Err(e) => {
+                        quotes.push(Err(anyhow!("Failed to simulate {e:?}")));
+                        continue;
+                    }
+                };
+                drop(evm);
+
+                let quote = match result {
+                    ExecutionResult::Success { output: value, .. } => {
+                        Vec::<U256>::abi_decode(value.data()).map_err(|_| anyhow!("Failed to decode"))
+                    }
+                    ExecutionResult::Revert { output, .. } => Err(anyhow!("Simulation reverted {output}")),
+                    _ => Err(anyhow!("Failed to simulate")),
+                };
+                // only successful routes move the overlay forward
+                if quote.is_ok() {
+                    overlay.commit(state);
+                }
+                quotes.push(quote);
+            }
+            quotes
+        }
+
+        // Rerun a quote with the hop tracer attached. Nothing is written back to the market
+        // state, the trace shows what each pool moved and which call the revert came from
+        pub fn trace_path(
+            quote_params: FlashQuoter::SwapParams,
+            market_state: Arc<MarketState<Http<Client>, Ethereum, RootProvider<Http<Client>>>>,
+        ) -> Result<PathTrace> {
+            let guard = market_state.db.read().unwrap();
+            let block = guard.block.at(SimulateAt::NextBlock);
+            let mut overlay = CacheDB::new(&*guard);
+
+            let mut tracer = HopTracer::new(&quote_params.pools);
+            let quote_calldata = FlashQuoter::quoteArbitrageCall { params: quote_params }.abi_encode();
+            let mut evm = sim_evm(&mut overlay, &block)
+                .with_external_context(&mut tracer)
+                .modify_tx_env(|tx| {
+                    tx.caller = address!("d8da6bf26964af9d7eed9e03e53415d37aa96045");
+                    tx.transact_to =
+                        TransactTo::Call(address!("0000000000000000000000000000000000001000"));
+                    tx.data = quote_calldata.into();
+                })
+                .build();
+            evm.transact().map_err(|e| anyhow!("Failed to trace {e:?}"))?;
+            drop(evm);
+            Ok(tracer.into_trace())
+        }
+
         /// Optimizes the input amount using binary search to find the maximum profitable input
-        /// Returns the optimal input amount and its corresponding output amounts
+        /// Returns the optimal input amount and its corresponding output amounts. The search
+        /// starts from the input of the path, which is sized in the units of its base token
         pub fn optimize_input(
             quote_path: FlashQuoter::SwapParams,
             initial_out: U256,
             market_state: Arc<MarketState<Http<Client>, Ethereum, RootProvider<Http<Client>>>>,
         ) -> (U256, U256) {
             let mut quote_path = quote_path.clone();
-            let mut curr_input = *AMOUNT;
-            let mut best_input = *AMOUNT;
+            let mut curr_input = quote_path.amountIn;
+            let mut best_input = quote_path.amountIn;
             let mut best_output = initial_out;
+            let step = (quote_path.amountIn / U256::from(OPTIMIZE_STEPS_PER_INPUT)).max(U256::from(1));
     
             for _ in 0..50 {
-                curr_input = curr_input + U256::from(2e14);
+                curr_input += step;
                 quote_path.amountIn = curr_input;
     
                 match Self::quote_path(quote_path.clone(), market_state.clone()) {
@@ -209,3 +299,48 @@ use alloy::transports::http::{Client, Http};
             (best_input, best_output)
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use alloy::primitives::Bytes;
+        use revm::database::EmptyDB;
+        use revm::state::{AccountInfo, Bytecode};
+        use std::str::FromStr;
+
+        // Quoter stand in. Counts the quotes in slot 0 and returns [amountIn, count], a zero
+        // input reverts
+        const COUNTING_QUOTER_CODE: &str =
+            "0x6064358015602757600054600101806000556060526040526002602052602060005260806000f35b60006000fd";
+
+        fn route(amount_in: u64) -> FlashQuoter::SwapParams {
+            FlashQuoter::SwapParams {
+                pools: vec![address!("00000000000000000000000000000000000000aa")],
+                poolVersions: vec![0],
+                amountIn: U256::from(amount_in),
+            }
+        }
+
+        // A reverted route is reported on its own, the routes after it are still quoted on top
+        // of the ones that succeeded before
+        #[test]
+        fn test_batch_tolerates_failed_routes() {
+            let mut db = CacheDB::new(EmptyDB::default());
+            let code = Bytecode::new_raw(Bytes::from_str(COUNTING_QUOTER_CODE).unwrap());
+            db.insert_account_info(
+                address!("0000000000000000000000000000000000001000"),
+                AccountInfo {
+                    nonce: 0,
+                    balance: U256::ZERO,
+                    code_hash: code.hash_slow(),
+                    code: Some(code),
+                },
+            );
+
+            let quotes = Quoter::quote_batch_on(&db, &BlockContext::default(), vec![route(5), route(0), route(7)]);
+            assert_eq!(quotes.len(), 3);
+            assert_eq!(quotes[0].as_ref().unwrap(), &vec![U256::from(5), U256::from(1)]);
+            assert!(quotes[1].is_err());
+            assert_eq!(quotes[2].as_ref().unwrap(), &vec![U256::from(7), U256::from(2)]);
+        }
+    }
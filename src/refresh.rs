@@ -0,0 +1,150 @@
+use alloy::network::Network;
+use alloy::primitives::Address;
+use alloy::providers::Provider;
+use alloy::transports::Transport;
+use anyhow::Result;
+use log::{debug, info, warn};
+use pool_sync::{Chain, Pool, PoolInfo, PoolSync, PoolType};
+use std::collections::HashSet;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+
+use crate::events::Event;
+use crate::filter::filter_pools;
+use crate::liquidity::LiquidityConfig;
+use crate::market_state::MarketState;
+use crate::universe::weth_prices;
+
+// Construct the pool syncer for all of the protocols we trade
+pub fn build_pool_sync() -> Result<PoolSync> {
+    let pool_sync = PoolSync::builder()
+        .add_pools(&[
+            PoolType::UniswapV2,
+            PoolType::PancakeSwapV2,
+            PoolType::SushiSwapV2,
+            PoolType::UniswapV3,
+            PoolType::SushiSwapV3,
+            PoolType::BaseSwapV2,
+            PoolType::BaseSwapV3,
+            PoolType::Aerodrome,
+            PoolType::Slipstream,
+            PoolType::AlienBaseV2,
+            PoolType::AlienBaseV3,
+        ])
+        .chain(Chain::Base)
+        .rate_limit(1000)
+        .build()?;
+    Ok(pool_sync)
+}
+
+// Keeps the working set of pools current while running. Every REFRESH_SECS the pools are
+// resynced, which picks up the pools created by the factories since the last sync. Pools
+// outside of the working set go through the same filters as at startup and are added to the
+// market state at the latest block, then the searcher. Working pools that no longer pass the liquidity thresholds
+// of their protocol are evicted
+pub async fn refresh_pools<T, N, P>(
+    market_state: Arc<MarketState<T, N, P>>,
+    address_tx: Sender<Event>,
+    working_pools: Vec<Pool>,
+) where
+    T: Transport + Clone + 'static,
+    N: Network,
+    P: Provider<N> + Clone + 'static,
+{
+    let refresh_secs: u64 = std::env::var("REFRESH_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(3600);
+
+    let mut working: HashSet<Address> = working_pools.iter().map(|pool| pool.address()).collect();
+    let mut interval = tokio::time::interval(Duration::from_secs(refresh_secs));
+    // the first tick completes immediately and the startup filter just ran
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        info!("Refreshing the working pool set...");
+
+        let (pools, synced_block) = match build_pool_sync() {
+            Ok(pool_sync) => match pool_sync.sync_pools().await {
+                Ok(synced) => synced,
+                Err(e) => {
+                    warn!("Failed to resync pools: {}", e);
+                    continue;
+                }
+            },
+            Err(e) => {
+                warn!("Failed to build the pool syncer: {}", e);
+                continue;
+            }
+        };
+        let prices = weth_prices(&pools);
+        let liquidity = LiquidityConfig::from_env();
+        let is_liquid = |pool: &Pool| liquidity.is_liquid(pool, &prices);
+
+        // evict the working pools that have drained
+        let evicted: Vec<Address> = pools
+            .iter()
+            .filter(|pool| working.contains(&pool.address()) && !is_liquid(pool))
+            .map(|pool| pool.address())
+            .collect();
+
+        // run every pool outside of the working set through the filters
+        let candidates: Vec<Pool> = pools
+            .iter()
+            .filter(|pool| !working.contains(&pool.address()) && is_liquid(pool))
+            .cloned()
+            .collect();
+        debug!("{} candidate pools", candidates.len());
+
+        // the filters and the warm up run the evm over the node db, keep them off the runtime
+        // workers. The added pools are in the market state at the latest block when it returns
+        let added = if candidates.is_empty() {
+            Vec::new()
+        } else {
+            let market_state = market_state.clone();
+            let universe_pools = pools.clone();
+            let handle = Handle::current();
+            let filtered = tokio::task::spawn_blocking(move || {
+                let filtered = handle.block_on(filter_pools(candidates, &universe_pools, 4000, Chain::Base));
+                if filtered.is_empty() {
+                    return filtered;
+                }
+                market_state.add_pools(&filtered)
+            })
+            .await;
+            match filtered {
+                Ok(added) => added,
+                Err(e) => {
+                    warn!("Failed to add the candidate pools: {}", e);
+                    Vec::new()
+                }
+            }
+        };
+
+        // the searcher drops evicted pools from the market state after it stops searching them
+        if !evicted.is_empty() {
+            working.retain(|pool| !evicted.contains(pool));
+            if let Err(e) = address_tx.send(Event::PoolsRemoved(evicted.clone(), synced_block)) {
+                warn!("Failed to send evicted pools: {}", e);
+            }
+        }
+        if !added.is_empty() {
+            working.extend(added.iter().map(|pool| pool.address()));
+            // the added pools were read again at the block the market state is at
+            let block = market_state.db.read().unwrap().block.number;
+            if let Err(e) = address_tx.send(Event::PoolsAdded(added.clone(), block)) {
+                warn!("Failed to send added pools: {}", e);
+            }
+        }
+        info!(
+            "Refreshed the working set at block {}, {} added, {} evicted, {} pools",
+            synced_block,
+            added.len(),
+            evicted.len(),
+            working.len()
+        );
+    }
+}
@@ -3,21 +3,30 @@ use alloy::network::Network;
 use alloy::primitives::{Address, U256};
 use alloy::providers::Provider;
 use log::{debug, info};
+use pool_sync::Pool;
 use rayon::prelude::*;
 use std::collections::{HashMap, HashSet};
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
 use std::time::Instant;
 
+use crate::base_tokens::BaseToken;
 use crate::calculation::Calculator;
+use crate::divergence::DivergenceTracker;
 use crate::estimator::Estimator;
 use crate::events::Event;
+use crate::flash_loan::{FlashLoans, LoanQuote};
+use crate::liquidity::{pool_scores, PoolScores};
 use crate::market_state::MarketState;
+use crate::protocol::executor_code;
+use crate::strategy::SearchStrategy;
 use crate::swap::SwapPath;
-use crate::AMOUNT;
+
+// how often to log the divergence stats, in blocks
+const DIVERGENCE_STATS_INTERVAL: u64 = 100;
 
 // top level sercher struct
-// contains the calculator and all path information
+// contains the calculator and the strategy used to find paths
 pub struct Searchoor<T, N, P>
 where
     T: Transport + Clone,
@@ -26,9 +35,12 @@ where
 {
     calculator: Calculator<T, N, P>,
     estimator: Estimator<T, N, P>,
-    path_index: HashMap<Address, Vec<usize>>,
-    cycles: Vec<SwapPath>,
-    min_profit: U256,
+    strategy: Box<dyn SearchStrategy<T, N, P>>,
+    flash_loans: FlashLoans<T, N, P>,
+    // base token => its config and the min output for a cycle through it to be profitable
+    bases: HashMap<Address, (BaseToken, U256)>,
+    divergence: Arc<DivergenceTracker>,
+    market_state: Arc<MarketState<T, N, P>>,
 }
 
 impl<T, N, P> Searchoor<T, N, P>
@@ -37,46 +49,141 @@ where
     N: Network,
     P: Provider<T, N>,
 {
-    // Construct the searcher with the calculator and all the swap paths
+    // Construct the searcher with the calculator and the search strategy
     pub fn new(
-        cycles: Vec<SwapPath>,
+        strategy: Box<dyn SearchStrategy<T, N, P>>,
+        bases: Vec<BaseToken>,
         market_state: Arc<MarketState<T, N, P>>,
         estimator: Estimator<T, N, P>,
+        divergence: Arc<DivergenceTracker>,
     ) -> Self {
-        let calculator = Calculator::new(market_state);
-
-        // make our path mapper for easily getting touched paths
-        let mut index: HashMap<Address, Vec<usize>> = HashMap::new();
-        for (path_index, path) in cycles.iter().enumerate() {
-            for step in &path.steps {
-                index.entry(step.pool_address).or_default().push(path_index)
-            }
-        }
+        let flash_loans = FlashLoans::from_env(market_state.clone());
+        let calculator = Calculator::new(market_state.clone());
 
-        // calculate the min profit percentage
-        let initial_amount = *AMOUNT;
-        let flash_loan_fee = (initial_amount * U256::from(9)) / U256::from(10000);
-        let repayment_amount = initial_amount + flash_loan_fee;
-        let min_profit_percentage = (initial_amount * U256::from(1)) / U256::from(100);
-        let min_profit = repayment_amount + min_profit_percentage;
+        // calculate the min profit percentage for each base. The flash loan premium depends on
+        // the source so it is added per path once the cheapest source is known
+        let bases = bases
+            .into_iter()
+            .map(|base| {
+                let initial_amount = base.amount;
+                let min_profit_percentage = (initial_amount * U256::from(1)) / U256::from(100);
+                let min_profit = initial_amount + min_profit_percentage;
+                (base.token, (base, min_profit))
+            })
+            .collect();
 
         Self {
             calculator,
             estimator,
-            cycles,
-            path_index: index,
-            min_profit,
+            strategy,
+            flash_loans,
+            bases,
+            divergence,
+            market_state,
+        }
+    }
+
+    // Start tracking a new pool
+    pub fn add_pool(&mut self, pool: Pool) {
+        self.strategy.add_pool(&pool);
+        self.estimator.process_pools(vec![pool]);
+    }
+
+    // Stop tracking the pools. They are only dropped from the market state once no path goes
+    // through them, touched events that were queued before still find them in the db
+    pub fn remove_pools(&mut self, pools: &[Address]) {
+        for pool in pools {
+            self.strategy.remove_pool(*pool);
+        }
+        self.market_state.remove_pools(pools);
+    }
+
+    // Verify the estimated paths with the calculator until TOP_K pass, then greedily take the
+    // most profitable ones that do not share any pools so they do not change each others output
+    fn select_paths(&self, candidates: &[&(SwapPath, U256, U256)]) -> Vec<(SwapPath, U256, LoanQuote)> {
+        let top_k: usize = std::env::var("TOP_K")
+            .ok()
+            .and_then(|top_k| top_k.parse().ok())
+            .unwrap_or(8);
+
+        // get the calculated output, the cheapest way to fund it and its profit in eth
+        let mut verified: Vec<(&SwapPath, U256, LoanQuote, U256)> = candidates
+            .iter()
+            .filter_map(|(path, estimated_out, _)| {
+                // the executor has to be able to swap through every pool
+                if !path.steps.iter().all(|step| executor_code(step.protocol, step.stable).is_ok()) {
+                    return None;
+                }
+                let (base, min_profit) = &self.bases[&path.steps[0].token_in];
+                let calculated_out = self.calculator.calculate_output(path, base.amount);
+                if calculated_out < *min_profit {
+                    return None;
+                }
+                let loan = self.flash_loans.cheapest(path, base.flash_loan_asset, base.amount)?;
+                if calculated_out < *min_profit + loan.premium {
+                    return None;
+                }
+                info!(
+                    "Estimated {}. Calculated {}. {:?} premium {}",
+                    estimated_out, calculated_out, loan.source, loan.premium
+                );
+                let profit = self
+                    .estimator
+                    .eth_value(base.token, calculated_out - base.amount - loan.premium)?;
+                Some((path, calculated_out, loan, profit))
+            })
+            .take(top_k)
+            .collect();
+        verified.sort_by(|a, b| b.3.cmp(&a.3));
+
+        let mut used_pools: HashSet<Address> = HashSet::new();
+        let mut selected = Vec::new();
+        for (path, calculated_out, loan, _) in verified {
+            if path.steps.iter().any(|step| used_pools.contains(&step.pool_address)) {
+                continue;
+            }
+            used_pools.extend(path.steps.iter().map(|step| step.pool_address));
+            selected.push((path.clone(), calculated_out, loan));
         }
+        selected
     }
 
     pub fn search_paths(&mut self, paths_tx: Sender<Event>, address_rx: Receiver<Event>) {
         let _sim: bool = std::env::var("SIM").unwrap().parse().unwrap();
 
         // wait for a new single with the pools that have reserved updated
-        while let Ok(Event::PoolsTouched(pools, block_number)) = address_rx.recv() {
+        while let Ok(event) = address_rx.recv() {
+            let (pools, block_number) = match event {
+                Event::PoolsTouched(pools, block_number) => (pools, block_number),
+                Event::PoolsAdded(pools, _) => {
+                    for pool in pools {
+                        self.add_pool(pool);
+                    }
+                    info!("Searching over {}", self.strategy.size());
+                    continue;
+                }
+                Event::PoolsRemoved(pools, _) => {
+                    self.remove_pools(&pools);
+                    info!("Searching over {}", self.strategy.size());
+                    continue;
+                }
+                _ => break,
+            };
             info!("Searching for arbs in block {}...", block_number);
             let res = Instant::now();
 
+            // sync the index with the pools that were quarantined or released
+            let (quarantined, released) = self.divergence.refresh(block_number);
+            for pool in quarantined {
+                self.strategy.exclude_pool(pool);
+            }
+            for pool in released {
+                self.strategy.restore_pool(pool);
+            }
+            if block_number % DIVERGENCE_STATS_INTERVAL == 0 {
+                self.divergence.log_stats();
+            }
+
             // invalidate all updated pools in the cache
             self.calculator.invalidate_cache(&pools);
 
@@ -85,22 +192,20 @@ where
             info!("Updated estimations");
 
             // from the updated pools, get all paths that we want to recheck
-            let affected_paths: HashSet<&SwapPath> = pools
-                .iter()
-                .filter_map(|pool| self.path_index.get(pool))
-                .flatten()
-                .map(|&index| &self.cycles[index])
-                .collect();
+            let affected_paths: Vec<SwapPath> = self.strategy.candidates(&self.estimator, &pools);
             info!("{} touched paths", affected_paths.len());
 
-            // get the output amount and check for profitability
-            let profitable_paths: Vec<(SwapPath, U256)> = affected_paths
+            // get the output amount and check for profitability, profit is converted to eth
+            // so paths from different bases can be ranked against each other
+            let profitable_paths: Vec<(SwapPath, U256, U256)> = affected_paths
                 .par_iter()
                 .filter_map(|path| {
+                    let (base, min_profit) = self.bases.get(&path.steps[0].token_in)?;
                     // estimate if the path is profitable
-                    let output_est = self.estimator.estimate_output_amount(path);
-                    if output_est >= self.min_profit && output_est < U256::from(1e18) {
-                        Some(((*path).clone(), output_est))
+                    let output_est = self.estimator.estimate_output_amount(path, base.amount);
+                    if output_est >= *min_profit && output_est < base.amount * U256::from(1000) {
+                        let profit = self.estimator.eth_value(base.token, output_est - base.amount)?;
+                        Some((path.clone(), output_est, profit))
                     } else {
                         None
                     }
@@ -110,23 +215,33 @@ where
             info!("{:?} elapsed estimating paths", res.elapsed());
             info!("{} estimated profitable paths", profitable_paths.len());
 
-            if !profitable_paths.is_empty() {
-                // get the best estimated quote and confirm that it is actual in profit
-                let best_path = profitable_paths.iter().max_by_key(|(_, amt)| amt).unwrap();
-                let calculated_out = self.calculator.calculate_output(&best_path.0);
-
-                if calculated_out >= self.min_profit {
-                    info!("Estimated {}. Calculated {}", best_path.1, calculated_out);
-                    match paths_tx.send(Event::ArbPath((
-                        best_path.0.clone(),
-                        calculated_out,
-                        block_number,
-                    ))) {
-                        Ok(_) => debug!("Sent path"),
-                        Err(_) => debug!("Failed to send path"),
-                    }
+            // shallow pools are more likely to have moved by the time the arb lands, so the
+            // candidates are verified in order of profit weighted by the worst pool on the path
+            let scores = pool_scores();
+            let mut candidates: Vec<&(SwapPath, U256, U256)> = profitable_paths.iter().collect();
+            candidates.sort_by_cached_key(|(path, _, profit)| std::cmp::Reverse(weighted_profit(path, *profit, &scores)));
+
+            // verify the top candidates and send every one that does not share a pool
+            // with a more profitable path, they can all land in the same block
+            let selected = self.select_paths(&candidates);
+            info!("{} selected paths", selected.len());
+            for (path, calculated_out, loan) in selected {
+                let amount_in = self.bases[&path.steps[0].token_in].0.amount;
+                match paths_tx.send(Event::ArbPath((path, amount_in, calculated_out, block_number, loan))) {
+                    Ok(_) => debug!("Sent path"),
+                    Err(_) => debug!("Failed to send path"),
                 }
             }
         }
     }
 }
+
+// Profit scaled by the lowest quality score of the pools on the path, unscored pools count as 1
+fn weighted_profit(path: &SwapPath, profit: U256, scores: &PoolScores) -> U256 {
+    let quality = path
+        .steps
+        .iter()
+        .map(|step| scores.get(&step.pool_address).copied().unwrap_or(1.0))
+        .fold(1.0, f64::min);
+    profit * U256::from((quality * 1e6) as u64) / U256::from(1_000_000)
+}
@@ -1,6 +1,6 @@
 use alloy::transports::http::{Client, Http};
 use alloy::network::Ethereum;
-use alloy::primitives::U256;
+use alloy::primitives::{Address, U256};
 use alloy::providers::RootProvider;
 use log::{debug, info, warn};
 use std::collections::HashSet;
@@ -9,11 +9,31 @@ use std::sync::Arc;
 use crate::traits::*;
 use crate::types::*;
 use crate::calculation::Calculator;
+use crate::divergence::{DivergenceTracker, QuarantineReason};
 use crate::events::Event;
 use crate::gen_::FlashQuoter;
+use crate::hop_trace::{HopFailure, PathTrace};
 use crate::market_state::MarketState;
 use crate::quoter::Quoter;
-use crate::AMOUNT;
+
+// What a reverted simulation is blamed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Blame {
+    Token(Address),
+    Pool(Address),
+    Path,
+}
+
+// Blame the token or pool the revert came from so other paths through the rest of the path
+// are still simulated. Every path runs through a base token, so a failed transfer of one is
+// put on the pool of the hop instead of shutting the bot down
+fn blame(failure: Option<&HopFailure>, base_tokens: &HashSet<Address>) -> Blame {
+    match failure {
+        Some(HopFailure { token: Some(token), .. }) if !base_tokens.contains(token) => Blame::Token(*token),
+        Some(HopFailure { pool: Some(pool), .. }) => Blame::Pool(*pool),
+        _ => Blame::Path,
+    }
+}
 
 // receive a stream of potential arbitrage paths from the searcher and
 // simulate them against the contract to determine if they are actually viable
@@ -21,27 +41,41 @@ pub async fn simulate_paths(
     tx_sender: Sender<Event>,
     arb_receiver: Receiver<Event>,
     market_state: Arc<MarketState<Http<Client>, Ethereum, RootProvider<Http<Client>>>>,
+    divergence: Arc<DivergenceTracker>,
+    base_tokens: HashSet<Address>,
 ) {
     // if this is just a sim run or not
     let sim: bool = std::env::var("SIM").unwrap_or_else(|_| "false".to_string()).parse().unwrap_or(false);
 
-    // blacklisted paths, some error in swapping that wasn't caught during filter
-    let mut blacklisted_paths: HashSet<u64> = HashSet::new();
+    // calculator to compare our native math against the quotes
+    let calculator = Calculator::new(market_state.clone());
 
     // receive new paths from the searcher
-    while let Ok(Event::ArbPath((arb_path, expected_out, block_number))) = arb_receiver.recv() {
+    while let Ok(Event::ArbPath((arb_path, amount_in, expected_out, block_number, loan))) = arb_receiver.recv() {
         // convert from searcher format into quoter format
-        let mut converted_path: FlashQuoter::SwapParams = arb_path.clone().into();
-        println!("{:?}", converted_path);
+        let mut converted_path: FlashQuoter::SwapParams = match (arb_path.clone(), amount_in).try_into() {
+            Ok(converted_path) => converted_path,
+            Err(e) => {
+                warn!("Can not execute path {}: {}", arb_path.hash, e);
+                continue;
+            }
+        };
+        debug!("Quoting {:?}", converted_path);
 
         // get the quote for the path and handle it appropriately
-        // if we have not blacklisted the path
-        if !blacklisted_paths.contains(&arb_path.hash) {
+        // if the path is not quarantined, some error in swapping that wasn't caught during filter
+        if !divergence.is_path_quarantined(arb_path.hash, block_number)
+            && !divergence.has_quarantined_token(&arb_path, block_number)
+        {
             info!("Simulating a new path...");
             // get an initial quote to see if we can swap
             // get read access to the db so we can quote the path
             match Quoter::quote_path(converted_path.clone(), market_state.clone()) {
                 Ok(quote) => {
+                    // track how far off our native math is for every pool on the path
+                    let calculated = calculator.debug_calculation(&arb_path, amount_in);
+                    divergence.record(&arb_path, &calculated, &quote, block_number);
+
                     // if we are just simulated, compare to the expected amount
                     if sim {
                         if *(quote.last().unwrap()) == expected_out {
@@ -51,12 +85,14 @@ pub async fn simulate_paths(
                                 arb_path.hash
                             );
                         } else {
-                            // get a full debug quote path
-                            let calculator = Calculator::new(market_state.clone());
-                            calculator.debug_calculation(&arb_path);
+                            info!(
+                                "Mismatch.. Calculated {:?}, Quoted {:?}, Path Hash {}",
+                                calculated, quote, arb_path.hash
+                            );
                         }
                     } else {
-                        if *quote.last().unwrap() < U256::from(1e18) {
+                        // outputs far above the input are a broken quote, not an arb
+                        if *quote.last().unwrap() < amount_in * U256::from(1000) {
                             info!(
                                 "Sim successful... Estimated output: {}, Block {}",
                                 expected_out, block_number
@@ -72,13 +108,14 @@ pub async fn simulate_paths(
                                 "Optimized input: {}. Optimized output: {}",
                                 optimized_amounts.0, optimized_amounts.1
                             );
-                            let profit = expected_out - *AMOUNT;
+                            let profit = expected_out.saturating_sub(amount_in + loan.premium);
                             converted_path.amountIn = optimized_amounts.0;
 
                             match tx_sender.send(Event::ValidPath((
                                 converted_path,
                                 profit,
                                 block_number,
+                                loan,
                             ))) {
                                 Ok(_) => debug!("Simulator sent path to Tx Sender"),
                                 Err(_) => warn!("Simulator: failed to send path to tx sender"),
@@ -91,9 +128,76 @@ pub async fn simulate_paths(
                         "Failed to simulate quote {}, {:#?} ",
                         quote_err, arb_path.hash
                     );
-                    blacklisted_paths.insert(arb_path.hash);
+                    let trace = match Quoter::trace_path(converted_path.clone(), market_state.clone()) {
+                        Ok(trace) => trace,
+                        Err(e) => {
+                            warn!("Failed to trace path {}: {}", arb_path.hash, e);
+                            PathTrace::default()
+                        }
+                    };
+                    let calculated = calculator.debug_calculation(&arb_path, amount_in);
+                    for (hop, traced) in trace.hops.iter().enumerate() {
+                        debug!(
+                            "Hop {} {}: calculated {:?} -> {:?}, traced {:?} -> {:?}",
+                            hop,
+                            traced.pool,
+                            calculated.get(hop),
+                            calculated.get(hop + 1),
+                            traced.amount_in,
+                            traced.amount_out
+                        );
+                    }
+
+                    let reason = trace.failure.as_ref().map(|failure| failure.reason.to_string()).unwrap_or_default();
+                    match blame(trace.failure.as_ref(), &base_tokens) {
+                        Blame::Token(token) => {
+                            info!("Transfer of {} reverted: {}", token, reason);
+                            divergence.quarantine_token(token, QuarantineReason::TransferReverted, block_number);
+                        }
+                        Blame::Pool(pool) => {
+                            info!("Pool {} reverted: {}", pool, reason);
+                            divergence.quarantine_pool(pool, QuarantineReason::SimulationReverted, block_number);
+                        }
+                        Blame::Path => {
+                            info!("Path {} reverted outside its pools: {}", arb_path.hash, reason);
+                            divergence.quarantine_path(
+                                arb_path.hash,
+                                QuarantineReason::SimulationReverted,
+                                block_number,
+                            );
+                        }
+                    }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hop_trace::RevertReason;
+    use alloy::primitives::address;
+
+    // A reverting transfer of a base token is put on the pool it was sent to or from
+    #[test]
+    fn test_base_tokens_are_never_blamed() {
+        let weth = address!("4200000000000000000000000000000000000006");
+        let token = address!("0000000000000000000000000000000000002000");
+        let pool = address!("00000000000000000000000000000000000000aa");
+        let base_tokens = HashSet::from([weth]);
+        let failure = |token| HopFailure {
+            hop: Some(0),
+            pool: Some(pool),
+            token,
+            reason: RevertReason::Empty,
+        };
+
+        assert_eq!(blame(Some(&failure(Some(token))), &base_tokens), Blame::Token(token));
+        assert_eq!(blame(Some(&failure(Some(weth))), &base_tokens), Blame::Pool(pool));
+        assert_eq!(blame(Some(&failure(None)), &base_tokens), Blame::Pool(pool));
+        let outside = HopFailure { pool: None, ..failure(Some(weth)) };
+        assert_eq!(blame(Some(&outside), &base_tokens), Blame::Path);
+        assert_eq!(blame(None, &base_tokens), Blame::Path);
+    }
+}
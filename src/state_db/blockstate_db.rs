@@ -24,6 +24,10 @@ use std::collections::HashSet;
 use std::future::IntoFuture;
 use tokio::runtime::{Handle, Runtime};
 
+use crate::state_db::layout::Field;
+use crate::state_db::tick_index::TickIndex;
+use crate::state_db::PoolState;
+use crate::block_env::BlockContext;
 use crate::traits::{IntoRevm, IntoAlloy};
 
 
@@ -55,6 +59,12 @@ pub struct BlockStateDB<T: Transport + Clone, N: Network, P: Provider<N>> {
     pub block_hashes: HashMap<BlockNumber, B256>, // Value is B256
     pub pools: HashSet<Address>,
     pub pool_info: HashMap<Address, Pool>, // Assuming Pool comes from pool_sync or similar
+    // initialized ticks of the v3 pools, only for the bitmap words that are loaded
+    pub tick_indexes: HashMap<Address, TickIndex>,
+    // decoded state of the tracked pools for the native calculators
+    pub pool_states: HashMap<Address, PoolState>,
+    // block the state is at, simulations run in it or the block after
+    pub block: BlockContext,
     provider: P,
     runtime: HandleOrRuntime,
     _marker: std::marker::PhantomData<fn() -> (T, N)>,
@@ -91,6 +101,9 @@ where
             block_hashes: HashMap::new(),
             pools: HashSet::new(),
             pool_info: HashMap::new(),
+            tick_indexes: HashMap::new(),
+            pool_states: HashMap::new(),
+            block: BlockContext::default(),
             provider,
             runtime: rt,
             _marker: std::marker::PhantomData,
@@ -133,6 +146,81 @@ where
         }
     }
 
+    // Stop tracking a pool, its accounts stay cached but are no longer updated
+    pub fn remove_pool(&mut self, pool_address: &Address) {
+        trace!("Removing pool {} from database", pool_address);
+        self.pools.remove(pool_address);
+        self.pool_info.remove(pool_address);
+        self.tick_indexes.remove(pool_address);
+        self.pool_states.remove(pool_address);
+    }
+
+    // An empty db over the same provider at the same block. Pools are built in it without
+    // holding the lock of this one and merged in once they are ready
+    pub fn scratch(&self) -> Option<Self>
+    where
+        P: Clone,
+    {
+        let mut db = Self::new(self.provider.clone())?;
+        db.block = self.block;
+        Some(db)
+    }
+
+    // Move the pools of a scratch db in. Their accounts are taken as they are, the other
+    // accounts it loaded only add the slots this db does not have yet
+    pub fn merge(&mut self, scratch: Self) {
+        for (address, account) in scratch.accounts {
+            if scratch.pools.contains(&address) {
+                self.accounts.insert(address, account);
+                continue;
+            }
+            match self.accounts.get_mut(&address) {
+                Some(existing) => {
+                    for (slot, value) in account.storage {
+                        existing.storage.entry(slot).or_insert(value);
+                    }
+                }
+                None => {
+                    self.accounts.insert(address, account);
+                }
+            }
+        }
+        for (hash, code) in scratch.contracts {
+            self.contracts.entry(hash).or_insert(code);
+        }
+        for (number, hash) in scratch.block_hashes {
+            self.block_hashes.entry(number).or_insert(hash);
+        }
+        self.pools.extend(scratch.pools);
+        self.pool_info.extend(scratch.pool_info);
+        self.tick_indexes.extend(scratch.tick_indexes);
+        self.pool_states.extend(scratch.pool_states);
+    }
+
+    // Read every cached slot of a pool again at the latest block. Pools are inserted from the
+    // synced state, which is behind the chain by the time a refresh adds them
+    pub fn resync_pool(&mut self, pool: Address) -> Result<()> {
+        let Some(account) = self.accounts.get_mut(&pool) else {
+            return Ok(());
+        };
+        let slots: Vec<U256> = account.storage.keys().copied().collect();
+        account.storage.clear();
+        for slot in slots {
+            let value = self.storage_ref(pool, slot)?;
+            self.insert_account_storage(pool, slot, value, InsertionType::Custom)?;
+        }
+
+        // ticks may have been crossed or initialized since the sync, index the words again
+        if let Some(index) = self.tick_indexes.insert(pool, TickIndex::default()) {
+            for word in index.words() {
+                self.load_tick_word(pool, word)?;
+            }
+            self.cover_current_words(pool)?;
+        }
+        self.refresh_pool_state(pool);
+        Ok(())
+    }
+
     pub fn get_pool(&self, pool_address: &Address) -> &Pool {
         self.pool_info.get(pool_address).unwrap()
     }
@@ -149,6 +237,29 @@ where
             .map(|info| info.token0_address() == token_in) // Assuming PoolInfo has token0_address
     }
 
+    // Read a packed value from the storage of an account
+    #[inline]
+    pub(crate) fn read_field(&self, address: &Address, field: Field) -> Result<U256> {
+        let word = self.storage_ref(*address, field.slot)?;
+        Ok(field.read(word))
+    }
+
+    // Write a packed value into the storage of a tracked account, keeping the values it is
+    // packed with if the word is already cached
+    pub(crate) fn write_field(&mut self, address: Address, field: Field, value: U256) {
+        let account = self.accounts.get_mut(&address).unwrap();
+        let word = account
+            .storage
+            .get(&field.slot)
+            .map(|slot| slot.value)
+            .unwrap_or_default();
+        let new_db_slot = BlockStateDBSlot {
+            value: field.write(word, value),
+            insertion_type: InsertionType::Custom,
+        };
+        account.storage.insert(field.slot, new_db_slot);
+    }
+
     #[inline]
     pub fn update_all_slots(
         &mut self,
@@ -159,8 +270,10 @@ where
             "Update all slots: updating all storage slots for adddress {}",
             address
         );
+        let mut written = Vec::new();
         if let Some(alloy_storage) = account_state.storage { // storage is Option<HashMap<B256, B256>>
             for (slot_b256, value_b256) in alloy_storage {
+                written.push(U256::from_be_bytes(slot_b256.0));
                 if let Some(account) = self.accounts.get_mut(&address) {
                     let new_slot_val = BlockStateDBSlot {
                         value: U256::from_be_bytes(value_b256.0), // Convert B256 to U256
@@ -170,6 +283,21 @@ where
                 }
             }
         }
+
+        if self.tracking_pool(&address) {
+            self.refresh_pool_state(address);
+        }
+
+        // keep the tick index in line with the slots, the price may also have moved out of
+        // the words we know
+        if self.tick_indexes.contains_key(&address) {
+            if let Err(e) = self.patch_tick_index(address, &written) {
+                warn!("Failed to patch the tick index of {address}: {e:?}");
+            }
+            if let Err(e) = self.cover_current_words(address) {
+                warn!("Failed to load tick words around the price of {address}: {e:?}");
+            }
+        }
         Ok(())
     }
 
@@ -444,8 +572,12 @@ impl<T: Transport + Clone, N: Network, P: Provider<N>> DatabaseRef for BlockStat
 
 impl<T: Transport + Clone, N: Network, P: Provider<N>> DatabaseCommit for BlockStateDB<T, N, P> {
     fn commit(&mut self, changes: HashMap<Address, Account>) { // revm types
+        let mut touched_pools = Vec::new();
         for (revm_address, mut revm_account) in changes {
             let alloy_address = revm_address.into_alloy();
+            if self.pools.contains(&alloy_address) {
+                touched_pools.push(alloy_address);
+            }
 
             if !revm_account.is_touched() && !revm_account.is_created() { // More robust check
                 continue;
@@ -498,6 +630,10 @@ impl<T: Transport + Clone, N: Network, P: Provider<N>> DatabaseCommit for BlockS
                 );
             }
         }
+
+        for pool in touched_pools {
+            self.refresh_pool_state(pool);
+        }
     }
 }
 
@@ -544,4 +680,51 @@ impl Default for BlockStateDBAccount {
             insertion_type: InsertionType::OnChain, // Or Custom as a sensible default
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::address;
+    use alloy::providers::ProviderBuilder;
+    use pool_sync::UniswapV2Pool;
+
+    // Merging a scratch db takes its pools as they are and only adds the slots of the other
+    // accounts that the live db does not have
+    #[test]
+    fn test_merge_scratch_pools() {
+        let pool_address = address!("00000000000000000000000000000000000000a1");
+        let token = address!("00000000000000000000000000000000000000b1");
+        let provider = ProviderBuilder::new().on_http("http://127.0.0.1:1".parse().unwrap());
+        let mut live = BlockStateDB::new(provider).unwrap();
+        live.block.number = 7;
+        live.insert_account_info(token, AccountInfo::default(), InsertionType::Custom);
+        live.insert_account_storage(token, U256::from(1), U256::from(1), InsertionType::Custom).unwrap();
+
+        let mut scratch = live.scratch().unwrap();
+        assert_eq!(scratch.block.number, 7);
+        scratch.insert_account_info(token, AccountInfo::default(), InsertionType::Custom);
+        scratch.insert_account_storage(token, U256::from(1), U256::from(2), InsertionType::Custom).unwrap();
+        scratch.insert_account_storage(token, U256::from(2), U256::from(3), InsertionType::Custom).unwrap();
+        scratch.insert_account_info(pool_address, AccountInfo::default(), InsertionType::Custom);
+        scratch.insert_v2(Pool::UniswapV2(UniswapV2Pool {
+            address: pool_address,
+            token0: token,
+            token1: address!("00000000000000000000000000000000000000b2"),
+            token0_name: String::new(),
+            token1_name: String::new(),
+            token0_decimals: 18,
+            token1_decimals: 18,
+            token0_reserves: U256::from(1000),
+            token1_reserves: U256::from(2000),
+            stable: None,
+            fee: None,
+        }));
+
+        live.merge(scratch);
+        assert!(live.tracking_pool(&pool_address));
+        assert_eq!(live.get_reserves(&pool_address), (U256::from(1000), U256::from(2000)));
+        assert!(live.pool_states.contains_key(&pool_address));
+        assert_eq!(live.storage_ref(token, U256::from(1)).unwrap(), U256::from(1));
+        assert_eq!(live.storage_ref(token, U256::from(2)).unwrap(), U256::from(3));
+    }
+}
@@ -0,0 +1,412 @@
+use alloy::primitives::{keccak256, Address, I256, U256};
+use anyhow::{anyhow, Result};
+use pool_sync::PoolType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Where the pool state lives in storage for each fork. The insert and read helpers in the db
+// go through these so a fork with a different layout is just another entry in the registry.
+// Every entry should have a recorded fixture in fixtures/layouts, record them with
+// `cargo test record_layout_fixtures -- --ignored`
+
+// A value packed into a storage word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub slot: U256,
+    pub offset: usize,
+    pub bits: usize,
+}
+
+impl Field {
+    const fn new(slot: u64, offset: usize, bits: usize) -> Self {
+        Self {
+            slot: U256::from_limbs([slot, 0, 0, 0]),
+            offset,
+            bits,
+        }
+    }
+
+    // The same field in a mapping entry
+    #[inline]
+    pub fn at(&self, slot: U256) -> Self {
+        Self { slot, ..*self }
+    }
+
+    #[inline]
+    fn mask(&self) -> U256 {
+        if self.bits == 256 {
+            U256::MAX
+        } else {
+            (U256::from(1) << self.bits) - U256::from(1)
+        }
+    }
+
+    // Extract the value from the storage word
+    #[inline]
+    pub fn read(&self, word: U256) -> U256 {
+        (word >> self.offset) & self.mask()
+    }
+
+    // Write the value into the storage word, keeping the values it is packed with
+    #[inline]
+    pub fn write(&self, word: U256, value: U256) -> U256 {
+        (word & !(self.mask() << self.offset)) | ((value & self.mask()) << self.offset)
+    }
+}
+
+// Slot of a mapping entry keyed by a signed integer, ticks and tick bitmap words
+#[inline]
+pub fn mapping_slot(key: i32, base: u64) -> Result<U256> {
+    let mut buf = I256::try_from(key)?.to_be_bytes::<32>().to_vec();
+    buf.extend_from_slice(&U256::from(base).to_be_bytes::<32>());
+    Ok(keccak256(buf.as_slice()).into())
+}
+
+// Layout of a uniswap v2 style pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct V2Layout {
+    pub token0: Field,
+    pub token1: Field,
+    pub reserve0: Field,
+    pub reserve1: Field,
+}
+
+// Layout of a uniswap v3 style pool. The tick spacing is not here, it is an immutable on most
+// forks and comes from the synced pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct V3Layout {
+    pub sqrt_price: Field,
+    pub tick: Field,
+    pub observation_index: Field,
+    pub observation_cardinality: Field,
+    pub observation_cardinality_next: Field,
+    pub fee_protocol: Option<Field>,
+    pub unlocked: Field,
+    pub liquidity: Field,
+    // base slots of the ticks and tickBitmap mappings
+    pub ticks: u64,
+    pub tick_bitmap: u64,
+    // liquidityNet within the first word of a Tick.Info
+    pub liquidity_net: Field,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageLayout {
+    V2(V2Layout),
+    V3(V3Layout),
+}
+
+// UniswapV2Pair, the erc20 takes slots 0-4 and factory is 5
+pub const UNISWAP_V2: V2Layout = V2Layout {
+    token0: Field::new(6, 0, 160),
+    token1: Field::new(7, 0, 160),
+    reserve0: Field::new(8, 0, 112),
+    reserve1: Field::new(8, 112, 112),
+};
+
+// Aerodrome Pool, oz ERC20Permit and ReentrancyGuard take slots 0-7, then the name, symbol and
+// voter with stable packed in. The reserves are full words
+const AERODROME: V2Layout = V2Layout {
+    token0: Field::new(11, 0, 160),
+    token1: Field::new(12, 0, 160),
+    reserve0: Field::new(18, 0, 256),
+    reserve1: Field::new(19, 0, 256),
+};
+
+// UniswapV3Pool, slot0 is one word
+pub const UNISWAP_V3: V3Layout = V3Layout {
+    sqrt_price: Field::new(0, 0, 160),
+    tick: Field::new(0, 160, 24),
+    observation_index: Field::new(0, 184, 16),
+    observation_cardinality: Field::new(0, 200, 16),
+    observation_cardinality_next: Field::new(0, 216, 16),
+    fee_protocol: Some(Field::new(0, 232, 8)),
+    unlocked: Field::new(0, 240, 8),
+    liquidity: Field::new(4, 0, 128),
+    ticks: 5,
+    tick_bitmap: 6,
+    liquidity_net: Field::new(0, 128, 128),
+};
+
+// PancakeV3Pool, feeProtocol is a uint32 so it and unlocked spill into a second word and
+// everything after slot0 moves down one
+const PANCAKE_V3: V3Layout = V3Layout {
+    fee_protocol: Some(Field::new(1, 0, 32)),
+    unlocked: Field::new(1, 32, 8),
+    liquidity: Field::new(5, 0, 128),
+    ticks: 6,
+    tick_bitmap: 7,
+    ..UNISWAP_V3
+};
+
+// Slipstream CLPool, a clone so the factory, tokens, gauge, nft, tick spacing and max liquidity
+// per tick are storage in front of slot0. There is no feeProtocol and the gauge accounting
+// sits between slot0 and liquidity
+const SLIPSTREAM: V3Layout = V3Layout {
+    sqrt_price: Field::new(6, 0, 160),
+    tick: Field::new(6, 160, 24),
+    observation_index: Field::new(6, 184, 16),
+    observation_cardinality: Field::new(6, 200, 16),
+    observation_cardinality_next: Field::new(6, 216, 16),
+    fee_protocol: None,
+    unlocked: Field::new(6, 232, 8),
+    liquidity: Field::new(15, 0, 128),
+    ticks: 16,
+    tick_bitmap: 17,
+    liquidity_net: Field::new(0, 128, 128),
+};
+
+// Look up the storage layout of a protocol
+pub fn storage_layout(protocol: PoolType) -> Result<StorageLayout> {
+    let layout = match protocol {
+        PoolType::UniswapV2
+        | PoolType::SushiSwapV2
+        | PoolType::PancakeSwapV2
+        | PoolType::BaseSwapV2
+        | PoolType::DackieSwapV2
+        | PoolType::AlienBaseV2
+        | PoolType::SwapBasedV2 => StorageLayout::V2(UNISWAP_V2),
+        PoolType::Aerodrome => StorageLayout::V2(AERODROME),
+        PoolType::UniswapV3
+        | PoolType::SushiSwapV3
+        | PoolType::BaseSwapV3
+        | PoolType::AlienBaseV3
+        | PoolType::SwapBasedV3 => StorageLayout::V3(UNISWAP_V3),
+        PoolType::PancakeSwapV3 | PoolType::DackieSwapV3 => StorageLayout::V3(PANCAKE_V3),
+        PoolType::Slipstream => StorageLayout::V3(SLIPSTREAM),
+        other => return Err(anyhow!("No storage layout for {other:?}")),
+    };
+    Ok(layout)
+}
+
+pub fn v2_layout(protocol: PoolType) -> Result<V2Layout> {
+    match storage_layout(protocol)? {
+        StorageLayout::V2(layout) => Ok(layout),
+        StorageLayout::V3(_) => Err(anyhow!("{protocol:?} is not a v2 pool")),
+    }
+}
+
+pub fn v3_layout(protocol: PoolType) -> Result<V3Layout> {
+    match storage_layout(protocol)? {
+        StorageLayout::V3(layout) => Ok(layout),
+        StorageLayout::V2(_) => Err(anyhow!("{protocol:?} is not a v3 pool")),
+    }
+}
+
+// Storage of a live pool next to what its view functions returned at the same block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayoutFixture {
+    pub block_number: u64,
+    pub pool_type: PoolType,
+    pub address: Address,
+    pub storage: BTreeMap<U256, U256>,
+    pub expected: LayoutValues,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LayoutValues {
+    V2 {
+        token0: Address,
+        token1: Address,
+        reserve0: U256,
+        reserve1: U256,
+    },
+    V3 {
+        sqrt_price: U256,
+        tick: i32,
+        liquidity: u128,
+        // an initialized tick, its liquidityNet and the bitmap word it is in
+        tick_index: i32,
+        liquidity_net: i128,
+        bitmap: U256,
+    },
+}
+
+// Decode the recorded storage with the registered layout, this has to match the views
+pub fn decode_fixture(fixture: &LayoutFixture) -> Result<LayoutValues> {
+    let read = |field: Field| field.read(fixture.storage.get(&field.slot).copied().unwrap_or_default());
+    let values = match (storage_layout(fixture.pool_type)?, &fixture.expected) {
+        (StorageLayout::V2(layout), LayoutValues::V2 { .. }) => LayoutValues::V2 {
+            token0: Address::from_word(read(layout.token0).into()),
+            token1: Address::from_word(read(layout.token1).into()),
+            reserve0: read(layout.reserve0),
+            reserve1: read(layout.reserve1),
+        },
+        (StorageLayout::V3(layout), LayoutValues::V3 { tick_index, .. }) => {
+            let tick_slot = mapping_slot(*tick_index, layout.ticks)?;
+            let bitmap_slot = mapping_slot(tick_index >> 8, layout.tick_bitmap)?;
+            LayoutValues::V3 {
+                sqrt_price: read(layout.sqrt_price),
+                tick: decode_tick(read(layout.tick)),
+                liquidity: read(layout.liquidity).saturating_to(),
+                tick_index: *tick_index,
+                liquidity_net: read(layout.liquidity_net.at(tick_slot)).saturating_to::<u128>() as i128,
+                bitmap: read(Field::new(0, 0, 256).at(bitmap_slot)),
+            }
+        }
+        _ => return Err(anyhow!("Fixture does not match the {:?} layout", fixture.pool_type)),
+    };
+    Ok(values)
+}
+
+// Sign extend a raw int24
+#[inline]
+pub fn decode_tick(raw: U256) -> i32 {
+    let raw: u32 = raw.saturating_to();
+    ((raw << 8) as i32) >> 8
+}
+
+#[cfg(test)]
+mod layout_tests {
+    use super::*;
+    use crate::fixtures::record_layout_fixture;
+    use crate::state_db::BlockStateDB;
+    use alloy::providers::ProviderBuilder;
+    use pool_sync::{Chain, Pool, PoolInfo, PoolSync};
+    use std::fs::{create_dir_all, File};
+    use std::io::{BufReader, BufWriter};
+    use std::path::Path;
+
+    const LAYOUT_FIXTURE_DIR: &str = "fixtures/layouts";
+
+    const ALL_TYPES: [PoolType; 16] = [
+        PoolType::UniswapV2,
+        PoolType::SushiSwapV2,
+        PoolType::PancakeSwapV2,
+        PoolType::BaseSwapV2,
+        PoolType::DackieSwapV2,
+        PoolType::AlienBaseV2,
+        PoolType::SwapBasedV2,
+        PoolType::Aerodrome,
+        PoolType::UniswapV3,
+        PoolType::SushiSwapV3,
+        PoolType::BaseSwapV3,
+        PoolType::AlienBaseV3,
+        PoolType::SwapBasedV3,
+        PoolType::PancakeSwapV3,
+        PoolType::DackieSwapV3,
+        PoolType::Slipstream,
+    ];
+
+    fn fields(layout: &StorageLayout) -> Vec<Field> {
+        match layout {
+            StorageLayout::V2(l) => vec![l.token0, l.token1, l.reserve0, l.reserve1],
+            StorageLayout::V3(l) => {
+                let mut fields = vec![
+                    l.sqrt_price,
+                    l.tick,
+                    l.observation_index,
+                    l.observation_cardinality,
+                    l.observation_cardinality_next,
+                    l.unlocked,
+                    l.liquidity,
+                ];
+                fields.extend(l.fee_protocol);
+                fields
+            }
+        }
+    }
+
+    // Fields that share a word must not overlap and every field has to round trip
+    #[test]
+    fn test_layouts_are_consistent() {
+        for pool_type in ALL_TYPES {
+            let layout = storage_layout(pool_type).unwrap();
+            let fields = fields(&layout);
+            for (i, a) in fields.iter().enumerate() {
+                assert!(a.offset + a.bits <= 256, "{pool_type:?} {a:?} overflows its word");
+                for b in &fields[i + 1..] {
+                    let disjoint = a.slot != b.slot || a.offset + a.bits <= b.offset || b.offset + b.bits <= a.offset;
+                    assert!(disjoint, "{pool_type:?} {a:?} overlaps {b:?}");
+                }
+            }
+
+            let mut words: BTreeMap<U256, U256> = BTreeMap::new();
+            for (i, field) in fields.iter().enumerate() {
+                let value = U256::from(i as u64 + 1);
+                let word = words.entry(field.slot).or_default();
+                *word = field.write(*word, value);
+            }
+            for (i, field) in fields.iter().enumerate() {
+                assert_eq!(field.read(words[&field.slot]), U256::from(i as u64 + 1), "{pool_type:?} {field:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_negative_tick() {
+        let tick = Field::new(0, 160, 24);
+        let word = tick.write(U256::MAX, U256::from((-887272i32) as u32));
+        assert_eq!(decode_tick(tick.read(word)), -887272);
+    }
+
+    // Every recorded pool has to decode to what its views returned, and every layout needs a
+    // recorded pool. Ignored until the fixtures are recorded into LAYOUT_FIXTURE_DIR with
+    // record_layout_fixtures, run it with `-- --ignored` once they are
+    #[test]
+    #[ignore]
+    fn test_layouts_match_fixtures() {
+        assert!(
+            Path::new(LAYOUT_FIXTURE_DIR).exists(),
+            "No fixtures in {LAYOUT_FIXTURE_DIR}, record them with `cargo test record_layout_fixtures -- --ignored`"
+        );
+        let mut paths: Vec<_> = std::fs::read_dir(LAYOUT_FIXTURE_DIR)
+            .unwrap()
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().map(|ext| ext == "json").unwrap_or(false))
+            .collect();
+        paths.sort();
+
+        let mut mismatches = Vec::new();
+        let mut recorded: Vec<PoolType> = Vec::new();
+        for path in paths {
+            let fixture: LayoutFixture = serde_json::from_reader(BufReader::new(File::open(&path).unwrap())).unwrap();
+            recorded.push(fixture.pool_type);
+            let decoded = decode_fixture(&fixture).unwrap();
+            if decoded != fixture.expected {
+                mismatches.push(format!(
+                    "{:?} {}: views {:?}, layout {:?}",
+                    fixture.pool_type, fixture.address, fixture.expected, decoded
+                ));
+            }
+        }
+        assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+
+        let missing: Vec<PoolType> = ALL_TYPES.into_iter().filter(|pool_type| !recorded.contains(pool_type)).collect();
+        assert!(missing.is_empty(), "No recorded pool for {missing:?}");
+    }
+
+    // Record the first synced pool of every fork with a registered layout from a live node
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn record_layout_fixtures() {
+        dotenv::dotenv().ok();
+        let pool_sync = PoolSync::builder()
+            .add_pools(&ALL_TYPES)
+            .chain(Chain::Base)
+            .build()
+            .unwrap();
+        let (pools, last_synced_block) = pool_sync.sync_pools().await.unwrap();
+
+        create_dir_all(LAYOUT_FIXTURE_DIR).unwrap();
+        let url = std::env::var("FULL").unwrap().parse().unwrap();
+        for pool_type in ALL_TYPES {
+            let Some(pool) = pools.iter().find(|pool: &&Pool| {
+                pool.pool_type() == pool_type && pool.get_v3().map_or(true, |v3| !v3.ticks.is_empty())
+            }) else {
+                println!("No synced {pool_type:?} pool");
+                continue;
+            };
+            let provider = ProviderBuilder::new().on_http(url.clone());
+            let mut db = BlockStateDB::new(provider).unwrap();
+            match record_layout_fixture(&mut db, pool, last_synced_block) {
+                Ok(fixture) => {
+                    let filename = format!("{}/{:?}_{}.json", LAYOUT_FIXTURE_DIR, pool_type, pool.address());
+                    let writer = BufWriter::new(File::create(filename).unwrap());
+                    serde_json::to_writer_pretty(writer, &fixture).unwrap();
+                }
+                Err(e) => println!("Failed to record {}: {}", pool.address(), e),
+            }
+        }
+    }
+}
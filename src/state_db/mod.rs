@@ -1,4 +1,9 @@
 pub use blockstate_db::{BlockStateDB, InsertionType};
+pub use pool_state::PoolState;
+pub use tick_index::{InsufficientTickData, TickIndex};
 mod blockstate_db;
+pub mod layout;
+mod pool_state;
+mod tick_index;
 mod v2_db;
 mod v3_db;
@@ -0,0 +1,159 @@
+use super::BlockStateDB;
+use alloy::network::Network;
+use alloy::primitives::{Address, U256};
+use alloy::providers::Provider;
+use alloy::transports::Transport;
+use anyhow::{anyhow, Result};
+use log::warn;
+use pool_sync::{Pool, PoolInfo, PoolType};
+
+// Decoded state of a pool for the native calculators. revm keeps reading the raw slots, this is
+// rebuilt from them whenever the slots of the pool change so the math does not unpack words
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolState {
+    V2 {
+        reserve0: U256,
+        reserve1: U256,
+    },
+    Aerodrome {
+        reserve0: U256,
+        reserve1: U256,
+        // out of 10000
+        fee: U256,
+        decimals0: u8,
+        decimals1: u8,
+        stable: bool,
+        token0: Address,
+    },
+    V3 {
+        sqrt_price: U256,
+        tick: i32,
+        liquidity: u128,
+    },
+}
+
+impl<T, N, P> BlockStateDB<T, N, P>
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<N>,
+{
+    // Typed state of a tracked pool. Debug builds check it against the raw slots revm reads
+    #[inline]
+    pub fn pool_state(&self, pool: &Address) -> Result<&PoolState> {
+        let state = self
+            .pool_states
+            .get(pool)
+            .ok_or_else(|| anyhow!("No state for pool {pool}"))?;
+        debug_assert_eq!(
+            Some(state),
+            self.pool_info
+                .get(pool)
+                .and_then(|info| self.decode_pool_state(info).ok().flatten())
+                .as_ref(),
+            "Typed state of {pool} does not match its slots"
+        );
+        Ok(state)
+    }
+
+    // Rebuild the typed state of a tracked pool after its slots were written
+    pub(crate) fn refresh_pool_state(&mut self, pool: Address) {
+        let Some(info) = self.pool_info.get(&pool) else {
+            return;
+        };
+        match self.decode_pool_state(info) {
+            Ok(Some(state)) => {
+                self.pool_states.insert(pool, state);
+            }
+            // the calculators of the protocol read the db themselves
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Failed to decode the state of {pool}: {e:?}");
+                self.pool_states.remove(&pool);
+            }
+        }
+    }
+
+    fn decode_pool_state(&self, pool: &Pool) -> Result<Option<PoolState>> {
+        let address = pool.address();
+        if pool.pool_type() == PoolType::Aerodrome {
+            let v2 = pool.get_v2().ok_or_else(|| anyhow!("{address} is not a v2 pool"))?;
+            let (reserve0, reserve1) = self.get_reserves(&address);
+            return Ok(Some(PoolState::Aerodrome {
+                reserve0,
+                reserve1,
+                fee: v2.fee.unwrap_or_default(),
+                decimals0: v2.token0_decimals,
+                decimals1: v2.token1_decimals,
+                stable: v2.stable.unwrap_or(false),
+                token0: pool.token0_address(),
+            }));
+        }
+        if pool.is_v2() {
+            let (reserve0, reserve1) = self.get_reserves(&address);
+            return Ok(Some(PoolState::V2 { reserve0, reserve1 }));
+        }
+        if pool.is_v3() {
+            let slot0 = self.slot0(address)?;
+            return Ok(Some(PoolState::V3 {
+                sqrt_price: slot0.sqrtPriceX96.to(),
+                tick: slot0.tick.as_i32(),
+                liquidity: self.liquidity(address)?,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_db::InsertionType;
+    use alloy::primitives::{address, B256};
+    use alloy::providers::ProviderBuilder;
+    use alloy::rpc::types::trace::geth::AccountState;
+    use alloy::rpc::types::AccountInfo;
+    use pool_sync::UniswapV2Pool;
+
+    // A trace that rewrites the reserve word has to show up in the typed state exactly as the
+    // slot reads decode it
+    #[test]
+    fn test_typed_state_follows_slots() {
+        let pool_address = address!("00000000000000000000000000000000000000a1");
+        let provider = ProviderBuilder::new().on_http("http://127.0.0.1:1".parse().unwrap());
+        let mut db = BlockStateDB::new(provider).unwrap();
+        db.insert_account_info(pool_address, AccountInfo::default(), InsertionType::Custom);
+        db.insert_v2(Pool::UniswapV2(UniswapV2Pool {
+            address: pool_address,
+            token0: address!("00000000000000000000000000000000000000b1"),
+            token1: address!("00000000000000000000000000000000000000b2"),
+            token0_name: String::new(),
+            token1_name: String::new(),
+            token0_decimals: 18,
+            token1_decimals: 6,
+            token0_reserves: U256::from(1000),
+            token1_reserves: U256::from(2000),
+            stable: None,
+            fee: None,
+        }));
+        assert_eq!(
+            db.pool_state(&pool_address).unwrap(),
+            &PoolState::V2 {
+                reserve0: U256::from(1000),
+                reserve1: U256::from(2000),
+            }
+        );
+
+        // reserve0 in the low 112 bits, reserve1 above it and the timestamp on top
+        let word = U256::from(7) | (U256::from(9) << 112) | (U256::from(0xabcdef) << 224);
+        let state = AccountState {
+            storage: Some([(B256::from(U256::from(8)), B256::from(word))].into_iter().collect()),
+            ..Default::default()
+        };
+        db.update_all_slots(pool_address, state).unwrap();
+
+        let (reserve0, reserve1) = db.get_reserves(&pool_address);
+        assert_eq!((reserve0, reserve1), (U256::from(7), U256::from(9)));
+        assert_eq!(db.pool_state(&pool_address).unwrap(), &PoolState::V2 { reserve0, reserve1 });
+    }
+}
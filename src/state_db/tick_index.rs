@@ -0,0 +1,198 @@
+use alloy::primitives::{Address, U256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+// A swap walked into a bitmap word of the pool that is not known locally. Reading it as empty
+// would overstate the output, the word has to be loaded with `load_tick_word` first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InsufficientTickData {
+    pub pool: Address,
+    pub word: i16,
+}
+
+impl fmt::Display for InsufficientTickData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Tick bitmap word {} of {} is not loaded", self.word, self.pool)
+    }
+}
+
+impl std::error::Error for InsufficientTickData {}
+
+// An initialized tick and the storage slot its liquidityNet lives in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexedTick {
+    pub tick: i32,
+    pub liquidity_net: i128,
+    pub slot: U256,
+}
+
+// Initialized ticks of a v3 pool sorted by tick, so a swap can walk them without going through
+// storage. Only the bitmap words in `words` are indexed, the slots map trace diffs back to the
+// words they change
+#[derive(Debug, Clone, Default)]
+pub struct TickIndex {
+    ticks: Vec<(i32, i128)>,
+    words: HashSet<i16>,
+    word_slots: HashMap<U256, i16>,
+    tick_slots: HashMap<U256, i32>,
+}
+
+// Bitmap word a tick is in, rounding towards negative infinity like the pool does
+#[inline]
+pub fn word_of(tick: i32, tick_spacing: i32) -> i16 {
+    (tick.div_euclid(tick_spacing) >> 8) as i16
+}
+
+impl TickIndex {
+    #[inline]
+    pub fn covers(&self, word: i16) -> bool {
+        self.words.contains(&word)
+    }
+
+    // The bitmap words that are indexed
+    pub fn words(&self) -> Vec<i16> {
+        self.words.iter().copied().collect()
+    }
+
+    // liquidityNet of the tick, zero if it is not initialized
+    #[inline]
+    pub fn liquidity_net(&self, tick: i32) -> i128 {
+        match self.ticks.binary_search_by_key(&tick, |(tick, _)| *tick) {
+            Ok(i) => self.ticks[i].1,
+            Err(_) => 0,
+        }
+    }
+
+    // Word a written storage slot belongs to, if it is a bitmap word or tick of the index
+    pub fn word_of_slot(&self, slot: &U256, tick_spacing: i32) -> Option<i16> {
+        self.word_slots
+            .get(slot)
+            .copied()
+            .or_else(|| self.tick_slots.get(slot).map(|tick| word_of(*tick, tick_spacing)))
+    }
+
+    // Replace the initialized ticks of a word
+    pub fn set_word(&mut self, word: i16, word_slot: U256, tick_spacing: i32, mut ticks: Vec<IndexedTick>) {
+        let (low, high) = word_bounds(word, tick_spacing);
+        let start = self.ticks.partition_point(|(tick, _)| *tick < low);
+        let end = self.ticks.partition_point(|(tick, _)| *tick <= high);
+        self.tick_slots.retain(|_, tick| *tick < low || *tick > high);
+
+        ticks.sort_by_key(|tick| tick.tick);
+        self.tick_slots.extend(ticks.iter().map(|tick| (tick.slot, tick.tick)));
+        self.ticks
+            .splice(start..end, ticks.iter().map(|tick| (tick.tick, tick.liquidity_net)));
+        self.word_slots.insert(word_slot, word);
+        self.words.insert(word);
+    }
+
+    // Same result as `next_initialized_tick_within_one_word` over the pool bitmap. The search
+    // stops at the end of the word so the swap is split into the same steps as on chain
+    pub fn next_initialized_tick_within_one_word(
+        &self,
+        pool: Address,
+        tick: i32,
+        tick_spacing: i32,
+        lte: bool,
+    ) -> Result<(i32, bool), InsufficientTickData> {
+        let compressed = tick.div_euclid(tick_spacing);
+        let word = if lte {
+            (compressed >> 8) as i16
+        } else {
+            ((compressed + 1) >> 8) as i16
+        };
+        if !self.covers(word) {
+            return Err(InsufficientTickData { pool, word });
+        }
+
+        let (low, high) = word_bounds(word, tick_spacing);
+        if lte {
+            // last initialized tick at or below the current one
+            let end = self.ticks.partition_point(|(t, _)| *t <= compressed * tick_spacing);
+            match end.checked_sub(1).map(|i| self.ticks[i].0) {
+                Some(next) if next >= low => Ok((next, true)),
+                _ => Ok((low, false)),
+            }
+        } else {
+            // first initialized tick above the current one
+            let start = self.ticks.partition_point(|(t, _)| *t <= compressed * tick_spacing);
+            match self.ticks.get(start).map(|(t, _)| *t) {
+                Some(next) if next <= high => Ok((next, true)),
+                _ => Ok((high, false)),
+            }
+        }
+    }
+}
+
+// First and last tick a bitmap word can hold
+#[inline]
+fn word_bounds(word: i16, tick_spacing: i32) -> (i32, i32) {
+    let first = (word as i32) * 256;
+    (first * tick_spacing, (first + 255) * tick_spacing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uniswap_v3_math::tick_bitmap::next_initialized_tick_within_one_word;
+
+    fn index(ticks: &[i32], words: &[i16], tick_spacing: i32) -> (TickIndex, HashMap<i16, U256>) {
+        let mut index = TickIndex::default();
+        let mut bitmap: HashMap<i16, U256> = HashMap::new();
+        for (i, word) in words.iter().enumerate() {
+            let in_word: Vec<IndexedTick> = ticks
+                .iter()
+                .filter(|tick| word_of(**tick, tick_spacing) == *word)
+                .map(|tick| IndexedTick {
+                    tick: *tick,
+                    liquidity_net: *tick as i128,
+                    slot: U256::from(*tick as u32),
+                })
+                .collect();
+            let mut bits = U256::ZERO;
+            for tick in &in_word {
+                bits.set_bit((tick.tick.div_euclid(tick_spacing) & 0xff) as usize, true);
+            }
+            bitmap.insert(*word, bits);
+            index.set_word(*word, U256::MAX - U256::from(i), tick_spacing, in_word);
+        }
+        (index, bitmap)
+    }
+
+    // Walking the index must step exactly like the bitmap search
+    #[test]
+    fn test_index_matches_bitmap() {
+        let tick_spacing = 10;
+        let ticks = [-5120, -2570, -10, 0, 40, 2550, 2560, 5110];
+        let (index, bitmap) = index(&ticks, &[-3, -2, -1, 0, 1, 2], tick_spacing);
+
+        for tick in (-5000..5000).step_by(7) {
+            for lte in [true, false] {
+                let expected =
+                    next_initialized_tick_within_one_word(&bitmap, tick, tick_spacing, lte).unwrap();
+                let indexed = index
+                    .next_initialized_tick_within_one_word(Address::ZERO, tick, tick_spacing, lte)
+                    .unwrap();
+                assert_eq!(indexed, expected, "tick {tick} lte {lte}");
+            }
+        }
+        assert_eq!(index.liquidity_net(40), 40);
+        assert_eq!(index.liquidity_net(50), 0);
+    }
+
+    // Replacing a word only touches the ticks in it, unknown words are reported
+    #[test]
+    fn test_set_word_and_coverage() {
+        let tick_spacing = 1;
+        let (mut index, _) = index(&[-1, 3, 300], &[-1, 0, 1], tick_spacing);
+        index.set_word(0, U256::from(7), tick_spacing, Vec::new());
+        assert_eq!(index.liquidity_net(3), 0);
+        assert_eq!(index.liquidity_net(-1), -1);
+        assert_eq!(index.liquidity_net(300), 300);
+        assert_eq!(index.word_of_slot(&U256::from(7), tick_spacing), Some(0));
+        assert_eq!(index.word_of_slot(&U256::from(300), tick_spacing), Some(1));
+
+        let missing = index.next_initialized_tick_within_one_word(Address::ZERO, 600, tick_spacing, true);
+        assert_eq!(missing, Err(InsufficientTickData { pool: Address::ZERO, word: 2 }));
+    }
+}
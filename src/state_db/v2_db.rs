@@ -1,19 +1,11 @@
 use super::BlockStateDB;
-use crate::state_db::blockstate_db::{BlockStateDBSlot, InsertionType};
+use crate::state_db::layout::{v2_layout, V2Layout, UNISWAP_V2};
 use alloy::transports::Transport;
 use alloy::network::Network;
-use revm::db::AlloyDB;
 use alloy::primitives::{Address, U256};
 use alloy::providers::Provider;
-use lazy_static::lazy_static;
 use log::trace;
 use pool_sync::{Pool, PoolInfo};
-use revm::DatabaseRef;
-use zerocopy::IntoBytes;
-
-lazy_static! {
-    static ref U112_MASK: U256 = (U256::from(1) << 112) - U256::from(1);
-}
 
 impl<T, N, P> BlockStateDB<T, N, P>
 where
@@ -42,22 +34,34 @@ where
         self.insert_token1(address, token1);
     }
 
+    // Storage layout of the pool, pools we do not track are read as uniswap v2
+    #[inline]
+    fn v2_layout_of(&self, pool: &Address) -> V2Layout {
+        self.pool_info
+            .get(pool)
+            .and_then(|info| v2_layout(info.pool_type()).ok())
+            .unwrap_or(UNISWAP_V2)
+    }
+
     // Function to retrieve V2 Pool state
     #[inline]
     pub fn get_reserves(&self, pool: &Address) -> (U256, U256) {
-        let value = self.storage_ref(*pool, U256::from(8)).unwrap();
-        ((value >> 0) & *U112_MASK, (value >> (112)) & *U112_MASK)
+        let layout = self.v2_layout_of(pool);
+        (
+            self.read_field(pool, layout.reserve0).unwrap(),
+            self.read_field(pool, layout.reserve1).unwrap(),
+        )
     }
 
     // get token 0
     pub fn get_token0(&self, pool: Address) -> Address {
-        let token0 = self.storage_ref(pool, U256::from(6)).unwrap();
+        let token0 = self.read_field(&pool, self.v2_layout_of(&pool).token0).unwrap();
         Address::from_word(token0.into())
     }
 
     #[warn(dead_code)]
     pub fn get_token1(&self, pool: Address) -> Address {
-        let token1 = self.storage_ref(pool, U256::from(7)).unwrap();
+        let token1 = self.read_field(&pool, self.v2_layout_of(&pool).token1).unwrap();
         Address::from_word(token1.into())
     }
 
@@ -69,41 +73,26 @@ where
     // Functions to insert v2 pool state
 
     // insert pool reserves into the database
-    fn insert_reserves(&mut self, pool: Address, reserve0: U256, reserve1: U256) {
-        let packed_reserves = (reserve1 << 112) | reserve0;
+    pub(crate) fn insert_reserves(&mut self, pool: Address, reserve0: U256, reserve1: U256) {
         trace!("V2 Database: Inserting reserves for {}", pool);
-        let account = self.accounts.get_mut(&pool).unwrap();
-        let new_db_slot = BlockStateDBSlot {
-            value: packed_reserves,
-            insertion_type: InsertionType::Custom,
-        };
-        account.storage.insert(U256::from(8), new_db_slot);
+        let layout = self.v2_layout_of(&pool);
+        self.write_field(pool, layout.reserve0, reserve0);
+        self.write_field(pool, layout.reserve1, reserve1);
+        self.refresh_pool_state(pool);
     }
 
     // insert token0 into the database
     fn insert_token0(&mut self, pool: Address, token: Address) {
-        let mut bytes = [0u8; 32];
-        bytes[12..].copy_from_slice(token.as_bytes());
         trace!("V2 Database: Inserting token 0 for {}", pool);
-        let account = self.accounts.get_mut(&pool).unwrap();
-        let new_db_slot = BlockStateDBSlot {
-            value: U256::from_be_bytes(bytes),
-            insertion_type: InsertionType::Custom,
-        };
-        account.storage.insert(U256::from(6), new_db_slot);
+        let layout = self.v2_layout_of(&pool);
+        self.write_field(pool, layout.token0, token.into_word().into());
     }
 
     // insert token1 into the database
     fn insert_token1(&mut self, pool: Address, token: Address) {
-        let mut bytes = [0u8; 32];
-        bytes[12..].copy_from_slice(token.as_bytes());
         trace!("V2 Database: Inserting token 1 for {}", pool);
-        let account = self.accounts.get_mut(&pool).unwrap();
-        let new_db_slot = BlockStateDBSlot {
-            value: U256::from_be_bytes(bytes),
-            insertion_type: InsertionType::Custom,
-        };
-        account.storage.insert(U256::from(7), new_db_slot);
+        let layout = self.v2_layout_of(&pool);
+        self.write_field(pool, layout.token1, token.into_word().into());
     }
 }
 
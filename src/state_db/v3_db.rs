@@ -1,30 +1,17 @@
 use super::BlockStateDB;
-use crate::state_db::blockstate_db::{BlockStateDBSlot, InsertionType};
+use crate::state_db::layout::{decode_tick, mapping_slot, v3_layout, V3Layout, UNISWAP_V3};
+use crate::state_db::tick_index::{word_of, IndexedTick, InsufficientTickData, TickIndex};
+use crate::state_db::InsertionType;
 use alloy::sol;
 use alloy::transports::Transport;
 use alloy::network::Network;
-use alloy::primitives::{keccak256, Address, Signed, Uint, I256, U160, U256};
+use alloy::primitives::{Address, U160, U256};
 use alloy::providers::Provider;
-use anyhow::Result;
-use lazy_static::lazy_static;
-use log::trace;
+use anyhow::{anyhow, Result};
+use log::{trace, warn};
 use pool_sync::{Pool, PoolInfo};
 use revm::DatabaseRef;
-use std::ops::{BitAnd, Shl, Shr};
-
-// Bitmasks for storage insertion
-lazy_static! {
-    static ref U112_MASK: U256 = (U256::from(1) << 112) - U256::from(1);
-}
-
-lazy_static! {
-    static ref BITS160MASK: U256 = U256::from(1).shl(160) - U256::from(1);
-    static ref BITS128MASK: U256 = U256::from(1).shl(128) - U256::from(1);
-    static ref BITS24MASK: U256 = U256::from(1).shl(24) - U256::from(1);
-    static ref BITS16MASK: U256 = U256::from(1).shl(16) - U256::from(1);
-    static ref BITS8MASK: U256 = U256::from(1).shl(8) - U256::from(1);
-    static ref BITS1MASK: U256 = U256::from(1);
-}
+use std::collections::HashSet;
 
 // Function signature for Slot0 call
 sol!(
@@ -42,6 +29,15 @@ sol!(
     }
 );
 
+// Words the synced ticks cover. The sync returns every initialized tick in the range it scanned,
+// so the words between the lowest and highest are known even when they are empty
+fn synced_words(words: impl Iterator<Item = i16>) -> HashSet<i16> {
+    let words: Vec<i16> = words.collect();
+    match (words.iter().min(), words.iter().max()) {
+        (Some(&low), Some(&high)) => (low..=high).collect(),
+        _ => HashSet::new(),
+    }
+}
 
 impl<T, N, P> BlockStateDB<T, N, P>
 where
@@ -60,24 +56,133 @@ where
         // extract the v3 pool
         let v3_pool = pool.get_v3().unwrap();
 
-        // Insert slot, liquidity, tick spacing
+        // Insert slot and liquidity, the tick spacing is read from the synced pool
         self.insert_slot0(address, U160::from(v3_pool.sqrt_price), v3_pool.tick)?;
         self.insert_liquidity(address, v3_pool.liquidity)?;
-        self.insert_tick_spacing(address, v3_pool.tick_spacing)?;
+        self.refresh_pool_state(address);
 
         // Insert tick-related data
         for (tick, liquidity_net) in v3_pool.ticks.clone() {
             self.insert_tick_liquidity_net(address, tick, liquidity_net.liquidity_net)?;
         }
 
-        // Insert tick bitmap
-        for (word_pos, bitmap) in v3_pool.tick_bitmap.clone() {
+        // Insert tick bitmap, the words in the synced range that are missing are empty
+        let words = synced_words(v3_pool.tick_bitmap.keys().copied());
+        for word_pos in words.clone() {
+            let bitmap = v3_pool.tick_bitmap.get(&word_pos).copied().unwrap_or_default();
             self.insert_tick_bitmap(address, word_pos, bitmap)?;
         }
 
+        // Index the synced words from the storage that was just written
+        self.tick_indexes.insert(address, TickIndex::default());
+        for word_pos in words {
+            self.index_word(address, word_pos)?;
+        }
+
+        // the swap math always reads the words next to the price, a word that fails to load
+        // here is loaded again when a quote walks into it
+        if let Err(e) = self.cover_current_words(address) {
+            warn!("Failed to load tick words around the price of {address}: {e:?}");
+        }
+
+        Ok(())
+    }
+
+    // Load a bitmap word and the initialized ticks in it from the state source
+    pub fn load_tick_word(&mut self, pool: Address, word: i16) -> Result<()> {
+        if self.tick_indexes.get(&pool).is_some_and(|index| index.covers(word)) {
+            return Ok(());
+        }
+        trace!("V3 Database: Loading tick word {} of pool {}", word, pool);
+
+        let layout = self.v3_layout_of(&pool);
+        let tick_spacing = self.tick_spacing(&pool)?;
+
+        // a word written by a trace is already cached and current
+        let bitmap_slot = mapping_slot(word as i32, layout.tick_bitmap)?;
+        let bitmap = self.storage_ref(pool, bitmap_slot)?;
+        self.insert_tick_bitmap(pool, word, bitmap)?;
+
+        for bit in (0..256).filter(|bit| bitmap.bit(*bit)) {
+            let tick = ((word as i32) * 256 + bit as i32) * tick_spacing;
+            let slot = layout.liquidity_net.at(mapping_slot(tick, layout.ticks)?).slot;
+            let value = self.storage_ref(pool, slot)?;
+            self.insert_account_storage(pool, slot, value, InsertionType::Custom)?;
+        }
+
+        self.index_word(pool, word)
+    }
+
+    // Rebuild the index of a word from the bitmap and ticks in the local storage
+    fn index_word(&mut self, pool: Address, word: i16) -> Result<()> {
+        let layout = self.v3_layout_of(&pool);
+        let tick_spacing = self.tick_spacing(&pool)?;
+        let word_slot = mapping_slot(word as i32, layout.tick_bitmap)?;
+        let bitmap = self.storage_ref(pool, word_slot)?;
+
+        let mut ticks = Vec::new();
+        for bit in (0..256).filter(|bit| bitmap.bit(*bit)) {
+            let tick = ((word as i32) * 256 + bit as i32) * tick_spacing;
+            let field = layout.liquidity_net.at(mapping_slot(tick, layout.ticks)?);
+            let liquidity_net: u128 = self.read_field(&pool, field)?.saturating_to();
+            ticks.push(IndexedTick {
+                tick,
+                liquidity_net: liquidity_net as i128,
+                slot: field.slot,
+            });
+        }
+
+        self.tick_indexes
+            .entry(pool)
+            .or_default()
+            .set_word(word, word_slot, tick_spacing, ticks);
+        Ok(())
+    }
+
+    // Patch the index with the slots a trace wrote. Initialized ticks and bitmap words are
+    // written together so the words they are in can be rebuilt from storage
+    pub(crate) fn patch_tick_index(&mut self, pool: Address, written: &[U256]) -> Result<()> {
+        let tick_spacing = self.tick_spacing(&pool)?;
+        let words: HashSet<i16> = match self.tick_indexes.get(&pool) {
+            Some(index) => written
+                .iter()
+                .filter_map(|slot| index.word_of_slot(slot, tick_spacing))
+                .collect(),
+            None => return Ok(()),
+        };
+        for word in words {
+            self.index_word(pool, word)?;
+        }
+        Ok(())
+    }
+
+    // Make sure the words the swap math starts from are known
+    pub(crate) fn cover_current_words(&mut self, pool: Address) -> Result<()> {
+        let tick = self.slot0(pool)?.tick.as_i32();
+        let word = word_of(tick, self.tick_spacing(&pool)?);
+        for word in word.saturating_sub(1)..=word.saturating_add(1) {
+            self.load_tick_word(pool, word)?;
+        }
         Ok(())
     }
 
+    // Sorted initialized ticks of the pool
+    #[inline]
+    pub fn tick_index(&self, address: &Address) -> Result<&TickIndex> {
+        self.tick_indexes
+            .get(address)
+            .ok_or_else(|| anyhow!("{address} has no tick index"))
+    }
+
+    // Storage layout of the pool, pools we do not track are read as uniswap v3
+    #[inline]
+    fn v3_layout_of(&self, pool: &Address) -> V3Layout {
+        self.pool_info
+            .get(pool)
+            .and_then(|info| v3_layout(info.pool_type()).ok())
+            .unwrap_or(UNISWAP_V3)
+    }
+
     // Insert tick bitmap
     fn insert_tick_bitmap(&mut self, pool: Address, tick: i16, bitmap: U256) -> Result<()> {
         trace!(
@@ -85,32 +190,17 @@ where
             tick,
             pool
         );
-        // Hash slot exactly as in read operation
-        let tick_bytes = I256::try_from(tick)?.to_be_bytes::<32>();
-        let mut buf = tick_bytes.to_vec();
-        buf.append(&mut U256::from(6).to_be_bytes::<32>().to_vec());
-        let slot = keccak256(buf.as_slice());
-
-        let account = self.accounts.get_mut(&pool).unwrap();
-        let new_db_slot = BlockStateDBSlot {
-            value: bitmap,
-            insertion_type: InsertionType::Custom,
-        };
-        account
-            .storage
-            .insert(U256::from_be_bytes(slot.into()), new_db_slot);
-        Ok(())
+        let layout = self.v3_layout_of(&pool);
+        let slot = mapping_slot(tick as i32, layout.tick_bitmap)?;
+        self.insert_account_storage(pool, slot, bitmap, InsertionType::Custom)
     }
 
     // Insert the pool liquidity
-    fn insert_liquidity(&mut self, pool: Address, liquidity: u128) -> Result<()> {
+    pub(crate) fn insert_liquidity(&mut self, pool: Address, liquidity: u128) -> Result<()> {
         trace!("V3 Database: Inserting liquidity for {}", pool);
-        let account = self.accounts.get_mut(&pool).unwrap();
-        let new_db_slot = BlockStateDBSlot {
-            value: U256::from(liquidity),
-            insertion_type: InsertionType::Custom,
-        };
-        account.storage.insert(U256::from(4), new_db_slot);
+        let layout = self.v3_layout_of(&pool);
+        self.write_field(pool, layout.liquidity, U256::from(liquidity));
+        self.refresh_v3_state(pool);
         Ok(())
     }
 
@@ -126,166 +216,108 @@ where
             tick,
             pool
         );
-        // Convert signed 128-bit to unsigned representation matching the read operation
-        let unsigned_liquidity = liquidity_net as u128;
-
-        // Hash slot
-        let tick_bytes = I256::try_from(tick)?.to_be_bytes::<32>();
-        let mut buf = tick_bytes.to_vec();
-        buf.append(&mut U256::from(5).to_be_bytes::<32>().to_vec());
-        let slot = keccak256(buf.as_slice());
-
-        // Convert to U256 and shift left by 128 bits (inverse of the right shift in read)
-        let value = U256::from(unsigned_liquidity) << 128;
-
-        let account = self.accounts.get_mut(&pool).unwrap();
-        let new_db_slot = BlockStateDBSlot {
-            value,
-            insertion_type: InsertionType::Custom,
-        };
-        account
-            .storage
-            .insert(U256::from_be_bytes(slot.into()), new_db_slot);
+        // Stored as the unsigned representation of the int128
+        let layout = self.v3_layout_of(&pool);
+        let field = layout.liquidity_net.at(mapping_slot(tick, layout.ticks)?);
+        self.write_field(pool, field, U256::from(liquidity_net as u128));
         Ok(())
     }
 
-    // Insert slot0
-    fn insert_slot0(&mut self, pool: Address, sqrt_price: U160, tick: i32) -> Result<()> {
+    // Insert slot0, the pool is left unlocked so it can be swapped through
+    pub(crate) fn insert_slot0(&mut self, pool: Address, sqrt_price: U160, tick: i32) -> Result<()> {
         trace!("V3 Database: Inserting slot0 for {}", pool);
-
-        // Extract from read operation:
-        // feeProtocol: ((Shr::<U256>::shr(cell, U256::from(160 + 24 + 16 + 16 + 16))) & *BITS8MASK)
-        let slot0 = U256::from(sqrt_price)
-            | ((U256::from(tick as u32) & *BITS24MASK) << 160)
-            | (U256::from(0) << (160 + 24))
-            | (U256::from(0) << (160 + 24 + 16))
-            | (U256::from(0) << (160 + 24 + 16 + 16))
-            | ((U256::from(0) & *BITS8MASK) << (160 + 24 + 16 + 16 + 16))
-            | (U256::from(1u8) << (160 + 24 + 16 + 16 + 16 + 8));
-
-        let account = self.accounts.get_mut(&pool).unwrap();
-        let new_db_slot = BlockStateDBSlot {
-            value: slot0,
-            insertion_type: InsertionType::Custom,
-        };
-        account.storage.insert(U256::from(0), new_db_slot);
+        let layout = self.v3_layout_of(&pool);
+        self.write_field(pool, layout.sqrt_price, U256::from(sqrt_price));
+        self.write_field(pool, layout.tick, U256::from(tick as u32));
+        self.write_field(pool, layout.unlocked, U256::from(1));
+        self.refresh_v3_state(pool);
         Ok(())
     }
 
-    fn insert_tick_spacing(&mut self, pool: Address, tick_spacing: i32) -> Result<()> {
-        trace!("V3 Database: Inserting tick spacing for {}", pool);
-
-        // get the account and insert into slot 14
-        let account = self.accounts.get_mut(&pool).unwrap();
-        let new_db_slot = BlockStateDBSlot {
-            value: U256::from(tick_spacing),
-            insertion_type: InsertionType::Custom,
-        };
-        account.storage.insert(U256::from(14), new_db_slot);
-        Ok(())
+    // Slot0 and the liquidity are written one after the other, the state of a new pool is only
+    // decoded once both are in
+    fn refresh_v3_state(&mut self, pool: Address) {
+        if self.pool_states.contains_key(&pool) {
+            self.refresh_pool_state(pool);
+        }
     }
 
     #[inline]
     pub fn tick_spacing(&self, address: &Address) -> Result<i32> {
-        let data = self
-            .accounts
+        let pool = self
+            .pool_info
             .get(address)
-            .unwrap()
-            .storage
-            .get(&U256::from(14))
-            .unwrap();
-        let data = data.value;
-        let tick_spacing: i32 = data.saturating_to();
-        Ok(tick_spacing)
+            .and_then(|pool| pool.get_v3())
+            .ok_or_else(|| anyhow!("{address} is not a tracked v3 pool"))?;
+        Ok(pool.tick_spacing)
     }
 
     // Get slot 0
     #[inline]
     pub fn slot0(&self, address: Address) -> Result<UniswapV3::slot0Return> {
-        let cell = *self
-            .accounts
-            .get(&address)
-            .unwrap()
-            .storage
-            .get(&U256::from(0))
-            .unwrap();
-        let cell = cell.value;
-        let tick: Uint<24, 1> = ((Shr::<U256>::shr(cell, U256::from(160))) & *BITS24MASK).to();
-        let tick: Signed<24, 1> = Signed::<24, 1>::from_raw(tick);
-        let tick: i32 = tick.as_i32();
-
-        let sqrt_price_x96: U160 = cell.bitand(*BITS160MASK).to();
+        let layout = self.v3_layout_of(&address);
+        let fee_protocol = match layout.fee_protocol {
+            Some(field) => self.read_field(&address, field)?.saturating_to(),
+            None => 0,
+        };
 
         Ok(UniswapV3::slot0Return {
-            sqrtPriceX96: sqrt_price_x96,
-            tick: tick.try_into()?,
-            observationIndex: ((Shr::<U256>::shr(cell, U256::from(160 + 24))) & *BITS16MASK).to(),
-            observationCardinality: ((Shr::<U256>::shr(cell, U256::from(160 + 24 + 16)))
-                & *BITS16MASK)
-                .to(),
-            observationCardinalityNext: ((Shr::<U256>::shr(cell, U256::from(160 + 24 + 16 + 16)))
-                & *BITS16MASK)
-                .to(),
-            feeProtocol: ((Shr::<U256>::shr(cell, U256::from(160 + 24 + 16 + 16 + 16)))
-                & *BITS8MASK)
-                .to(),
-            unlocked: ((Shr::<U256>::shr(cell, U256::from(160 + 24 + 16 + 16 + 16 + 8)))
-                & *BITS1MASK)
+            sqrtPriceX96: self.read_field(&address, layout.sqrt_price)?.to(),
+            tick: decode_tick(self.read_field(&address, layout.tick)?).try_into()?,
+            observationIndex: self.read_field(&address, layout.observation_index)?.to(),
+            observationCardinality: self.read_field(&address, layout.observation_cardinality)?.to(),
+            observationCardinalityNext: self
+                .read_field(&address, layout.observation_cardinality_next)?
                 .to(),
+            feeProtocol: fee_protocol,
+            unlocked: self.read_field(&address, layout.unlocked)? != U256::ZERO,
         })
     }
 
     #[inline]
     pub fn liquidity(&self, address: Address) -> Result<u128> {
-        let cell = self
-            .accounts
-            .get(&address)
-            .unwrap()
-            .storage
-            .get(&U256::from(4))
-            .unwrap();
-        let cell = cell.value;
-        let cell: u128 = cell.saturating_to();
-        Ok(cell)
+        let layout = self.v3_layout_of(&address);
+        Ok(self.read_field(&address, layout.liquidity)?.saturating_to())
     }
 
     #[inline]
     pub fn ticks_liquidity_net(&self, address: Address, tick: i32) -> Result<i128> {
         //i24
-        let cell = self.read_hashed_slot(
-            &address,
-            &U256::from(5),
-            &U256::from_be_bytes(I256::try_from(tick)?.to_be_bytes::<32>()),
-        )?;
-        let unsigned_liqudity: Uint<128, 2> = cell.shr(U256::from(128)).to();
-        let lu128: u128 = unsigned_liqudity.to();
-        let li128: i128 = lu128 as i128;
-
-        Ok(li128)
+        let layout = self.v3_layout_of(&address);
+        let field = layout.liquidity_net.at(mapping_slot(tick, layout.ticks)?);
+        let lu128: u128 = self.read_field(&address, field)?.saturating_to();
+        Ok(lu128 as i128)
     }
 
+    // Bitmap word of the pool, a word that is not loaded is an `InsufficientTickData` error
     #[inline]
     pub fn tick_bitmap(&self, address: Address, tick: i16) -> Result<U256> {
         //i16
-        let cell = self.read_hashed_slot(
-            &address,
-            &U256::from(6),
-            &U256::from_be_bytes(I256::try_from(tick)?.to_be_bytes::<32>()),
-        )?;
-        Ok(cell)
+        if !self
+            .tick_indexes
+            .get(&address)
+            .is_some_and(|index| index.covers(tick))
+        {
+            return Err(InsufficientTickData { pool: address, word: tick }.into());
+        }
+        let layout = self.v3_layout_of(&address);
+        let slot = mapping_slot(tick as i32, layout.tick_bitmap)?;
+        Ok(self.storage_ref(address, slot)?)
     }
+}
 
-    #[inline]
-    fn read_hashed_slot(
-        &self,
-        account: &Address,
-        hashmap_offset: &U256,
-        item: &U256,
-    ) -> Result<U256> {
-        let mut buf = item.to_be_bytes::<32>().to_vec();
-        buf.append(&mut hashmap_offset.to_be_bytes::<32>().to_vec());
-        let slot: U256 = keccak256(buf.as_slice()).into();
-        Ok(self.storage_ref(*account, slot)?)
+#[cfg(test)]
+mod coverage_tests {
+    use super::*;
+
+    // Empty words between synced words are known, words outside the range are not
+    #[test]
+    fn test_synced_words_cover_range() {
+        let words = synced_words([-3i16, 2].into_iter());
+        assert_eq!(words.len(), 6);
+        assert!(words.contains(&0));
+        assert!(!words.contains(&3) && !words.contains(&-4));
+        assert!(synced_words(std::iter::empty()).is_empty());
     }
 }
 
@@ -0,0 +1,142 @@
+use alloy::network::Network;
+use alloy::primitives::Address;
+use alloy::providers::Provider;
+use alloy::transports::Transport;
+use log::{debug, info};
+use pool_sync::{Pool, PoolInfo};
+use std::collections::{HashMap, HashSet};
+
+use super::SearchStrategy;
+use crate::estimator::Estimator;
+use crate::graph::ArbGraph;
+use crate::swap::SwapPath;
+
+// Re-evaluate the enumerated cycles that go through the touched pools
+pub struct CycleIndex {
+    graph: ArbGraph,
+    // pool => hashes of all paths through it
+    path_index: HashMap<Address, HashSet<u64>>,
+    // paths pulled out of the index for each quarantined pool
+    excluded: HashMap<Address, HashSet<u64>>,
+    cycles: HashMap<u64, SwapPath>,
+}
+
+impl CycleIndex {
+    // Build the graph and enumerate all of the cycles
+    pub fn new(pools: Vec<Pool>, start_tokens: Vec<Address>) -> Self {
+        let graph = ArbGraph::new(pools, start_tokens);
+        let cycles = graph.generate_cycles();
+        info!("Generated {} cycles", cycles.len());
+
+        let mut index = Self {
+            graph,
+            path_index: HashMap::new(),
+            excluded: HashMap::new(),
+            cycles: HashMap::new(),
+        };
+
+        // make our path mapper for easily getting touched paths
+        for path in cycles {
+            index.insert_path(path);
+        }
+        index
+    }
+
+    // Track a path, it stays out of the index if it goes through a quarantined pool
+    fn insert_path(&mut self, path: SwapPath) {
+        let hash = path.hash;
+        if let Some(blocking) = path
+            .steps
+            .iter()
+            .find(|step| self.excluded.contains_key(&step.pool_address))
+        {
+            self.excluded.get_mut(&blocking.pool_address).unwrap().insert(hash);
+        } else {
+            for step in &path.steps {
+                self.path_index.entry(step.pool_address).or_default().insert(hash);
+            }
+        }
+        self.cycles.insert(hash, path);
+    }
+}
+
+impl<T, N, P> SearchStrategy<T, N, P> for CycleIndex
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<T, N>,
+{
+    // from the updated pools, get all paths that we want to recheck
+    fn candidates(
+        &mut self,
+        _estimator: &Estimator<T, N, P>,
+        touched: &HashSet<Address>,
+    ) -> Vec<SwapPath> {
+        let hashes: HashSet<u64> = touched
+            .iter()
+            .filter_map(|pool| self.path_index.get(pool))
+            .flatten()
+            .copied()
+            .collect();
+        hashes.iter().map(|hash| self.cycles[hash].clone()).collect()
+    }
+
+    // Add a new pool, only the cycles through it are generated
+    fn add_pool(&mut self, pool: &Pool) {
+        let new_paths = self.graph.add_pool(pool.clone());
+        debug!("{} new paths through {}", new_paths.len(), pool.address());
+        for path in new_paths {
+            self.insert_path(path);
+        }
+    }
+
+    // Remove a pool and drop every cycle through it
+    fn remove_pool(&mut self, pool: Address) {
+        self.graph.remove_pool(pool);
+        let mut hashes = self.path_index.remove(&pool).unwrap_or_default();
+        hashes.extend(self.excluded.remove(&pool).unwrap_or_default());
+        for hash in hashes {
+            let Some(path) = self.cycles.remove(&hash) else {
+                continue;
+            };
+            for step in &path.steps {
+                if let Some(hashes) = self.path_index.get_mut(&step.pool_address) {
+                    hashes.remove(&hash);
+                }
+                if let Some(hashes) = self.excluded.get_mut(&step.pool_address) {
+                    hashes.remove(&hash);
+                }
+            }
+        }
+    }
+
+    // Remove all paths through a quarantined pool from the index
+    fn exclude_pool(&mut self, pool: Address) {
+        let paths = self.path_index.remove(&pool).unwrap_or_default();
+        for hash in &paths {
+            for step in &self.cycles[hash].steps {
+                if let Some(hashes) = self.path_index.get_mut(&step.pool_address) {
+                    hashes.remove(hash);
+                }
+            }
+        }
+        self.excluded.insert(pool, paths);
+    }
+
+    // Put the paths through a released pool back into the index. Paths that still go
+    // through another quarantined pool stay out until that pool is released too
+    fn restore_pool(&mut self, pool: Address) {
+        let Some(paths) = self.excluded.remove(&pool) else {
+            return;
+        };
+        for hash in paths {
+            if let Some(path) = self.cycles.remove(&hash) {
+                self.insert_path(path);
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.cycles.len()
+    }
+}
@@ -0,0 +1,65 @@
+use alloy::network::Network;
+use alloy::primitives::Address;
+use alloy::providers::Provider;
+use alloy::transports::Transport;
+use pool_sync::Pool;
+use std::collections::HashSet;
+
+use crate::estimator::Estimator;
+use crate::swap::SwapPath;
+
+pub mod cycle_index;
+pub mod negative_cycle;
+pub use cycle_index::CycleIndex;
+pub use negative_cycle::NegativeCycle;
+
+// How the searcher finds candidate paths for the pools touched in a block. The candidates
+// are estimated, verified and sent on by the searcher so every strategy ends up in the
+// same Event::ArbPath format
+pub trait SearchStrategy<T, N, P>: Send
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<T, N>,
+{
+    // Candidate paths that could have become profitable from the touched pools
+    fn candidates(
+        &mut self,
+        estimator: &Estimator<T, N, P>,
+        touched: &HashSet<Address>,
+    ) -> Vec<SwapPath>;
+
+    // A new pool is being tracked
+    fn add_pool(&mut self, pool: &Pool);
+
+    // A pool is no longer tracked
+    fn remove_pool(&mut self, pool: Address);
+
+    // A pool was quarantined, it should not show up in candidates
+    fn exclude_pool(&mut self, pool: Address);
+
+    // A pool was released from quarantine
+    fn restore_pool(&mut self, pool: Address);
+
+    // Number of paths or edges being searched over, for logging
+    fn size(&self) -> usize;
+}
+
+// Construct the strategy selected by SEARCH_STRATEGY, either `cycles` (default) to re-evaluate
+// the enumerated cycles or `bellman_ford` to search for negative cycles on the rate graph
+pub fn strategy_from_env<T, N, P>(
+    pools: Vec<Pool>,
+    start_tokens: Vec<Address>,
+) -> Box<dyn SearchStrategy<T, N, P>>
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<T, N>,
+{
+    let strategy = std::env::var("SEARCH_STRATEGY").unwrap_or_else(|_| "cycles".to_string());
+    match strategy.as_str() {
+        "bellman_ford" => Box::new(NegativeCycle::new(pools, start_tokens)),
+        "cycles" => Box::new(CycleIndex::new(pools, start_tokens)),
+        other => panic!("Unknown search strategy {other}"),
+    }
+}
@@ -0,0 +1,339 @@
+use alloy::network::Network;
+use alloy::primitives::{Address, U256};
+use alloy::providers::Provider;
+use alloy::transports::Transport;
+use log::debug;
+use pool_sync::{Pool, PoolInfo, PoolType};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use super::SearchStrategy;
+use crate::estimator::{Estimator, RATE_SCALE_VALUE};
+use crate::protocol::is_stable;
+use crate::swap::{SwapPath, SwapStep};
+use crate::bytecode::{is_code_consistent, pool_codes};
+use crate::token_profile::{is_pool_tradeable, token_profiles, TokenProfiles};
+
+// Ignore improvements smaller than this so rounding noise does not look like a cycle
+const EPSILON: f64 = 1e-12;
+
+// A directed swap through a pool
+#[derive(Debug, Clone)]
+struct RateEdge {
+    pool: Address,
+    token_out: Address,
+    protocol: PoolType,
+    fee: u32,
+    stable: bool,
+}
+
+// Search for negative cycles on the directed graph of -ln(rate). A cycle whose weights sum
+// below zero has a rate product above one. The search is seeded with the tokens of the touched
+// pools and runs a bellman-ford style relaxation from the tokens that improved, for at most
+// MAX_HOPS rounds, so it finds cycles that were never enumerated
+pub struct NegativeCycle {
+    // token => outgoing edges
+    edges: HashMap<Address, Vec<RateEdge>>,
+    // pool => the tokens it connects
+    pool_tokens: HashMap<Address, (Address, Address)>,
+    // quarantined pools
+    excluded: HashSet<Address>,
+    // cycles have to go through one of these to be sized and executed
+    start_tokens: HashSet<Address>,
+    // pools with a token that can not be traded are left out
+    token_profiles: Arc<TokenProfiles>,
+    max_hops: usize,
+}
+
+impl NegativeCycle {
+    pub fn new(pools: Vec<Pool>, start_tokens: Vec<Address>) -> Self {
+        let max_hops: usize = std::env::var("MAX_HOPS")
+            .ok()
+            .and_then(|hops| hops.parse().ok())
+            .unwrap_or(4);
+        let mut strategy = Self {
+            edges: HashMap::new(),
+            pool_tokens: HashMap::new(),
+            excluded: HashSet::new(),
+            start_tokens: start_tokens.into_iter().collect(),
+            token_profiles: token_profiles(),
+            max_hops,
+        };
+        for pool in &pools {
+            strategy.insert_pool(pool);
+        }
+        strategy
+    }
+
+    // Add an edge in each direction. The estimator only keeps rates for the token0/token1
+    // pair so multi token pools contribute a single pair
+    fn insert_pool(&mut self, pool: &Pool) {
+        if !is_pool_tradeable(&self.token_profiles, pool) || !is_code_consistent(&pool_codes(), pool) {
+            return;
+        }
+        let token0 = pool.token0_address();
+        let token1 = pool.token1_address();
+        if self.pool_tokens.insert(pool.address(), (token0, token1)).is_some() {
+            return;
+        }
+        for (token_in, token_out) in [(token0, token1), (token1, token0)] {
+            self.edges.entry(token_in).or_default().push(RateEdge {
+                pool: pool.address(),
+                token_out,
+                protocol: pool.pool_type(),
+                fee: pool.fee(),
+                stable: is_stable(pool),
+            });
+        }
+    }
+
+    // Follow the predecessors back from a node, if they loop the loop is a negative cycle
+    fn pred_cycle(
+        &self,
+        node: Address,
+        pred: &HashMap<Address, (Address, usize)>,
+    ) -> Option<Vec<SwapStep>> {
+        let mut seen: HashSet<Address> = HashSet::new();
+        let mut current = node;
+        while seen.insert(current) {
+            current = pred.get(&current)?.0;
+        }
+
+        // current is on the loop, walk it once collecting the edges in reverse
+        let loop_start = current;
+        let mut steps = Vec::new();
+        loop {
+            let (from, edge_index) = pred[&current];
+            let edge = &self.edges[&from][edge_index];
+            steps.push(SwapStep {
+                pool_address: edge.pool,
+                token_in: from,
+                token_out: current,
+                protocol: edge.protocol,
+                fee: edge.fee,
+                stable: edge.stable,
+            });
+            current = from;
+            if current == loop_start || steps.len() > self.max_hops {
+                break;
+            }
+        }
+        if current != loop_start || steps.len() < 2 {
+            return None;
+        }
+        steps.reverse();
+
+        // the same pool can not be swapped through twice
+        let pools: HashSet<Address> = steps.iter().map(|step| step.pool_address).collect();
+        if pools.len() != steps.len() {
+            return None;
+        }
+
+        // rotate so the cycle starts and ends in a base token
+        let start = steps
+            .iter()
+            .position(|step| self.start_tokens.contains(&step.token_in))?;
+        steps.rotate_left(start);
+        Some(steps)
+    }
+}
+
+impl NegativeCycle {
+    // Relax from the tokens of the touched pools with the rate of each directed edge
+    fn search(
+        &self,
+        touched: &HashSet<Address>,
+        rate: impl Fn(&Address, &Address) -> Option<U256>,
+    ) -> Vec<SwapPath> {
+        // seed with every token of the touched pools
+        let mut dist: HashMap<Address, f64> = HashMap::new();
+        let mut frontier: HashSet<Address> = HashSet::new();
+        for (token0, token1) in touched.iter().filter_map(|pool| self.pool_tokens.get(pool)) {
+            for token in [*token0, *token1] {
+                dist.insert(token, 0.0);
+                frontier.insert(token);
+            }
+        }
+
+        let scale: f64 = RATE_SCALE_VALUE.saturating_to::<u128>() as f64;
+        let mut pred: HashMap<Address, (Address, usize)> = HashMap::new();
+        let mut found: HashMap<u64, SwapPath> = HashMap::new();
+
+        for _ in 0..self.max_hops {
+            let mut next: HashSet<Address> = HashSet::new();
+            for token_in in &frontier {
+                let Some(edges) = self.edges.get(token_in) else {
+                    continue;
+                };
+                let dist_in = dist[token_in];
+                for (edge_index, edge) in edges.iter().enumerate() {
+                    if self.excluded.contains(&edge.pool) {
+                        continue;
+                    }
+                    let rate = match rate(&edge.pool, token_in) {
+                        Some(rate) if rate > U256::ZERO => rate.saturating_to::<u128>() as f64 / scale,
+                        _ => continue,
+                    };
+                    let candidate = dist_in - rate.ln();
+                    let current = dist.get(&edge.token_out).copied().unwrap_or(f64::INFINITY);
+                    if candidate < current - EPSILON {
+                        dist.insert(edge.token_out, candidate);
+                        pred.insert(edge.token_out, (*token_in, edge_index));
+                        next.insert(edge.token_out);
+                    }
+                }
+            }
+
+            for token in &next {
+                if let Some(steps) = self.pred_cycle(*token, &pred) {
+                    let path = SwapPath::new(steps);
+                    found.entry(path.hash).or_insert(path);
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            frontier = next;
+        }
+
+        debug!("Found {} negative cycles", found.len());
+        found.into_values().collect()
+    }
+}
+
+impl<T, N, P> SearchStrategy<T, N, P> for NegativeCycle
+where
+    T: Transport + Clone,
+    N: Network,
+    P: Provider<T, N>,
+{
+    fn candidates(
+        &mut self,
+        estimator: &Estimator<T, N, P>,
+        touched: &HashSet<Address>,
+    ) -> Vec<SwapPath> {
+        self.search(touched, |pool, token_in| estimator.rate(pool, token_in))
+    }
+
+    fn add_pool(&mut self, pool: &Pool) {
+        self.insert_pool(pool);
+    }
+
+    fn remove_pool(&mut self, pool: Address) {
+        let Some((token0, token1)) = self.pool_tokens.remove(&pool) else {
+            return;
+        };
+        for token in [token0, token1] {
+            if let Some(edges) = self.edges.get_mut(&token) {
+                edges.retain(|edge| edge.pool != pool);
+            }
+        }
+    }
+
+    fn exclude_pool(&mut self, pool: Address) {
+        self.excluded.insert(pool);
+    }
+
+    fn restore_pool(&mut self, pool: Address) {
+        self.excluded.remove(&pool);
+    }
+
+    fn size(&self) -> usize {
+        self.pool_tokens.len() * 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::address;
+    use pool_sync::UniswapV2Pool;
+
+    const WETH: Address = address!("4200000000000000000000000000000000000006");
+    const USDC: Address = address!("833589fCD6eDb6E08f4c7C32D4f71b54bdA02913");
+    const DAI: Address = address!("50c5725949A6F0c72E6C4a641F24049A917DB0Cb");
+
+    fn pool(address: Address, token0: Address, token1: Address) -> Pool {
+        Pool::UniswapV2(UniswapV2Pool {
+            address,
+            token0,
+            token1,
+            token0_name: String::new(),
+            token1_name: String::new(),
+            token0_decimals: 18,
+            token1_decimals: 18,
+            token0_reserves: U256::ZERO,
+            token1_reserves: U256::ZERO,
+            stable: None,
+            fee: None,
+        })
+    }
+
+    // rates scaled by RATE_SCALE_VALUE, pool => token in => rate
+    fn rates(entries: &[(Address, Address, f64)]) -> HashMap<(Address, Address), U256> {
+        entries
+            .iter()
+            .map(|(pool, token_in, rate)| ((*pool, *token_in), U256::from((rate * 1e18) as u128)))
+            .collect()
+    }
+
+    // A triangle whose rate product is above one is found and starts in the base token,
+    // without it no cycle is reported
+    #[test]
+    fn test_finds_profitable_triangle() {
+        let weth_usdc = address!("00000000000000000000000000000000000000a1");
+        let usdc_dai = address!("00000000000000000000000000000000000000a2");
+        let dai_weth = address!("00000000000000000000000000000000000000a3");
+        let mut strategy = NegativeCycle {
+            edges: HashMap::new(),
+            pool_tokens: HashMap::new(),
+            excluded: HashSet::new(),
+            start_tokens: [WETH].into_iter().collect(),
+            token_profiles: Arc::new(TokenProfiles::new()),
+            max_hops: 4,
+        };
+        for pool in [pool(weth_usdc, WETH, USDC), pool(usdc_dai, USDC, DAI), pool(dai_weth, DAI, WETH)] {
+            strategy.insert_pool(&pool);
+        }
+        let touched: HashSet<Address> = [weth_usdc].into_iter().collect();
+
+        // 2000 * 1.01 / 2000 > 1 going weth -> usdc -> dai -> weth
+        let profitable = rates(&[
+            (weth_usdc, WETH, 2000.0),
+            (weth_usdc, USDC, 1.0 / 2000.0),
+            (usdc_dai, USDC, 1.01),
+            (usdc_dai, DAI, 1.0 / 1.02),
+            (dai_weth, DAI, 1.0 / 2000.0),
+            (dai_weth, WETH, 1990.0),
+        ]);
+        let paths = strategy.search(&touched, |pool, token_in| profitable.get(&(*pool, *token_in)).copied());
+        assert_eq!(paths.len(), 1);
+        let steps = &paths[0].steps;
+        assert_eq!(steps[0].token_in, WETH);
+        assert_eq!(steps.last().unwrap().token_out, WETH);
+        assert_eq!(
+            steps.iter().map(|step| step.pool_address).collect::<Vec<_>>(),
+            vec![weth_usdc, usdc_dai, dai_weth]
+        );
+
+        // excluding a pool on the cycle removes it
+        strategy.excluded.insert(usdc_dai);
+        assert!(strategy
+            .search(&touched, |pool, token_in| profitable.get(&(*pool, *token_in)).copied())
+            .is_empty());
+        strategy.excluded.clear();
+
+        // every direction loses to fees
+        let fair = rates(&[
+            (weth_usdc, WETH, 1990.0),
+            (weth_usdc, USDC, 1.0 / 2010.0),
+            (usdc_dai, USDC, 0.99),
+            (usdc_dai, DAI, 0.99),
+            (dai_weth, DAI, 1.0 / 2010.0),
+            (dai_weth, WETH, 1990.0),
+        ]);
+        assert!(strategy
+            .search(&touched, |pool, token_in| fair.get(&(*pool, *token_in)).copied())
+            .is_empty());
+    }
+}
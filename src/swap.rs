@@ -1,13 +1,15 @@
+use crate::gen_::BatchFlashSwap;
 use crate::gen_::FlashQuoter;
 use crate::gen_::FlashSwap;
-use crate::AMOUNT;
+use crate::protocol::executor_code;
 use crate::traits::*;
 use crate::types::*;
-use alloy::primitives::Address;
+use alloy::primitives::{Address, U256};
+use anyhow::Result;
 use pool_sync::PoolType;
 use serde::{Deserialize, Serialize};
-use std::convert::From;
-use std::hash::Hash;
+use std::convert::{From, TryFrom};
+use std::hash::{DefaultHasher, Hash, Hasher};
 
 // A full representation of a path that we can swap along with its hash
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
@@ -16,6 +18,18 @@ pub struct SwapPath {
     pub hash: u64,
 }
 
+impl SwapPath {
+    // Construct a path, the hash is over all of the steps
+    pub fn new(steps: Vec<SwapStep>) -> Self {
+        let mut hasher = DefaultHasher::new();
+        steps.iter().for_each(|step| step.hash(&mut hasher));
+        Self {
+            steps,
+            hash: hasher.finish(),
+        }
+    }
+}
+
 // A step representing an individual swap
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct SwapStep {
@@ -24,6 +38,9 @@ pub struct SwapStep {
     pub token_out: Address,
     pub protocol: PoolType,
     pub fee: u32,
+    // if this is an aerodrome stable pool
+    #[serde(default)]
+    pub stable: bool,
 }
 
 // Convert from Quoter format into SwapFormat. The same thing
@@ -37,23 +54,110 @@ impl From<FlashQuoter::SwapParams> for FlashSwap::SwapParams {
     }
 }
 
-// Convert from arb SwapPath into Quoter format
-impl From<SwapPath> for FlashQuoter::SwapParams {
-    fn from(path: SwapPath) -> Self {
+// Convert from arb SwapPath and its input amount into Quoter format. Fails if the
+// executor can not swap through one of the protocols on the path
+impl TryFrom<(SwapPath, U256)> for FlashQuoter::SwapParams {
+    type Error = anyhow::Error;
+
+    fn try_from((path, amount_in): (SwapPath, U256)) -> Result<Self> {
         let mut pools: Vec<Address> = Vec::new();
         let mut protocol: Vec<u8> = Vec::new();
         for step in path.steps {
             pools.push(step.pool_address);
-            if step.protocol.is_v3() {
-                protocol.push(1);
-            } else {
-                protocol.push(0);
-            }
+            protocol.push(executor_code(step.protocol, step.stable)?);
         }
-        FlashQuoter::SwapParams {
+        Ok(FlashQuoter::SwapParams {
             pools,
             poolVersions: protocol,
-            amountIn: *AMOUNT,
+            amountIn: amount_in,
+        })
+    }
+}
+
+// Convert from Quoter format into a batch route
+impl From<FlashQuoter::SwapParams> for BatchFlashSwap::Route {
+    fn from(params: FlashQuoter::SwapParams) -> Self {
+        BatchFlashSwap::Route {
+            pools: params.pools,
+            poolVersions: params.poolVersions,
+            amountIn: params.amountIn,
         }
     }
 }
+
+// Convert a set of independent quoted routes into a batch, a failing route does not revert the others
+impl From<Vec<FlashQuoter::SwapParams>> for BatchFlashSwap::BatchParams {
+    fn from(routes: Vec<FlashQuoter::SwapParams>) -> Self {
+        BatchFlashSwap::BatchParams {
+            routes: routes.into_iter().map(Into::into).collect(),
+            singleLoan: true,
+            allowPartial: true,
+        }
+    }
+}
+
+// Convert a set of arb SwapPaths and their input amounts into a batch
+impl TryFrom<Vec<(SwapPath, U256)>> for BatchFlashSwap::BatchParams {
+    type Error = anyhow::Error;
+
+    fn try_from(paths: Vec<(SwapPath, U256)>) -> Result<Self> {
+        let routes = paths
+            .into_iter()
+            .map(FlashQuoter::SwapParams::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(routes.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::address;
+
+    fn path(protocol: PoolType) -> SwapPath {
+        let weth = address!("4200000000000000000000000000000000000006");
+        let usdc = address!("833589fcd6edb6e08f4c7c32d4f71b54bda02913");
+        let step = |pool_address, token_in, token_out| SwapStep {
+            pool_address,
+            token_in,
+            token_out,
+            protocol,
+            fee: 0,
+            stable: false,
+        };
+        SwapPath::new(vec![
+            step(address!("00000000000000000000000000000000000000aa"), weth, usdc),
+            step(address!("00000000000000000000000000000000000000bb"), usdc, weth),
+        ])
+    }
+
+    // Every path becomes a route with its own input, the batch runs under one loan and a
+    // failing route does not revert the rest
+    #[test]
+    fn test_paths_to_batch() {
+        let v2 = executor_code(PoolType::UniswapV2, false).unwrap();
+        let batch = BatchFlashSwap::BatchParams::try_from(vec![
+            (path(PoolType::UniswapV2), U256::from(10)),
+            (path(PoolType::UniswapV2), U256::from(20)),
+        ])
+        .unwrap();
+        assert_eq!(batch.routes.len(), 2);
+        assert_eq!(batch.routes[1].amountIn, U256::from(20));
+        assert_eq!(
+            batch.routes[0].pools,
+            vec![
+                address!("00000000000000000000000000000000000000aa"),
+                address!("00000000000000000000000000000000000000bb")
+            ]
+        );
+        assert_eq!(batch.routes[0].poolVersions, vec![v2, v2]);
+        assert!(batch.singleLoan && batch.allowPartial);
+
+        // a path the executor can not swap through fails the whole conversion
+        let unsupported = BatchFlashSwap::BatchParams::try_from(vec![
+            (path(PoolType::UniswapV2), U256::from(10)),
+            (path(PoolType::MaverickV1), U256::from(10)),
+        ]);
+        assert!(unsupported.is_err());
+    }
+}
@@ -0,0 +1,354 @@
+use alloy::primitives::{address, keccak256, Address, FixedBytes, U256};
+use alloy::sol_types::{SolCall, SolValue};
+use eyre::Result;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use node_db::{InsertionType, NodeDB};
+use pool_sync::{Pool, PoolInfo};
+use revm::{
+    context_interface::{result::ExecutionResult, Database, TransactTo},
+    inspector::Inspector,
+    interpreter::{opcode, Interpreter},
+    EvmContext,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, File};
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use crate::balance_slot::BalanceSlot;
+use crate::block_env::{self, sim_evm, BlockContext, SimulateAt};
+use crate::gen_::ERC20Token;
+
+// Where the profiles are persisted between runs
+const PROFILE_CACHE: &str = "cache/token_profiles.json";
+
+// The balance slot map is built for this account, so it is the one we can fund
+const SENDER: Address = address!("0000000000000000000000000000000000000001");
+const RECIPIENT: Address = address!("0000000000000000000000000000000000000002");
+
+// Mapping indexes searched when deciding if a slot is keyed by an account
+const MAPPING_INDEXES: u64 = 256;
+
+// Most flag candidates that are toggled per token
+const MAX_FLAG_CANDIDATES: usize = 16;
+
+lazy_static! {
+    // If tokens that an owner can pause or blacklist should be dropped
+    static ref REJECT_CONTROLLED_TOKENS: bool = std::env::var("REJECT_CONTROLLED_TOKENS")
+        .map(|reject| reject == "true")
+        .unwrap_or(false);
+    static ref PROFILES: RwLock<Arc<TokenProfiles>> =
+        RwLock::new(Arc::new(read_profiles_from_file(PROFILE_CACHE).unwrap_or_default()));
+    static ref SEED_BALANCE: U256 = U256::from(10).pow(U256::from(30));
+    static ref TRANSFER_AMOUNT: U256 = U256::from(10).pow(U256::from(24));
+    // eip-1967 implementation and beacon slots
+    static ref PROXY_SLOTS: [U256; 2] = [
+        U256::from_str("0x360894a13ba1a3210667c828492db98dca3e2076cc3735a920a3ca505d382bbc").unwrap(),
+        U256::from_str("0xa3f0ad74e5423aebfd80d3ef4346578335a9a72aeaee59ff6cb3582b35133d50").unwrap(),
+    ];
+}
+
+pub type TokenProfiles = HashMap<Address, TokenProfile>;
+
+// How a token behaves on transfer
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenProfile {
+    pub token: Address,
+    // largest cut taken from a transfer between accounts, into a pool or out of one, in bps
+    pub transfer_tax_bps: u32,
+    // balances did not move by the transferred amount, rebasing or reflection tokens
+    pub balance_mismatch: bool,
+    // a plain transfer reverted
+    pub transfer_fails: bool,
+    // a global flag read during the transfer can stop all transfers
+    pub pausable: bool,
+    // a per account flag read during the transfer can stop an account from transferring
+    pub blacklistable: bool,
+    // behind an eip-1967 proxy, the behaviour can be upgraded
+    pub proxy: bool,
+}
+
+impl TokenProfile {
+    fn default_for(token: Address) -> Self {
+        Self {
+            token,
+            ..Default::default()
+        }
+    }
+
+    // If the token can be swapped through
+    pub fn is_tradeable(&self) -> bool {
+        if self.transfer_fails || self.balance_mismatch {
+            return false;
+        }
+        // the executor forwards the quoted amounts, any tax makes the next hop revert
+        if self.transfer_tax_bps > 0 {
+            return false;
+        }
+        !(*REJECT_CONTROLLED_TOKENS && (self.pausable || self.blacklistable))
+    }
+}
+
+// Snapshot of all known profiles
+pub fn token_profiles() -> Arc<TokenProfiles> {
+    PROFILES.read().unwrap().clone()
+}
+
+// Tokens without a profile could not be classified and are let through
+pub fn is_pool_tradeable(profiles: &TokenProfiles, pool: &Pool) -> bool {
+    [pool.token0_address(), pool.token1_address()]
+        .iter()
+        .all(|token| profiles.get(token).map_or(true, TokenProfile::is_tradeable))
+}
+
+// Profile every token of the pools that is not in the cache yet. The balance slot of each token
+// is needed to fund the sender, tokens without one are skipped. The transfers run in the block
+// after the one the node db is at
+pub fn classify_tokens(
+    pools: &[Pool],
+    slot_map: &HashMap<Address, BalanceSlot>,
+    block: &BlockContext,
+) -> Arc<TokenProfiles> {
+    let block = block.at(SimulateAt::NextBlock);
+    let mut profiles: TokenProfiles = (*token_profiles()).clone();
+
+    // taxes are often only taken on buys and sells, so transfers also go through a pool
+    let mut token_pools: HashMap<Address, Address> = HashMap::new();
+    for pool in pools {
+        token_pools.entry(pool.token0_address()).or_insert(pool.address());
+        token_pools.entry(pool.token1_address()).or_insert(pool.address());
+    }
+
+    let database_path = std::env::var("DB_PATH").unwrap();
+    let mut nodedb = NodeDB::new(database_path).unwrap();
+
+    for (token, pool) in token_pools.iter() {
+        if profiles.contains_key(token) {
+            continue;
+        }
+        let Some(slot) = slot_map.get(token) else {
+            continue;
+        };
+        match profile_token(&mut nodedb, &block, *token, *slot, *pool) {
+            Ok(profile) => {
+                if profile != TokenProfile::default_for(*token) {
+                    debug!("Token profile {:?}", profile);
+                }
+                profiles.insert(*token, profile);
+            }
+            Err(e) => warn!("Failed to profile token {}: {}", token, e),
+        }
+    }
+
+    let untradeable = profiles.values().filter(|profile| !profile.is_tradeable()).count();
+    info!("Profiled {} tokens, {} untradeable", profiles.len(), untradeable);
+
+    create_dir_all("cache").unwrap();
+    if let Err(e) = write_profiles_to_file(&profiles, PROFILE_CACHE) {
+        warn!("Failed to write token profiles: {}", e);
+    }
+
+    let profiles = Arc::new(profiles);
+    *PROFILES.write().unwrap() = profiles.clone();
+    profiles
+}
+
+// Fund the sender through the balance slot and transfer to a fresh recipient, into the pool
+// and back out of it. Compare the balance changes against the amounts, then toggle every flag
+// like slot the plain transfer read to see if it can stop the transfer
+fn profile_token(
+    nodedb: &mut NodeDB,
+    block: &BlockContext,
+    token: Address,
+    balance_slot: BalanceSlot,
+    pool: Address,
+) -> Result<TokenProfile> {
+    let mut profile = TokenProfile::default_for(token);
+
+    for slot in PROXY_SLOTS.iter() {
+        if nodedb.storage(token, *slot)? != U256::ZERO {
+            profile.proxy = true;
+        }
+    }
+
+    // a balance that does not read back as written is share based
+    let sender_slot = balance_slot.slot(SENDER);
+    let seeded = balance_slot.pack(nodedb.storage(token, sender_slot)?, *SEED_BALANCE);
+    nodedb.insert_account_storage(token, sender_slot, seeded, InsertionType::OnChain)?;
+    if balance_of(nodedb, block, token, SENDER)? != *SEED_BALANCE {
+        profile.balance_mismatch = true;
+    }
+
+    // baseline transfer, record what it touches. Then a buy and a sell like transfer, the pool
+    // sends back half of what it got so a tax on the way in still leaves enough
+    let mut recorder = StorageRecorder::new(token);
+    let legs = [
+        (SENDER, RECIPIENT, *TRANSFER_AMOUNT),
+        (SENDER, pool, *TRANSFER_AMOUNT),
+        (pool, RECIPIENT, *TRANSFER_AMOUNT / U256::from(2)),
+    ];
+    for (index, (from, to, amount)) in legs.into_iter().enumerate() {
+        let from_before = balance_of(nodedb, block, token, from)?;
+        let to_before = balance_of(nodedb, block, token, to)?;
+        let leg_recorder = (index == 0).then_some(&mut recorder);
+        if !transfer(nodedb, block, token, from, to, amount, leg_recorder, true)? {
+            profile.transfer_fails = true;
+            return Ok(profile);
+        }
+        let debited = from_before.saturating_sub(balance_of(nodedb, block, token, from)?);
+        let received = balance_of(nodedb, block, token, to)?.saturating_sub(to_before);
+        if debited != amount || received > amount {
+            profile.balance_mismatch = true;
+        }
+        if received < amount {
+            let tax: u32 = ((amount - received) * U256::from(10000) / amount).saturating_to();
+            profile.transfer_tax_bps = profile.transfer_tax_bps.max(tax);
+        }
+    }
+
+    // slots that were read but not written are flags, balances and allowances are written
+    let candidates: Vec<U256> = recorder
+        .loaded
+        .difference(&recorder.stored)
+        .filter(|slot| !PROXY_SLOTS.contains(slot))
+        .copied()
+        .take(MAX_FLAG_CANDIDATES)
+        .collect();
+    for slot in candidates {
+        let original = nodedb.storage(token, slot)?;
+        for toggled in toggles(original) {
+            nodedb.insert_account_storage(token, slot, toggled, InsertionType::OnChain)?;
+            let passes = transfer(nodedb, block, token, SENDER, RECIPIENT, *TRANSFER_AMOUNT, None, false)?;
+            nodedb.insert_account_storage(token, slot, original, InsertionType::OnChain)?;
+            if passes {
+                continue;
+            }
+            if is_account_key(slot, &[SENDER, RECIPIENT]) {
+                profile.blacklistable = true;
+            } else {
+                profile.pausable = true;
+            }
+            break;
+        }
+    }
+
+    Ok(profile)
+}
+
+// Values to try for a flag, bools are often packed after an owner address
+fn toggles(value: U256) -> Vec<U256> {
+    let mut values = vec![
+        if value == U256::ZERO { U256::from(1) } else { U256::ZERO },
+        value ^ U256::from(1),
+        value ^ (U256::from(1) << 160),
+    ];
+    values.sort();
+    values.dedup();
+    values.retain(|toggled| *toggled != value);
+    values
+}
+
+// If the slot is a mapping entry keyed by one of the accounts
+fn is_account_key(slot: U256, accounts: &[Address]) -> bool {
+    let key = FixedBytes::<32>::from(slot.to_be_bytes());
+    accounts.iter().any(|account| {
+        (0..MAPPING_INDEXES).any(|index| keccak256((*account, U256::from(index)).abi_encode()) == key)
+    })
+}
+
+// Transfer the amount between the accounts, returns if it succeeded
+#[allow(clippy::too_many_arguments)]
+fn transfer(
+    nodedb: &mut NodeDB,
+    block: &BlockContext,
+    token: Address,
+    from: Address,
+    to: Address,
+    amount: U256,
+    recorder: Option<&mut StorageRecorder>,
+    commit: bool,
+) -> Result<bool> {
+    let calldata = ERC20Token::transferCall { to, amount }.abi_encode();
+    let mut fallback = StorageRecorder::new(token);
+    let recorder = recorder.unwrap_or(&mut fallback);
+    let mut evm = sim_evm(&mut *nodedb, block)
+        .with_external_context(recorder)
+        .modify_tx_env(|tx| {
+            tx.caller = from;
+            tx.transact_to = TransactTo::Call(token);
+            tx.data = calldata.into();
+            tx.value = U256::ZERO;
+            tx.gas_limit = 500000;
+        })
+        .build();
+    let result = if commit {
+        evm.transact_commit()?
+    } else {
+        evm.transact()?.result
+    };
+    Ok(matches!(result, ExecutionResult::Success { .. }))
+}
+
+fn balance_of(nodedb: &mut NodeDB, block: &BlockContext, token: Address, account: Address) -> Result<U256> {
+    block_env::balance_of(&mut *nodedb, block, token, account).map_err(|e| eyre::eyre!("{e}"))
+}
+
+// Records the storage slots of the token that are read and written. The storage of a proxy
+// is the token address so delegatecalls into the implementation are included
+struct StorageRecorder {
+    token: Address,
+    loaded: HashSet<U256>,
+    stored: HashSet<U256>,
+}
+
+impl StorageRecorder {
+    fn new(token: Address) -> Self {
+        Self {
+            token,
+            loaded: HashSet::new(),
+            stored: HashSet::new(),
+        }
+    }
+}
+
+impl<DB: Database> Inspector<DB> for StorageRecorder {
+    fn step(&mut self, interp: &mut Interpreter, _context: &mut EvmContext<DB>) {
+        if interp.contract.target_address != self.token {
+            return;
+        }
+        let Ok(slot) = interp.stack().peek(0) else {
+            return;
+        };
+        match interp.current_opcode() {
+            opcode::SLOAD => {
+                self.loaded.insert(slot);
+            }
+            opcode::SSTORE => {
+                self.stored.insert(slot);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn write_profiles_to_file(profiles: &TokenProfiles, filename: &str) -> std::io::Result<()> {
+    let file = File::create(filename)?;
+    let writer = BufWriter::new(file);
+    let profiles: Vec<&TokenProfile> = profiles.values().collect();
+    serde_json::to_writer(writer, &profiles)?;
+    Ok(())
+}
+
+fn read_profiles_from_file(filename: &str) -> Result<TokenProfiles> {
+    if !Path::new(filename).exists() {
+        return Ok(TokenProfiles::new());
+    }
+    let file = File::open(filename)?;
+    let reader = BufReader::new(file);
+    let profiles: Vec<TokenProfile> = serde_json::from_reader(reader)?;
+    Ok(profiles.into_iter().map(|profile| (profile.token, profile)).collect())
+}
@@ -1,7 +1,11 @@
 
+use crate::block_env::CHAIN_ID;
 use crate::events::Event;
+use crate::execution::{access_list_for, simulate_execution, ExecutionSim};
+use crate::flash_loan::encode_execution;
 use crate::gas_station::GasStation;
 use crate::gen_::FlashSwap;
+use crate::market_state::MarketState;
 use crate::traits::*;
 use crate::types::*;
 use alloy::hex;
@@ -12,13 +16,13 @@ use alloy::transports::http::Client;
 ses it.
 use alloy::eips::Encodable2718;
 use alloy::network::{EthereumWallet, Ethereum, Network, TransactionBuilder};
-use alloy::primitives::{Address, FixedBytes, Bytes};
+use alloy::primitives::{Address, FixedBytes};
 use alloy::providers::Provider;
 use alloy::providers::ProviderBuilder;
 use alloy::providers::RootProvider; // Already imported
 use alloy::rpc::types::TransactionRequest;
-use alloy::sol_types::SolCall;
-use log::info;
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
 //use reqwest::Client; // alloy's Client is used
 use serde_json::Value;
 use std::str::FromStr;
@@ -26,6 +30,9 @@ use std::sync::mpsc::Receiver;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+// Gas limit the transaction is measured with before its real limit is known
+const MEASURE_GAS_LIMIT: u64 = 2_000_000;
+
 // Handles sending transactions
 pub struct TransactionSender {
     wallet: EthereumWallet,
@@ -34,10 +41,17 @@ pub struct TransactionSender {
     client: Arc<Client>, // This is alloy::transports::http::Client
     provider: Arc<RootProvider<Http<Client>, Ethereum>>, // Corrected RootProvider type
     nonce: u64,
+    // every transaction is simulated against the market state before it is sent
+    market_state: Arc<MarketState<Http<Client>, Ethereum, RootProvider<Http<Client>>>>,
+    account: Address,
+    weth: Address,
 }
 
 impl TransactionSender {
-    pub async fn new(gas_station: Arc<GasStation>) -> Self {
+    pub async fn new(
+        gas_station: Arc<GasStation>,
+        market_state: Arc<MarketState<Http<Client>, Ethereum, RootProvider<Http<Client>>>>,
+    ) -> Self {
         // construct a wallet
         let key = std::env::var("PRIVATE_KEY").unwrap();
         let key_hex = hex::decode(key).unwrap();
@@ -80,8 +94,9 @@ impl TransactionSender {
                 .on_http(provider_url),
         );
 
+        let account: Address = std::env::var("ACCOUNT").unwrap().parse().unwrap();
         let nonce = provider
-            .get_transaction_count(std::env::var("ACCOUNT").unwrap().parse().unwrap(), None) // Added None for block_id
+            .get_transaction_count(account, None) // Added None for block_id
             .await
             .unwrap();
 
@@ -92,33 +107,50 @@ impl TransactionSender {
             client: Arc::new(http_client), // Store the http_client
             provider,
             nonce,
+            market_state,
+            account,
+            weth: std::env::var("WETH").unwrap().parse().unwrap(),
         }
     }
 
     // Receive a path that has passed simulation to be sent to the sequencer
     pub async fn send_transactions(&mut self, tx_receiver: Receiver<Event>) {
         // wait for a new transaction that has passed simulation
-        while let Ok(Event::ValidPath((arb_path, profit, block_number))) = tx_receiver.recv() {
+        while let Ok(Event::ValidPath((arb_path, _, block_number, loan))) = tx_receiver.recv() {
             info!("Sending path...");
 
-            // Setup the calldata
+            // Setup the calldata for the entrypoint of the flash loan source
             let converted_path: FlashSwap::SwapParams = arb_path.clone().into();
-            let calldata = FlashSwap::executeArbitrageCall {
-                arb: converted_path,
-            }
-            .abi_encode();
+            let calldata = encode_execution(loan.source, converted_path);
 
-            // Construct, sign, and encode transaction
-            let (max_fee, priority_fee) = self.gas_station.get_gas_fees(profit);
+            // Construct the transaction and measure its gas before bidding
             let tx = TransactionRequest::default()
                 .with_to(self.contract_address)
                 .with_nonce(self.nonce)
-                .with_gas_limit(2_000_000)
-                .with_chain_id(8453)
-                .with_max_fee_per_gas(max_fee.into()) // Ensure U256
-                .with_max_priority_fee_per_gas(priority_fee.into()) // Ensure U256
+                .with_gas_limit(MEASURE_GAS_LIMIT)
+                .with_chain_id(CHAIN_ID)
                 .with_transaction_type(2) // EIP-1559
-                .with_input(Bytes::from(calldata));
+                .with_input(calldata);
+            // the executor run is the gate, never send a transaction that fails or loses
+            let (tx, sim) = match self.measure(tx) {
+                Ok(measured) => measured,
+                Err(e) => {
+                    warn!("Not sending, could not measure the execution: {e:?}");
+                    continue;
+                }
+            };
+            info!(
+                "Execution simulated. Gas used {}, refunded {}, profit {}",
+                sim.gas_used, sim.gas_refunded, sim.profit
+            );
+
+            // bid from the profit the execution returned, not the quote, on the gas the
+            // transaction pays for and leave a margin on the limit
+            let (max_fee, priority_fee) = self.gas_station.get_gas_fees(sim.profit, sim.gas_used);
+            let tx = tx
+                .with_gas_limit(sim.gas_limit())
+                .with_max_fee_per_gas(max_fee)
+                .with_max_priority_fee_per_gas(priority_fee);
             self.nonce += 1;
             
             // Build the transaction envelope using the wallet
@@ -170,6 +202,34 @@ impl TransactionSender {
         }
     }
 
+    // Run the transaction through the executor in the next block. An access list is attached if
+    // warming the accounts and slots up front costs less than touching them cold
+    fn measure(&self, tx: TransactionRequest) -> Result<(TransactionRequest, ExecutionSim)> {
+        let plain = simulate_execution(&self.market_state, &tx, self.account, self.weth)?;
+        if !plain.succeeded() {
+            return Err(anyhow!(
+                "Execution failed. Gas used {}, profit {}, revert {:?}",
+                plain.gas_used, plain.profit, plain.revert
+            ));
+        }
+
+        let access_list = match access_list_for(&self.market_state, &tx, self.account) {
+            Ok(access_list) => access_list,
+            Err(e) => {
+                warn!("Failed to generate an access list: {e:?}");
+                return Ok((tx, plain));
+            }
+        };
+        let listed = tx.clone().with_access_list(access_list);
+        match simulate_execution(&self.market_state, &listed, self.account, self.weth) {
+            Ok(sim) if sim.succeeded() && sim.gas_used < plain.gas_used => {
+                debug!("Access list saves {} gas", plain.gas_used - sim.gas_used);
+                Ok((listed, sim))
+            }
+            _ => Ok((tx, plain)),
+        }
+    }
+
     // Send the transaction and monitor its status
     pub async fn send_and_monitor(
         provider: Arc<RootProvider<Http<Client>, Ethereum>>, // Corrected RootProvider type
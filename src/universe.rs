@@ -0,0 +1,505 @@
+use alloy::primitives::Address;
+use async_trait::async_trait;
+use eyre::{eyre, Result};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use pool_sync::{Chain, Pool, PoolInfo};
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, rename, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// What a source can use to rank tokens
+pub struct UniverseContext<'a> {
+    pub chain: Chain,
+    // every synced pool, before any filtering
+    pub pools: &'a [Pool],
+    // how many tokens to return
+    pub num_results: usize,
+}
+
+// A source of the tokens we want to trade, ranked best first
+#[async_trait]
+pub trait TokenUniverse: Send + Sync {
+    // Name of the source, used for the cache file
+    fn name(&self) -> String;
+
+    async fn tokens(&self, ctx: &UniverseContext<'_>) -> Result<Vec<Address>>;
+}
+
+// Construct the universe from TOKEN_UNIVERSE. Sources are birdeye, static, liquidity and trace.
+// `|` takes the union of the terms and `&` intersects the sources in a term, so
+// `birdeye&liquidity|static` is the liquid birdeye tokens plus the allowlist. Every source is
+// cached for UNIVERSE_TTL_SECS, defaults to birdeye with a day ttl
+pub fn universe_from_env() -> Box<dyn TokenUniverse> {
+    let expression = std::env::var("TOKEN_UNIVERSE").unwrap_or_else(|_| "birdeye".to_string());
+    let ttl: u64 = std::env::var("UNIVERSE_TTL_SECS")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(86400);
+
+    let mut terms: Vec<Box<dyn TokenUniverse>> = expression
+        .split('|')
+        .map(|term| {
+            let mut sources: Vec<Box<dyn TokenUniverse>> = term
+                .split('&')
+                .map(|source| -> Box<dyn TokenUniverse> {
+                    let source: Box<dyn TokenUniverse> = match source.trim() {
+                        "birdeye" => Box::new(Birdeye),
+                        "static" => Box::new(StaticList::from_env()),
+                        "liquidity" => Box::new(Liquidity),
+                        "trace" => Box::new(TraceVolume::from_env()),
+                        other => panic!("Unknown token universe source {other}"),
+                    };
+                    Box::new(Cached::new(source, ttl))
+                })
+                .collect();
+            if sources.len() == 1 {
+                sources.remove(0)
+            } else {
+                Box::new(Intersection(sources))
+            }
+        })
+        .collect();
+    if terms.len() == 1 {
+        terms.remove(0)
+    } else {
+        Box::new(Union(terms))
+    }
+}
+
+// Tokens from every source, ordered by their best rank in any of them
+pub struct Union(pub Vec<Box<dyn TokenUniverse>>);
+
+#[async_trait]
+impl TokenUniverse for Union {
+    fn name(&self) -> String {
+        let names: Vec<String> = self.0.iter().map(|source| source.name()).collect();
+        names.join("|")
+    }
+
+    async fn tokens(&self, ctx: &UniverseContext<'_>) -> Result<Vec<Address>> {
+        let mut best_rank: HashMap<Address, usize> = HashMap::new();
+        for source in &self.0 {
+            for (rank, token) in source.tokens(ctx).await?.into_iter().enumerate() {
+                let best = best_rank.entry(token).or_insert(rank);
+                *best = (*best).min(rank);
+            }
+        }
+        let mut tokens: Vec<(Address, usize)> = best_rank.into_iter().collect();
+        tokens.sort_by_key(|(token, rank)| (*rank, *token));
+        Ok(tokens.into_iter().map(|(token, _)| token).collect())
+    }
+}
+
+// Tokens that are in every source, in the order of the first source
+pub struct Intersection(pub Vec<Box<dyn TokenUniverse>>);
+
+#[async_trait]
+impl TokenUniverse for Intersection {
+    fn name(&self) -> String {
+        let names: Vec<String> = self.0.iter().map(|source| source.name()).collect();
+        names.join("&")
+    }
+
+    async fn tokens(&self, ctx: &UniverseContext<'_>) -> Result<Vec<Address>> {
+        let Some((first, rest)) = self.0.split_first() else {
+            return Ok(Vec::new());
+        };
+        let mut tokens = first.tokens(ctx).await?;
+        for source in rest {
+            let other: HashSet<Address> = source.tokens(ctx).await?.into_iter().collect();
+            tokens.retain(|token| other.contains(token));
+        }
+        Ok(tokens)
+    }
+}
+
+// Cached tokens with the time they were fetched
+#[derive(Serialize, Deserialize)]
+struct CachedTokens {
+    fetched_at: u64,
+    tokens: Vec<Address>,
+}
+
+// Caches a source on disk for ttl seconds. If the source fails a stale cache is used so the
+// working set can refresh without the network
+pub struct Cached {
+    inner: Box<dyn TokenUniverse>,
+    ttl: u64,
+}
+
+impl Cached {
+    pub fn new(inner: Box<dyn TokenUniverse>, ttl: u64) -> Self {
+        Self { inner, ttl }
+    }
+}
+
+#[async_trait]
+impl TokenUniverse for Cached {
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+
+    async fn tokens(&self, ctx: &UniverseContext<'_>) -> Result<Vec<Address>> {
+        let cache_file = format!("cache/universe_{}_{}.json", self.name(), ctx.chain);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let cached = read_cache(&cache_file).ok();
+        if let Some(cached) = &cached {
+            if now.saturating_sub(cached.fetched_at) < self.ttl {
+                return Ok(cached.tokens.clone());
+            }
+        }
+
+        match self.inner.tokens(ctx).await {
+            Ok(tokens) => {
+                create_dir_all("cache")?;
+                write_cache(&cache_file, &CachedTokens { fetched_at: now, tokens: tokens.clone() })?;
+                Ok(tokens)
+            }
+            Err(e) => match cached {
+                Some(cached) => {
+                    warn!("Failed to refresh {} tokens, using stale cache: {}", self.name(), e);
+                    Ok(cached.tokens)
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+fn write_cache(filename: &str, cached: &CachedTokens) -> std::io::Result<()> {
+    let file = File::create(filename)?;
+    let writer = BufWriter::new(file);
+    serde_json::to_writer(writer, cached)?;
+    Ok(())
+}
+
+fn read_cache(filename: &str) -> Result<CachedTokens> {
+    let file = File::open(filename)?;
+    let reader = BufReader::new(file);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+#[derive(Debug, Deserialize)]
+struct BirdeyeResponse {
+    data: ResponseData,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseData {
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Token {
+    address: String,
+}
+
+// Top 24h volume tokens from birdeye, needs BIRDEYE_KEY
+pub struct Birdeye;
+
+#[async_trait]
+impl TokenUniverse for Birdeye {
+    fn name(&self) -> String {
+        "birdeye".to_string()
+    }
+
+    async fn tokens(&self, ctx: &UniverseContext<'_>) -> Result<Vec<Address>> {
+        let client = reqwest::Client::new();
+        let mut headers = HeaderMap::new();
+        let api_key = std::env::var("BIRDEYE_KEY")?;
+        headers.insert("X-API-KEY", HeaderValue::from_str(&api_key)?);
+        if ctx.chain == Chain::Ethereum {
+            headers.insert("x-chain", HeaderValue::from_static("ethereum"));
+        } else if ctx.chain == Chain::Base {
+            headers.insert("x-chain", HeaderValue::from_static("base"));
+        }
+
+        let mut query_params: Vec<(usize, usize)> = Vec::new();
+        if ctx.num_results < 50 {
+            query_params.push((0, ctx.num_results));
+        } else {
+            for offset in (0..ctx.num_results).step_by(50) {
+                query_params.push((offset, 50));
+            }
+        }
+
+        let mut addresses: Vec<String> = Vec::new();
+        for (offset, num) in query_params {
+            let response = client
+                .get("https://public-api.birdeye.so/defi/tokenlist")
+                .headers(headers.clone())
+                .query(&[
+                    ("sort_by", "v24hUSD"),
+                    ("sort_type", "desc"),
+                    ("offset", &offset.to_string()),
+                    ("limit", &num.to_string()),
+                ])
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(eyre!("Birdeye returned {}", response.status()));
+            }
+            let birdeye_response: BirdeyeResponse = response.json().await?;
+            addresses.extend(birdeye_response.data.tokens.into_iter().map(|token| token.address));
+        }
+        addresses
+            .into_iter()
+            .map(|addr| Ok(Address::from_str(&addr)?))
+            .collect()
+    }
+}
+
+// Allowlist file from TOKEN_ALLOWLIST, one address per line and `#` starts a comment
+pub struct StaticList {
+    path: String,
+}
+
+impl StaticList {
+    pub fn from_env() -> Self {
+        Self {
+            path: std::env::var("TOKEN_ALLOWLIST").unwrap_or_else(|_| "tokens.txt".to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl TokenUniverse for StaticList {
+    fn name(&self) -> String {
+        "static".to_string()
+    }
+
+    async fn tokens(&self, _ctx: &UniverseContext<'_>) -> Result<Vec<Address>> {
+        if !Path::new(&self.path).exists() {
+            return Err(eyre!("Token allowlist {} does not exist", self.path));
+        }
+        std::fs::read_to_string(&self.path)?
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| Ok(Address::from_str(line)?))
+            .collect()
+    }
+}
+
+// Ranks tokens by the weth value of their reserves across the synced pools. Tokens are priced
+// outwards from weth through the deepest pool that connects them to a priced token
+pub struct Liquidity;
+
+#[async_trait]
+impl TokenUniverse for Liquidity {
+    fn name(&self) -> String {
+        "liquidity".to_string()
+    }
+
+    async fn tokens(&self, ctx: &UniverseContext<'_>) -> Result<Vec<Address>> {
+        let prices = weth_prices(ctx.pools);
+        let mut value: HashMap<Address, f64> = HashMap::new();
+        for pool in ctx.pools {
+            let Some((reserve0, reserve1)) = pool_reserves(pool) else {
+                continue;
+            };
+            for (token, reserve) in [(pool.token0_address(), reserve0), (pool.token1_address(), reserve1)] {
+                if let Some(price) = prices.get(&token) {
+                    *value.entry(token).or_default() += reserve * price;
+                }
+            }
+        }
+
+        let mut ranked: Vec<(Address, f64)> = value.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        info!("Ranked {} tokens by liquidity", ranked.len());
+        Ok(ranked
+            .into_iter()
+            .take(ctx.num_results)
+            .map(|(token, _)| token)
+            .collect())
+    }
+}
+
+// Weth price of every token that connects to weth through the pools, in wei per token unit.
+// Each token is priced through the deepest pool that connects it to a priced token
+pub fn weth_prices(pools: &[Pool]) -> HashMap<Address, f64> {
+    let weth: Address = std::env::var("WETH").unwrap().parse().unwrap();
+    let reserves: Vec<(Address, f64, Address, f64)> = pools
+        .iter()
+        .filter_map(|pool| {
+            let (reserve0, reserve1) = pool_reserves(pool)?;
+            Some((pool.token0_address(), reserve0, pool.token1_address(), reserve1))
+        })
+        .collect();
+
+    // price of each token, along with the depth of the pool it was priced from
+    let mut prices: HashMap<Address, (f64, f64)> = HashMap::new();
+    prices.insert(weth, (1.0, f64::INFINITY));
+    for _ in 0..3 {
+        let mut updated = false;
+        for (token0, reserve0, token1, reserve1) in &reserves {
+            for (priced, priced_reserve, token, token_reserve) in [
+                (token0, reserve0, token1, reserve1),
+                (token1, reserve1, token0, reserve0),
+            ] {
+                let Some((price, _)) = prices.get(priced).copied() else {
+                    continue;
+                };
+                let depth = priced_reserve * price;
+                if *token_reserve <= 0.0 || prices.get(token).is_some_and(|(_, best)| *best >= depth) {
+                    continue;
+                }
+                prices.insert(*token, (depth / token_reserve, depth));
+                updated = true;
+            }
+        }
+        if !updated {
+            break;
+        }
+    }
+    prices.into_iter().map(|(token, (price, _))| (token, price)).collect()
+}
+
+// Weth value of the reserves of a pool
+pub fn pool_liquidity(pool: &Pool, prices: &HashMap<Address, f64>) -> Option<f64> {
+    let (reserve0, reserve1) = pool_reserves(pool)?;
+    let price0 = prices.get(&pool.token0_address())?;
+    let price1 = prices.get(&pool.token1_address())?;
+    Some(reserve0 * price0 + reserve1 * price1)
+}
+
+// Reserves of a pool, virtual reserves at the current price for v3 pools
+fn pool_reserves(pool: &Pool) -> Option<(f64, f64)> {
+    if let Some(v2) = pool.get_v2() {
+        return Some((
+            v2.token0_reserves.saturating_to::<u128>() as f64,
+            v2.token1_reserves.saturating_to::<u128>() as f64,
+        ));
+    }
+    if let Some(v3) = pool.get_v3() {
+        let sqrt_price = (v3.sqrt_price >> 32).saturating_to::<u128>() as f64 / 2f64.powi(64);
+        if sqrt_price == 0.0 {
+            return None;
+        }
+        let liquidity = v3.liquidity as f64;
+        return Some((liquidity / sqrt_price, liquidity * sqrt_price));
+    }
+    None
+}
+
+// Pools touched in each traced block
+// Blocks the state updater traced, one line per block so recording a block is an append
+const VOLUME_LOG: &str = "cache/trace_volume.jsonl";
+
+lazy_static! {
+    // the updater appends while a rebuild compacts the log
+    static ref VOLUME_LOG_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// The contracts whose state a block changed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockVolume {
+    pub block: u64,
+    pub touched: Vec<Address>,
+}
+
+// Record the contracts a traced block changed, TraceVolume ranks tokens from these
+pub fn record_block_volume(block: u64, touched: Vec<Address>) -> Result<()> {
+    let _guard = VOLUME_LOG_LOCK.lock().unwrap();
+    create_dir_all("cache")?;
+    let file = OpenOptions::new().create(true).append(true).open(VOLUME_LOG)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, &BlockVolume { block, touched })?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Ranks tokens by how many of the recent blocks swapped through one of their pools. Nothing is
+// traced here, the state updater already traces every block and records what it touched. A
+// cold cache ranks from the blocks recorded so far
+pub struct TraceVolume {
+    blocks: u64,
+}
+
+impl TraceVolume {
+    // Look back TRACE_BLOCKS blocks, defaults to an hour of base blocks
+    pub fn from_env() -> Self {
+        let blocks = std::env::var("TRACE_BLOCKS")
+            .ok()
+            .and_then(|blocks| blocks.parse().ok())
+            .unwrap_or(1800);
+        Self { blocks }
+    }
+}
+
+#[async_trait]
+impl TokenUniverse for TraceVolume {
+    fn name(&self) -> String {
+        "trace".to_string()
+    }
+
+    async fn tokens(&self, ctx: &UniverseContext<'_>) -> Result<Vec<Address>> {
+        let pool_tokens: HashMap<Address, (Address, Address)> = ctx
+            .pools
+            .iter()
+            .map(|pool| (pool.address(), (pool.token0_address(), pool.token1_address())))
+            .collect();
+
+        let volumes = recent_volumes(VOLUME_LOG, self.blocks)?;
+        if volumes.len() < self.blocks as usize {
+            info!("Ranking from {} of {} traced blocks", volumes.len(), self.blocks);
+        }
+        let ranked = rank_tokens(&volumes, &pool_tokens);
+        info!("Ranked {} tokens by traced swaps", ranked.len());
+        Ok(ranked.into_iter().take(ctx.num_results).collect())
+    }
+}
+
+// Tokens by how many blocks touched one of their pools, most first
+fn rank_tokens(volumes: &[BlockVolume], pool_tokens: &HashMap<Address, (Address, Address)>) -> Vec<Address> {
+    let mut swaps: HashMap<Address, u64> = HashMap::new();
+    for volume in volumes {
+        for pool in volume.touched.iter().filter(|pool| pool_tokens.contains_key(pool)) {
+            let (token0, token1) = pool_tokens[pool];
+            *swaps.entry(token0).or_default() += 1;
+            *swaps.entry(token1).or_default() += 1;
+        }
+    }
+    let mut ranked: Vec<(Address, u64)> = swaps.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked.into_iter().map(|(token, _)| token).collect()
+}
+
+// The last `blocks` recorded blocks. The log is compacted to them so it does not grow
+fn recent_volumes(filename: &str, blocks: u64) -> Result<Vec<BlockVolume>> {
+    let _guard = VOLUME_LOG_LOCK.lock().unwrap();
+    if !Path::new(filename).exists() {
+        return Ok(Vec::new());
+    }
+    let reader = BufReader::new(File::open(filename)?);
+    let mut volumes: HashMap<u64, BlockVolume> = HashMap::new();
+    for line in reader.lines() {
+        // a line cut off by a crash is skipped
+        if let Ok(volume) = serde_json::from_str::<BlockVolume>(&line?) {
+            volumes.insert(volume.block, volume);
+        }
+    }
+    let latest = volumes.keys().max().copied().unwrap_or_default();
+    let first = latest.saturating_sub(blocks);
+    let mut volumes: Vec<BlockVolume> = volumes.into_values().filter(|volume| volume.block > first).collect();
+    volumes.sort_by_key(|volume| volume.block);
+
+    let compacted = format!("{filename}.tmp");
+    let mut writer = BufWriter::new(File::create(&compacted)?);
+    for volume in &volumes {
+        serde_json::to_writer(&mut writer, volume)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    rename(&compacted, filename)?;
+    Ok(volumes)
+}